@@ -290,8 +290,8 @@ impl<Window: WindowMethods> IOCompositor<Window> {
                 self.remove_outstanding_render_msg();
             }
 
-            (ScrollFragmentPoint(pipeline_id, layer_id, point), NotShuttingDown) => {
-                self.scroll_fragment_to_point(pipeline_id, layer_id, point);
+            (ScrollFragmentPoint(pipeline_id, layer_id, point, smooth), NotShuttingDown) => {
+                self.scroll_fragment_to_point(pipeline_id, layer_id, point, smooth);
             }
 
             (LoadComplete(..), NotShuttingDown) => {
@@ -607,7 +607,11 @@ impl<Window: WindowMethods> IOCompositor<Window> {
     fn scroll_fragment_to_point(&mut self,
                                 pipeline_id: PipelineId,
                                 layer_id: LayerId,
-                                point: Point2D<f32>) {
+                                point: Point2D<f32>,
+                                smooth: bool) {
+        // TODO: Animate smooth scrolls. For now they jump straight to the point like instant
+        // ones do.
+        debug!("scrolling to fragment point {:?} (smooth: {})", point, smooth);
         if self.move_layer(pipeline_id, layer_id, Point2D::from_untyped(&point)) {
             if self.send_buffer_requests_for_all_layers() {
                 self.start_scrolling_timer_if_necessary();
@@ -20,7 +20,7 @@ use layers::geometry::LayerPixel;
 use layers::layers::{Layer, LayerBufferSet};
 use layers::platform::surface::NativeSurfaceMethods;
 use script_traits::{ClickEvent, MouseDownEvent, MouseMoveEvent, MouseUpEvent, SendEventMsg};
-use script_traits::{ScriptControlChan};
+use script_traits::{ScriptControlChan, ScrollEvent};
 use servo_msg::compositor_msg::{Epoch, FixedPosition, LayerId, ScrollPolicy};
 use std::rc::Rc;
 
@@ -110,6 +110,11 @@ pub trait CompositorLayer {
     fn send_mouse_move_event(&self,
                              cursor: TypedPoint2D<LayerPixel, f32>);
 
+    // Tells the layer's pipeline where its content has been scrolled to, so that script can
+    // report geometry relative to the viewport.
+    fn send_scroll_event(&self,
+                         scroll_offset: TypedPoint2D<LayerPixel, f32>);
+
     fn clamp_scroll_offset_and_scroll_layer(&self,
                                             new_offset: TypedPoint2D<LayerPixel, f32>)
                                             -> ScrollEventResult;
@@ -298,6 +303,7 @@ impl CompositorLayer for Layer<CompositorData> {
         // The scroll offset is just a record of the scroll position of this scrolling root,
         // but scroll_layer_and_all_child_layers actually moves the child layers.
         self.extra_data.borrow_mut().scroll_offset = new_offset;
+        self.send_scroll_event(new_offset);
 
         let mut result = false;
         for child in self.children().iter() {
@@ -333,6 +339,15 @@ impl CompositorLayer for Layer<CompositorData> {
         let _ = chan.send_opt(SendEventMsg(pipeline.id.clone(), message));
     }
 
+    fn send_scroll_event(&self,
+                         scroll_offset: TypedPoint2D<LayerPixel, f32>) {
+        // The scroll offset moves the content, so the page is scrolled the opposite way.
+        let message = ScrollEvent(Point2D(-scroll_offset.x.get(), -scroll_offset.y.get()));
+        let pipeline = &self.extra_data.borrow().pipeline;
+        let ScriptControlChan(ref chan) = pipeline.script_chan;
+        let _ = chan.send_opt(SendEventMsg(pipeline.id.clone(), message));
+    }
+
     fn scroll_layer_and_all_child_layers(&self,
                                          new_offset: TypedPoint2D<LayerPixel, f32>)
                                          -> bool {
@@ -69,8 +69,9 @@ impl ScriptListener for Box<CompositorProxy+'static+Send> {
     fn scroll_fragment_point(&mut self,
                              pipeline_id: PipelineId,
                              layer_id: LayerId,
-                             point: Point2D<f32>) {
-        self.send(ScrollFragmentPoint(pipeline_id, layer_id, point));
+                             point: Point2D<f32>,
+                             smooth: bool) {
+        self.send(ScrollFragmentPoint(pipeline_id, layer_id, point, smooth));
     }
 
     fn close(&mut self) {
@@ -177,8 +178,8 @@ pub enum Msg {
     CreateOrUpdateDescendantLayer(LayerProperties),
     /// Alerts the compositor that the specified layer's origin has changed.
     SetLayerOrigin(PipelineId, LayerId, Point2D<f32>),
-    /// Scroll a page in a window
-    ScrollFragmentPoint(PipelineId, LayerId, Point2D<f32>),
+    /// Scroll a page in a window, smoothly if the flag is set.
+    ScrollFragmentPoint(PipelineId, LayerId, Point2D<f32>, bool),
     /// Requests that the compositor paint the given layer buffer set for the given page size.
     Paint(PipelineId, Epoch, Vec<(LayerId, Box<LayerBufferSet>)>),
     /// Alerts the compositor to the current status of page loading.
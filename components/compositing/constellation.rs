@@ -678,6 +678,9 @@ impl<LTF: LayoutTaskFactory, STF: ScriptTaskFactory> Constellation<LTF, STF> {
         // Being here means either there are no pending frames, or none of the pending
         // changes would be overriden by changing the subframe associated with source_id.
 
+        // The source pipeline has nothing left to lay out until it is navigated back to.
+        source_frame.pipeline.suppress_reflows();
+
         let parent = source_frame.parent.clone();
         let subpage_id = source_frame.pipeline.subpage_id;
         let next_pipeline_id = self.get_next_pipeline_id();
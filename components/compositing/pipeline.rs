@@ -6,6 +6,7 @@ use CompositorProxy;
 use layout_traits::{LayoutTaskFactory, LayoutControlChan};
 use script_traits::{ScriptControlChan, ScriptTaskFactory};
 use script_traits::{AttachLayoutMsg, LoadMsg, NewLayoutInfo, ExitPipelineMsg};
+use script_traits::SuppressReflowsMsg;
 
 use devtools_traits::DevtoolsControlChan;
 use gfx::render_task::{PaintPermissionGranted, PaintPermissionRevoked};
@@ -158,6 +159,13 @@ impl Pipeline {
         chan.send(LoadMsg(self.id, self.load_data.clone()));
     }
 
+    /// Tells script not to reflow this pipeline until it is loaded again, since it is being
+    /// navigated away from.
+    pub fn suppress_reflows(&self) {
+        let ScriptControlChan(ref chan) = self.script_chan;
+        let _ = chan.send_opt(SuppressReflowsMsg(self.id));
+    }
+
     pub fn grant_paint_permission(&self) {
         let _ = self.render_chan.send_opt(PaintPermissionGranted);
     }
@@ -41,7 +41,7 @@ pub mod optimizer;
 /// Because the script task's GC does not trace layout, node data cannot be safely stored in layout
 /// data structures. Also, layout code tends to be faster when the DOM is not being accessed, for
 /// locality reasons. Using `OpaqueNode` enforces this invariant.
-#[deriving(Clone, PartialEq)]
+#[deriving(Clone, PartialEq, Eq, Hash)]
 pub struct OpaqueNode(pub uintptr_t);
 
 impl OpaqueNode {
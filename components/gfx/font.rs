@@ -9,7 +9,8 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use servo_util::cache::{Cache, HashCache};
 use servo_util::smallvec::{SmallVec, SmallVec8};
-use style::computed_values::{font_variant, font_weight};
+use style::computed_values::{font_feature_settings, font_stretch, font_variant, font_weight};
+use style::computed_values::{font_synthesis, text_rendering};
 use style::style_structs::Font as FontStyle;
 use sync::Arc;
 
@@ -35,19 +36,111 @@ pub trait FontHandleMethods {
     fn face_name(&self) -> String;
     fn is_italic(&self) -> bool;
     fn boldness(&self) -> font_weight::T;
+    fn stretchiness(&self) -> font_stretch::T;
 
     fn glyph_index(&self, codepoint: char) -> Option<GlyphId>;
     fn glyph_h_advance(&self, GlyphId) -> Option<FractionalPixel>;
     fn glyph_h_kerning(&self, GlyphId, GlyphId) -> FractionalPixel;
     fn get_metrics(&self) -> FontMetrics;
     fn get_table_for_tag(&self, FontTableTag) -> Option<FontTable>;
+    /// Sets how glyphs are hinted when their outlines and advances are loaded.
+    fn set_hinting(&mut self, hinting: HintingMode);
 }
 
+/// How strongly glyph outlines are fitted to the pixel grid.
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+pub enum HintingMode {
+    /// Outlines are used as designed.
+    NoHinting,
+    /// Outlines are only fitted vertically, which keeps the shapes and advances of glyphs.
+    SlightHinting,
+    /// Outlines are fitted in both directions. This is what the platform does by default.
+    FullHinting,
+}
+
+/// The hinting mode that fonts were always created with before it could be chosen.
+pub static DEFAULT_HINTING_MODE: HintingMode = FullHinting;
+
 // Used to abstract over the shaper's choice of fixed int representation.
 pub type FractionalPixel = f64;
 
 pub type FontTableTag = u32;
 
+/// The OpenType glyph substitution table.
+pub static GSUB: FontTableTag = 0x47535542;
+
+/// The OpenType feature tag for true small capitals.
+pub static SMCP: FontTableTag = 0x736d6370;
+
+/// The OpenType feature tags for standard and contextual ligatures.
+pub static LIGA: FontTableTag = 0x6c696761;
+pub static CLIG: FontTableTag = 0x636c6967;
+
+/// The OpenType feature tag for kerning.
+pub static KERN: FontTableTag = 0x6b65726e;
+
+/// The OpenType tables holding color glyphs: layered vector glyphs, and embedded bitmaps in the
+/// Apple and Google formats.
+pub static COLR: FontTableTag = 0x434f4c52;
+pub static SBIX: FontTableTag = 0x73626978;
+pub static CBDT: FontTableTag = 0x43424454;
+pub static COLOR_FONT_TABLES: [FontTableTag, ..3] = [COLR, SBIX, CBDT];
+
+/// Returns the tag spelled by the given four characters, as used in `font-feature-settings`.
+pub fn font_table_tag(tag: &str) -> FontTableTag {
+    tag.bytes().fold(0, |tag, byte| (tag << 8) | byte as FontTableTag)
+}
+
+/// Returns true if the feature list of the given GSUB table contains `feature`. Malformed tables
+/// are treated as having no features.
+pub fn gsub_has_feature(gsub: &[u8], feature: FontTableTag) -> bool {
+    // The header is a 32-bit version followed by the offsets of the script, feature and lookup
+    // lists. The feature list is a count followed by 6-byte (tag, offset) records.
+    let feature_list = match read_u16(gsub, 6) {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let feature_count = match read_u16(gsub, feature_list) {
+        Some(count) => count,
+        None => return false,
+    };
+    range(0, feature_count).any(|i| {
+        read_u32(gsub, feature_list + 2 + i * 6) == Some(feature)
+    })
+}
+
+/// Returns true if the table directory of the given OpenType font file lists any of the tables
+/// that hold color glyphs. Malformed files and font collections are treated as monochrome.
+pub fn font_data_has_color_tables(font_data: &[u8]) -> bool {
+    // The header is a 32-bit version followed by the number of tables and three 16-bit search
+    // hints. The directory that follows is made of 16-byte records starting with the table tag.
+    let table_count = match read_u16(font_data, 4) {
+        Some(count) => count,
+        None => return false,
+    };
+    range(0, table_count).any(|i| {
+        match read_u32(font_data, 12 + i * 16) {
+            Some(tag) => COLOR_FONT_TABLES.contains(&tag),
+            None => false,
+        }
+    })
+}
+
+fn read_u16(buf: &[u8], offset: uint) -> Option<uint> {
+    if offset + 2 > buf.len() {
+        return None
+    }
+    Some((buf[offset] as uint << 8) | buf[offset + 1] as uint)
+}
+
+fn read_u32(buf: &[u8], offset: uint) -> Option<u32> {
+    if offset + 4 > buf.len() {
+        return None
+    }
+    Some((buf[offset] as u32 << 24) | (buf[offset + 1] as u32 << 16) |
+         (buf[offset + 2] as u32 << 8) | buf[offset + 3] as u32)
+}
+
 pub trait FontTableTagConversions {
     fn tag_to_str(&self) -> String;
 }
@@ -84,6 +177,26 @@ pub struct FontMetrics {
     pub line_gap:         Au,
 }
 
+impl FontMetrics {
+    /// Metrics that are all zero, for text whose font couldn't be loaded.
+    pub fn zero() -> FontMetrics {
+        FontMetrics {
+            underline_size:   Au(0),
+            underline_offset: Au(0),
+            strikeout_size:   Au(0),
+            strikeout_offset: Au(0),
+            leading:          Au(0),
+            x_height:         Au(0),
+            em_size:          Au(0),
+            ascent:           Au(0),
+            descent:          Au(0),
+            max_advance:      Au(0),
+            average_advance:  Au(0),
+            line_gap:         Au(0),
+        }
+    }
+}
+
 pub type SpecifiedFontStyle = FontStyle;
 pub type UsedFontStyle = FontStyle;
 
@@ -91,6 +204,26 @@ pub struct Font {
     pub handle: FontHandle,
     pub metrics: FontMetrics,
     pub variant: font_variant::T,
+    /// Whether small caps come from the font's own `smcp` glyphs rather than from scaling
+    /// capitals down.
+    pub true_small_caps: bool,
+    /// Whether glyphs must be emboldened when drawn, because the font is much lighter than the
+    /// weight that was requested.
+    pub synthetic_bold: bool,
+    /// Whether glyphs must be slanted when drawn, because an italic face was requested but the
+    /// font is upright.
+    pub synthetic_oblique: bool,
+    /// Whether `font-synthesis` allows the font to be emboldened or slanted at all. If not, a
+    /// face that is too light or upright is drawn as it is.
+    pub synthesis: font_synthesis::T,
+    /// Whether the shaper may form ligatures. This is false for `font-variant-ligatures: none`.
+    pub ligatures: bool,
+    /// Whether the font has color glyphs, e.g. for emoji.
+    pub is_color: bool,
+    /// Whether the shaper favours speed or legibility, from `text-rendering`.
+    pub text_rendering: text_rendering::T,
+    /// The OpenType features that the shaper enables or disables, from `font-feature-settings`.
+    pub feature_settings: font_feature_settings::T,
     pub descriptor: FontTemplateDescriptor,
     pub requested_pt_size: Au,
     pub actual_pt_size: Au,
@@ -143,44 +276,121 @@ impl Font {
 
     pub fn glyph_index(&self, codepoint: char) -> Option<GlyphId> {
         let codepoint = match self.variant {
-            font_variant::small_caps => codepoint.to_uppercase(),
-            font_variant::normal => codepoint,
+            font_variant::small_caps if !self.true_small_caps => codepoint.to_uppercase(),
+            font_variant::small_caps | font_variant::normal => codepoint,
         };
         self.handle.glyph_index(codepoint)
     }
 
+    /// Returns true if this font may be used for the given codepoint and has a glyph for it.
+    pub fn covers(&self, codepoint: char) -> bool {
+        self.descriptor.covers(codepoint) && self.glyph_index(codepoint).is_some()
+    }
+
     pub fn glyph_h_kerning(&mut self, first_glyph: GlyphId, second_glyph: GlyphId) -> FractionalPixel {
         self.handle.glyph_h_kerning(first_glyph, second_glyph)
     }
 
     pub fn glyph_h_advance(&mut self, glyph: GlyphId) -> FractionalPixel {
         let handle = &self.handle;
-        self.glyph_advance_cache.find_or_create(&glyph, |glyph| {
-            match handle.glyph_h_advance(*glyph) {
-                Some(adv) => adv,
-                None => 10f64 as FractionalPixel // FIXME: Need fallback strategy
-            }
+        cached_glyph_advance(&mut self.glyph_advance_cache, glyph, |glyph| {
+            handle.glyph_h_advance(glyph)
         })
     }
+
+    /// Fills the advance cache with the glyphs of the given characters, so that shaping text
+    /// made of them doesn't have to ask the platform font for their advances. Characters that
+    /// the font has no glyph for are skipped.
+    pub fn warm_advances(&mut self, chars: &[char]) {
+        for &codepoint in chars.iter() {
+            match self.glyph_index(codepoint) {
+                Some(glyph) => {
+                    self.glyph_h_advance(glyph);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Returns the advance of `glyph` from `cache`, looking it up with `advance` and caching it on a
+/// miss.
+fn cached_glyph_advance(cache: &mut HashCache<u32, FractionalPixel>,
+                        glyph: GlyphId,
+                        advance: |GlyphId| -> Option<FractionalPixel>)
+                        -> FractionalPixel {
+    cache.find_or_create(&glyph, |glyph| {
+        match advance(*glyph) {
+            Some(adv) => adv,
+            None => 10f64 as FractionalPixel // FIXME: Need fallback strategy
+        }
+    })
 }
 
 pub struct FontGroup {
     pub fonts: SmallVec8<Rc<RefCell<Font>>>,
+    /// The font used for codepoints that none of `fonts` has a glyph for, if any.
+    pub last_resort: Option<Rc<RefCell<Font>>>,
 }
 
 impl FontGroup {
-    pub fn new(fonts: SmallVec8<Rc<RefCell<Font>>>) -> FontGroup {
+    pub fn new(fonts: SmallVec8<Rc<RefCell<Font>>>, last_resort: Option<Rc<RefCell<Font>>>)
+               -> FontGroup {
         FontGroup {
             fonts: fonts,
+            last_resort: last_resort,
         }
     }
 
+    /// Returns true if the group has no fonts. Groups made by a `FontContext` always have at
+    /// least one, if only the last resort font.
+    pub fn is_empty(&self) -> bool {
+        self.fonts.len() == 0
+    }
+
+    /// Returns the first font of the group, whose metrics stand for the group's as a whole.
+    /// Fails if the group is empty.
+    pub fn primary(&self) -> Rc<RefCell<Font>> {
+        primary_font(self.fonts.as_slice()).clone()
+    }
+
+    /// Returns the first font of the group that has a glyph for the given codepoint within its
+    /// unicode-range. If none does, returns the last resort font, or the first font if the group
+    /// has no last resort font.
+    pub fn font_for_char(&self, codepoint: char) -> Rc<RefCell<Font>> {
+        assert!(self.fonts.len() > 0);
+        select_font(self.fonts.as_slice(), self.last_resort.as_ref(), |font| {
+            font.borrow().covers(codepoint)
+        }).clone()
+    }
+
     pub fn create_textrun(&self, text: String) -> TextRun {
         assert!(self.fonts.len() > 0);
 
-        // TODO(Issue #177): Actually fall back through the FontGroup when a font is unsuitable.
-        TextRun::new(&mut *self.fonts.get(0).borrow_mut(), text.clone())
+        // TODO(Issue #177): Split the run wherever `font_for_char` picks another font, rather
+        // than shaping all of it with the primary one.
+        TextRun::new(&mut *self.primary().borrow_mut(), text.clone())
+    }
+}
+
+/// Returns the first of `fonts`, failing with a clear message rather than an index out of bounds
+/// if there are none.
+fn primary_font<'a, F>(fonts: &'a [F]) -> &'a F {
+    match fonts.head() {
+        Some(font) => font,
+        None => fail!("font group has no fonts, not even a last resort one"),
+    }
+}
+
+/// Returns the first of `fonts` that `covers` accepts, falling back to `last_resort` and then to
+/// the first of `fonts`.
+fn select_font<'a, F>(fonts: &'a [F], last_resort: Option<&'a F>, covers: |&F| -> bool) -> &'a F {
+    for font in fonts.iter() {
+        if covers(font) {
+            return font
+        }
     }
+    last_resort.unwrap_or(&fonts[0])
 }
 
 pub struct RunMetrics {
@@ -210,3 +420,103 @@ impl RunMetrics {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CLIG, LIGA, SMCP, font_table_tag, gsub_has_feature, primary_font, select_font};
+    use super::cached_glyph_advance;
+    use servo_util::cache::HashCache;
+    use super::font_data_has_color_tables;
+
+    /// Builds a GSUB table whose feature list contains the given tags.
+    fn gsub_with_features(tags: &[&[u8]]) -> Vec<u8> {
+        let mut gsub = vec!(0, 1, 0, 0,     // version 1.0
+                            0, 0,           // script list
+                            0, 10,          // feature list
+                            0, 0);          // lookup list
+        gsub.push_all([0, tags.len() as u8]);
+        for tag in tags.iter() {
+            gsub.push_all(*tag);
+            gsub.push_all([0, 0]);
+        }
+        gsub
+    }
+
+    #[test]
+    fn test_gsub_has_feature() {
+        assert!(gsub_has_feature(gsub_with_features([b"liga", b"smcp"]).as_slice(), SMCP));
+        assert!(!gsub_has_feature(gsub_with_features([b"liga", b"kern"]).as_slice(), SMCP));
+        assert!(!gsub_has_feature(gsub_with_features([]).as_slice(), SMCP));
+        assert!(!gsub_has_feature([0, 1, 0, 0, 0], SMCP));
+    }
+
+    /// Builds the header and table directory of a font file with the given tables.
+    fn font_data_with_tables(tags: &[&[u8]]) -> Vec<u8> {
+        let mut font_data = vec!(0, 1, 0, 0);   // TrueType outlines
+        font_data.push_all([0, tags.len() as u8]);
+        font_data.push_all([0, 0, 0, 0, 0, 0]);  // search hints
+        for tag in tags.iter() {
+            font_data.push_all(*tag);
+            font_data.push_all([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        font_data
+    }
+
+    #[test]
+    fn test_color_font_tables_are_detected() {
+        assert!(font_data_has_color_tables(font_data_with_tables([b"cmap", b"COLR", b"CPAL"])
+                                           .as_slice()));
+        assert!(font_data_has_color_tables(font_data_with_tables([b"sbix"]).as_slice()));
+        assert!(font_data_has_color_tables(font_data_with_tables([b"CBDT", b"CBLC"]).as_slice()));
+        assert!(!font_data_has_color_tables(font_data_with_tables([b"cmap", b"glyf", b"GSUB"])
+                                            .as_slice()));
+        assert!(!font_data_has_color_tables([0, 1, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_font_table_tag() {
+        assert_eq!(font_table_tag("smcp"), SMCP);
+        assert_eq!(font_table_tag("liga"), LIGA);
+        assert_eq!(font_table_tag("clig"), CLIG);
+    }
+
+    #[test]
+    fn test_char_covered_only_by_last_resort_uses_it() {
+        let fonts = vec!("abc", "def");
+        let last_resort = "\u4e2d";
+        let font = select_font(fonts.as_slice(), Some(&last_resort), |font| {
+            font.contains_char('\u4e2d')
+        });
+        assert_eq!(*font, last_resort);
+    }
+
+    #[test]
+    fn test_primary_font_is_the_first_one() {
+        let fonts = vec!(3u, 1, 2);
+        assert_eq!(*primary_font(fonts.as_slice()), 3);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_empty_group_has_no_primary_font() {
+        let fonts: Vec<uint> = vec!();
+        primary_font(fonts.as_slice());
+    }
+
+    #[test]
+    fn test_warmed_advances_are_cache_hits() {
+        let mut cache = HashCache::new();
+        let mut lookups = 0u;
+        for &glyph in [36u32, 37].iter() {
+            cached_glyph_advance(&mut cache, glyph, |_| { lookups += 1; Some(8.0) });
+        }
+        assert_eq!(lookups, 2);
+
+        // Shaping the warmed glyphs again doesn't ask the platform font.
+        assert_eq!(cached_glyph_advance(&mut cache, 36, |_| { lookups += 1; Some(9.0) }), 8.0);
+        assert_eq!(cached_glyph_advance(&mut cache, 37, |_| { lookups += 1; Some(9.0) }), 8.0);
+        assert_eq!(lookups, 2);
+        assert_eq!(cached_glyph_advance(&mut cache, 38, |_| { lookups += 1; Some(9.0) }), 9.0);
+        assert_eq!(lookups, 3);
+    }
+}
@@ -11,7 +11,7 @@ use platform::font_context::FontContextHandle;
 use collections::str::Str;
 use std::collections::HashMap;
 use sync::Arc;
-use font_template::{FontTemplate, FontTemplateDescriptor};
+use font_template::{FontTemplate, FontTemplateDescriptor, UnicodeRange};
 use platform::font_template::FontTemplateData;
 use servo_net::resource_task::{ResourceTask, load_whole_resource};
 use servo_util::task::spawn_named;
@@ -59,14 +59,15 @@ impl FontFamily {
         None
     }
 
-    fn add_template(&mut self, identifier: &str, maybe_data: Option<Vec<u8>>) {
+    fn add_template(&mut self, identifier: &str, maybe_data: Option<Vec<u8>>,
+                    unicode_range: Option<Vec<UnicodeRange>>) {
         for template in self.templates.iter() {
             if template.identifier() == identifier {
                 return;
             }
         }
 
-        let template = FontTemplate::new(identifier, maybe_data);
+        let template = FontTemplate::new(identifier, maybe_data, unicode_range);
         self.templates.push(template);
     }
 }
@@ -75,7 +76,7 @@ impl FontFamily {
 pub enum Command {
     GetFontTemplate(String, FontTemplateDescriptor, Sender<Reply>),
     GetLastResortFontTemplate(FontTemplateDescriptor, Sender<Reply>),
-    AddWebFont(String, Source, Sender<()>),
+    AddWebFont(String, Source, Option<Vec<UnicodeRange>>, Sender<()>),
     Exit(Sender<()>),
 }
 
@@ -120,7 +121,7 @@ impl FontCache {
                     let font_template = self.get_last_resort_font_template(&descriptor);
                     result.send(GetFontTemplateReply(Some(font_template)));
                 }
-                AddWebFont(family_name, src, result) => {
+                AddWebFont(family_name, src, unicode_range, result) => {
                     let family_name = LowercaseString::new(family_name.as_slice());
                     if !self.web_families.contains_key(&family_name) {
                         let family = FontFamily::new();
@@ -134,7 +135,8 @@ impl FontCache {
                             match maybe_resource {
                                 Ok((_, bytes)) => {
                                     let family = self.web_families.get_mut(&family_name);
-                                    family.add_template(url.to_string().as_slice(), Some(bytes));
+                                    family.add_template(url.to_string().as_slice(), Some(bytes),
+                                                        unicode_range);
                                 },
                                 Err(_) => {
                                     debug!("Failed to load web font: family={} url={}", family_name, url);
@@ -144,7 +146,8 @@ impl FontCache {
                         LocalSource(ref local_family_name) => {
                             let family = self.web_families.get_mut(&family_name);
                             get_variations_for_family(local_family_name.as_slice(), |path| {
-                                family.add_template(path.as_slice(), None);
+                                family.add_template(path.as_slice(), None,
+                                                    unicode_range.clone());
                             });
                         }
                     }
@@ -186,7 +189,7 @@ impl FontCache {
 
             if s.templates.len() == 0 {
                 get_variations_for_family(family_name.as_slice(), |path| {
-                    s.add_template(path.as_slice(), None);
+                    s.add_template(path.as_slice(), None, None);
                 });
             }
 
@@ -241,6 +244,25 @@ impl FontCache {
     }
 }
 
+/// The ways in which loading fonts through the font cache task can fail.
+#[deriving(Clone, PartialEq, Show)]
+pub enum FontCacheError {
+    /// The font cache task has exited or failed, so it can't answer any requests.
+    FontCacheTaskDied,
+    /// No font could be created for a style, not even the last resort font.
+    NoFontAvailable,
+}
+
+/// Returns the template in the font cache task's reply to a request, or an error if the task
+/// died before replying.
+fn template_reply(reply: Result<Reply, ()>)
+                  -> Result<Option<Arc<FontTemplateData>>, FontCacheError> {
+    match reply {
+        Ok(GetFontTemplateReply(data)) => Ok(data),
+        Err(()) => Err(FontCacheTaskDied),
+    }
+}
+
 /// The public interface to the font cache task, used exclusively by
 /// the per-thread/task FontContext structures.
 #[deriving(Clone)]
@@ -279,39 +301,47 @@ impl FontCacheTask {
         }
     }
 
-    pub fn get_font_template(&self, family: String, desc: FontTemplateDescriptor)
-                                                -> Option<Arc<FontTemplateData>> {
+    /// Returns a font cache task whose requests are sent to `chan` instead, so that tests can
+    /// answer them.
+    #[cfg(test)]
+    pub fn from_chan(chan: Sender<Command>) -> FontCacheTask {
+        FontCacheTask {
+            chan: chan,
+        }
+    }
 
+    /// Returns the template of the given family that best matches `desc`, if the family has
+    /// any. Fails if the font cache task has died.
+    pub fn get_font_template(&self, family: String, desc: FontTemplateDescriptor)
+                             -> Result<Option<Arc<FontTemplateData>>, FontCacheError> {
         let (response_chan, response_port) = channel();
-        self.chan.send(GetFontTemplate(family, desc, response_chan));
-
-        let reply = response_port.recv();
-
-        match reply {
-            GetFontTemplateReply(data) => {
-                data
-            }
+        match self.chan.send_opt(GetFontTemplate(family, desc, response_chan)) {
+            Ok(()) => {}
+            Err(_) => return Err(FontCacheTaskDied),
         }
+        template_reply(response_port.recv_opt())
     }
 
+    /// Returns the last resort template that best matches `desc`. Fails if the font cache task
+    /// has died, which it does when it can't find any last resort font, or if it has no
+    /// template to reply with.
     pub fn get_last_resort_font_template(&self, desc: FontTemplateDescriptor)
-                                                -> Arc<FontTemplateData> {
-
+                                         -> Result<Arc<FontTemplateData>, FontCacheError> {
         let (response_chan, response_port) = channel();
-        self.chan.send(GetLastResortFontTemplate(desc, response_chan));
-
-        let reply = response_port.recv();
-
-        match reply {
-            GetFontTemplateReply(data) => {
-                data.unwrap()
-            }
+        match self.chan.send_opt(GetLastResortFontTemplate(desc, response_chan)) {
+            Ok(()) => {}
+            Err(_) => return Err(FontCacheTaskDied),
+        }
+        match try!(template_reply(response_port.recv_opt())) {
+            Some(data) => Ok(data),
+            None => Err(NoFontAvailable),
         }
     }
 
-    pub fn add_web_font(&self, family: String, src: Source) {
+    pub fn add_web_font(&self, family: String, src: Source,
+                        unicode_range: Option<Vec<UnicodeRange>>) {
         let (response_chan, response_port) = channel();
-        self.chan.send(AddWebFont(family, src, response_chan));
+        self.chan.send(AddWebFont(family, src, unicode_range, response_chan));
         response_port.recv();
     }
 
@@ -321,3 +351,63 @@ impl FontCacheTask {
         response_port.recv();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FontCacheTask, FontCacheTaskDied, GetFontTemplate, GetFontTemplateReply};
+    use super::{GetLastResortFontTemplate, NoFontAvailable};
+    use font_template::FontTemplateDescriptor;
+    use style::computed_values::{font_stretch, font_weight};
+
+    fn regular_descriptor() -> FontTemplateDescriptor {
+        FontTemplateDescriptor::new(font_weight::Weight400, font_stretch::normal, false)
+    }
+
+    #[test]
+    fn test_dead_font_cache_task_returns_error() {
+        // The task is gone before the request is sent.
+        let (chan, port) = channel();
+        drop(port);
+        let font_cache_task = FontCacheTask {
+            chan: chan,
+        };
+        let template = font_cache_task.get_font_template("Arial".to_string(),
+                                                         regular_descriptor());
+        assert_eq!(template.err(), Some(FontCacheTaskDied));
+        let last_resort = font_cache_task.get_last_resort_font_template(regular_descriptor());
+        assert_eq!(last_resort.err(), Some(FontCacheTaskDied));
+
+        // The task dies after receiving the request, without replying.
+        let (chan, port) = channel();
+        let font_cache_task = FontCacheTask {
+            chan: chan,
+        };
+        spawn(proc() {
+            match port.recv() {
+                GetFontTemplate(_, _, reply_chan) => drop(reply_chan),
+                _ => fail!("unexpected command"),
+            }
+        });
+        let template = font_cache_task.get_font_template("Arial".to_string(),
+                                                         regular_descriptor());
+        assert_eq!(template.err(), Some(FontCacheTaskDied));
+    }
+
+    #[test]
+    fn test_missing_last_resort_template_is_an_error() {
+        let (chan, port) = channel();
+        let font_cache_task = FontCacheTask {
+            chan: chan,
+        };
+        spawn(proc() {
+            match port.recv() {
+                GetLastResortFontTemplate(_, reply_chan) => {
+                    reply_chan.send(GetFontTemplateReply(None))
+                }
+                _ => fail!("unexpected command"),
+            }
+        });
+        let last_resort = font_cache_task.get_last_resort_font_template(regular_descriptor());
+        assert_eq!(last_resort.err(), Some(NoFontAvailable));
+    }
+}
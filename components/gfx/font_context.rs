@@ -2,24 +2,31 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use font::{Font, FontGroup};
-use font::SpecifiedFontStyle;
+use font::{Font, FontGroup, FontMetrics, FontTableMethods, GSUB, SMCP, gsub_has_feature};
+use font::{DEFAULT_HINTING_MODE, HintingMode, SpecifiedFontStyle};
 use platform::font_context::FontContextHandle;
-use style::computed_values::{font_style, font_variant};
+use style::computed_values::{font_feature_settings, font_style, font_synthesis, font_variant};
+use style::computed_values::{font_variant_ligatures, font_weight, text_rendering};
 
-use font_cache_task::FontCacheTask;
+use font_cache_task::{FontCacheError, FontCacheTask, NoFontAvailable};
 use font_template::FontTemplateDescriptor;
 use platform::font_template::FontTemplateData;
 use font::FontHandleMethods;
-use platform::font::FontHandle;
-use servo_util::cache::HashCache;
+use platform::font::{FontHandle, FontTable};
+use servo_util::cache::{Cache, HashCache, LRUCache};
 use servo_util::smallvec::{SmallVec, SmallVec8};
 use servo_util::geometry::Au;
 use servo_util::arc_ptr_eq;
+use text::glyph::GlyphStore;
 
+use std::collections::{HashMap, HashSet};
+use std::default::Default;
+use std::hash;
+use std::hash::{Hash, Writer};
 use std::rc::Rc;
+use std::slice;
 use std::cell::RefCell;
-use sync::Arc;
+use sync::{Arc, Mutex, Weak};
 
 use azure::AzFloat;
 use azure::azure_hl::SkiaBackend;
@@ -29,35 +36,661 @@ use azure::scaled_font::ScaledFont;
 #[cfg(target_os="android")]
 use azure::scaled_font::FontData;
 
+// FIXME: Azure can't be told how to hint outlines, so the hinting mode of the font context only
+// reaches render fonts through the pixel grid that their glyphs are drawn at (see
+// `render_context`).
 #[cfg(target_os="linux")]
 #[cfg(target_os="android")]
-fn create_scaled_font(template: &Arc<FontTemplateData>, pt_size: Au) -> ScaledFont {
+fn create_monochrome_scaled_font(template: &Arc<FontTemplateData>, pt_size: Au) -> ScaledFont {
     ScaledFont::new(SkiaBackend, FontData(&template.bytes), pt_size.to_subpx() as AzFloat)
 }
 
 #[cfg(target_os="macos")]
-fn create_scaled_font(template: &Arc<FontTemplateData>, pt_size: Au) -> ScaledFont {
+fn create_monochrome_scaled_font(template: &Arc<FontTemplateData>, pt_size: Au) -> ScaledFont {
     let cgfont = template.ctfont.as_ref().unwrap().copy_to_CGFont();
     ScaledFont::new(SkiaBackend, &cgfont, pt_size.to_subpx() as AzFloat)
 }
 
-static SMALL_CAPS_SCALE_FACTOR: f64 = 0.8;      // Matches FireFox (see gfxFont.h)
+/// Creates the render font for the given template.
+///
+/// FIXME: Azure has no scaled fonts that draw color glyphs on any platform yet, so color fonts
+/// (see `FontTemplateData::is_color`) fall back to monochrome everywhere. Layout still knows them
+/// by `Font::is_color`, so only this function has to change once Azure can draw them.
+fn create_scaled_font(template: &Arc<FontTemplateData>, pt_size: Au) -> ScaledFont {
+    if template.is_color() {
+        debug!("Drawing the color font {} in monochrome", template.identifier);
+    }
+    create_monochrome_scaled_font(template, pt_size)
+}
+
+/// The default scale of fake small caps relative to the font size.
+pub static SMALL_CAPS_SCALE_FACTOR: f64 = 0.8;      // Matches FireFox (see gfxFont.h)
+
+/// The range that custom small caps scales are clamped to.
+static MIN_SMALL_CAPS_SCALE_FACTOR: f64 = 0.5;
+static MAX_SMALL_CAPS_SCALE_FACTOR: f64 = 1.0;
+
+/// The angle, in degrees, by which upright fonts are slanted to stand in for italic ones.
+pub static SYNTHETIC_OBLIQUE_ANGLE: f64 = 14.0;
+
+/// Returns true if a font must be slanted synthetically: an italic face was requested but the
+/// font is upright.
+fn needs_synthetic_oblique(requested_italic: bool, actual_italic: bool) -> bool {
+    requested_italic && !actual_italic
+}
+
+/// The reasons that a layout font can fail to be created.
+#[deriving(Clone, PartialEq, Show)]
+pub enum FontCreationError {
+    /// The platform couldn't create a font handle from the template.
+    FontHandleCreationFailed,
+}
+
+/// The writing systems that fallback families can be configured for, so that text in each one
+/// falls back to a family that covers it.
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+pub enum Script {
+    LatinScript,
+    GreekScript,
+    CyrillicScript,
+    ArabicScript,
+    HebrewScript,
+    DevanagariScript,
+    ThaiScript,
+    HanScript,
+    HiraganaScript,
+    KatakanaScript,
+    HangulScript,
+}
 
-struct LayoutFontCacheEntry {
-    family: String,
-    font: Option<Rc<RefCell<Font>>>,
+/// Returns the families to fall back to for text in `script`: the family configured for that
+/// script, if any, followed by the generic `fallback_families`.
+fn fallback_families_for_script(script_families: &HashMap<Script, String>,
+                                script: Option<Script>,
+                                fallback_families: &[String])
+                                -> Vec<String> {
+    let script_family = script.and_then(|script| script_families.find(&script));
+    script_family.into_iter().chain(fallback_families.iter()).map(|family| {
+        family.clone()
+    }).collect()
 }
 
-struct FallbackFontCacheEntry {
-    font: Rc<RefCell<Font>>,
+/// Converts the result of creating a platform font handle into a `FontCreationError` result.
+fn handle_or_error<H>(handle: Result<H, ()>) -> Result<H, FontCreationError> {
+    handle.map_err(|()| FontHandleCreationFailed)
 }
 
-/// A cached azure font (per render task) that
-/// can be shared by multiple text runs.
-struct RenderFontCacheEntry {
+/// Returns the given size raised to the minimum font size, if there is one.
+fn apply_minimum_font_size(pt_size: Au, minimum_font_size: Option<Au>) -> Au {
+    match minimum_font_size {
+        Some(minimum_font_size) if pt_size < minimum_font_size => minimum_font_size,
+        _ => pt_size,
+    }
+}
+
+/// Returns the size at which to instantiate a font of the given variant, and whether its small
+/// caps come from the font's own glyphs. Fonts without the `smcp` feature fake small caps by
+/// rendering capitals at `small_caps_scale` times the size. This is decided for each font of a
+/// group on its own, so a group can mix true and fake small caps.
+fn actual_pt_size_for_variant(pt_size: Au,
+                              variant: font_variant::T,
+                              has_true_small_caps: bool,
+                              small_caps_scale: f64)
+                              -> (Au, bool) {
+    match variant {
+        font_variant::small_caps if has_true_small_caps => (pt_size, true),
+        font_variant::small_caps => (pt_size.scale_by(small_caps_scale), false),
+        font_variant::normal => (pt_size, false),
+    }
+}
+
+/// The default number of weight units, e.g. 300 between normal and bold, by which a font must be
+/// lighter than requested for it to be emboldened synthetically.
+pub static DEFAULT_SYNTHETIC_BOLD_THRESHOLD: uint = 300;
+
+/// Returns the numeric value of a font weight, from 100 to 900.
+fn weight_value(weight: font_weight::T) -> uint {
+    match weight {
+        font_weight::Weight100 => 100,
+        font_weight::Weight200 => 200,
+        font_weight::Weight300 => 300,
+        font_weight::Weight400 => 400,
+        font_weight::Weight500 => 500,
+        font_weight::Weight600 => 600,
+        font_weight::Weight700 => 700,
+        font_weight::Weight800 => 800,
+        font_weight::Weight900 => 900,
+    }
+}
+
+/// Returns true if a font of weight `actual` must be emboldened to stand in for the `requested`
+/// weight, i.e. if it is lighter by at least `threshold`.
+fn needs_synthetic_bold(requested: font_weight::T, actual: font_weight::T, threshold: uint)
+                        -> bool {
+    let (requested, actual) = (weight_value(requested), weight_value(actual));
+    requested > actual && requested - actual >= threshold
+}
+
+/// Returns whether a font whose face has the given weight and style must be emboldened and
+/// whether it must be slanted to stand in for `descriptor`. Neither is synthesized if
+/// `synthesis` disallows it.
+fn synthetic_bold_and_oblique(descriptor: &FontTemplateDescriptor,
+                              actual_weight: font_weight::T,
+                              actual_italic: bool,
+                              threshold: uint,
+                              synthesis: font_synthesis::T)
+                              -> (bool, bool) {
+    (synthesis.weight && needs_synthetic_bold(descriptor.weight, actual_weight, threshold),
+     synthesis.style && needs_synthetic_oblique(descriptor.italic, actual_italic))
+}
+
+/// Returns true if the given GSUB table of a font, if it has one, has the OpenType `smcp`
+/// feature.
+fn has_true_small_caps(gsub: Option<FontTable>) -> bool {
+    match gsub {
+        None => false,
+        Some(gsub) => {
+            let mut result = false;
+            gsub.with_buffer(|buf, len| {
+                result = unsafe {
+                    slice::raw::buf_as_slice(buf, len, |gsub| gsub_has_feature(gsub, SMCP))
+                };
+            });
+            result
+        }
+    }
+}
+
+/// The default number of bytes that the render fonts of a `FontContext` may take up.
+pub static DEFAULT_RENDER_FONT_CACHE_BUDGET: uint = 4 * 1024 * 1024;
+
+/// The approximate cost of a render font, excluding its glyph cache.
+static RENDER_FONT_BASE_COST: uint = 4096;
+
+/// The number of rasterized glyphs assumed to be cached for each render font.
+static ESTIMATED_CACHED_GLYPHS: uint = 128;
+
+/// Approximates the number of bytes taken up by a render font of the given size, assuming one
+/// byte per pixel for each of its cached glyphs.
+fn estimated_render_font_cost(pt_size: Au) -> uint {
+    let px = pt_size.to_subpx().ceil() as uint;
+    RENDER_FONT_BASE_COST + ESTIMATED_CACHED_GLYPHS * px * px
+}
+
+/// The approximate cost of a layout font, excluding its template and shape caches.
+static LAYOUT_FONT_BASE_COST: uint = 1024;
+
+/// Approximates the number of bytes taken up by layout fonts created from templates with the
+/// given identifiers and byte lengths. Fonts from the same template share its data, so each
+/// template is only counted once.
+fn estimated_layout_fonts_cost<I: Iterator<(String, uint)>>(templates: I) -> uint {
+    let mut counted_templates = HashSet::new();
+    templates.fold(0, |cost, (identifier, byte_len)| {
+        let template_cost = if counted_templates.insert(identifier) { byte_len } else { 0 };
+        cost + LAYOUT_FONT_BASE_COST + template_cost
+    })
+}
+
+/// A family name in a layout font cache key, hashed once up front. Keys of different families
+/// then almost always differ by hash alone, so the names are only compared in full when the
+/// hashes are equal, and the hash map hashes a `u64` rather than the whole name.
+#[deriving(Clone)]
+struct FamilyKey {
+    hash: u64,
+    name: String,
+}
+
+impl FamilyKey {
+    fn new(name: &str) -> FamilyKey {
+        FamilyKey {
+            hash: family_hash(name),
+            name: name.to_string(),
+        }
+    }
+
+    /// Returns true if this is the key of the family with the given name and `family_hash`.
+    fn matches(&self, hash: u64, name: &str) -> bool {
+        self.hash == hash && self.name.as_slice() == name
+    }
+}
+
+impl PartialEq for FamilyKey {
+    fn eq(&self, other: &FamilyKey) -> bool {
+        self.matches(other.hash, other.name.as_slice())
+    }
+}
+
+impl Eq for FamilyKey {}
+
+impl<S: Writer> Hash<S> for FamilyKey {
+    fn hash(&self, state: &mut S) {
+        self.hash.hash(state);
+    }
+}
+
+fn family_hash(name: &str) -> u64 {
+    hash::hash(&name)
+}
+
+/// The key under which layout fonts are cached: a family name together with everything else
+/// that affects the font created for it.
+type LayoutFontCacheKey = (FamilyKey, FontTemplateDescriptor, Au, font_variant::T, bool,
+                          text_rendering::T, font_feature_settings::T, font_synthesis::T,
+                          HintingMode);
+
+fn layout_font_cache_key(family: &str,
+                         descriptor: &FontTemplateDescriptor,
+                         pt_size: Au,
+                         variant: font_variant::T,
+                         ligatures: bool,
+                         text_rendering: text_rendering::T,
+                         feature_settings: &font_feature_settings::T,
+                         synthesis: font_synthesis::T,
+                         hinting: HintingMode)
+                         -> LayoutFontCacheKey {
+    (FamilyKey::new(family), descriptor.clone(), pt_size, variant, ligatures, text_rendering,
+     feature_settings.clone(), synthesis, hinting)
+}
+
+/// Removes the entries of the given family from a layout font cache and returns their values.
+fn remove_family<V>(cache: &mut HashMap<LayoutFontCacheKey, V>, family: &str) -> Vec<V> {
+    let hash = family_hash(family);
+    let stale_keys: Vec<LayoutFontCacheKey> = cache.keys().filter(|key| {
+        key.ref0().matches(hash, family)
+    }).map(|key| key.clone()).collect();
+    stale_keys.iter().filter_map(|key| cache.pop(key)).collect()
+}
+
+/// Lists the fonts in a layout font cache by family and then size, each descriptor and size of a
+/// family only once. Fonts that the font cache task had no template for are listed too.
+fn list_layout_fonts<V>(cache: &HashMap<LayoutFontCacheKey, Option<V>>) -> Vec<CachedLayoutFont> {
+    let mut fonts: Vec<CachedLayoutFont> = vec!();
+    for (key, font) in cache.iter() {
+        let font = CachedLayoutFont {
+            family: key.ref0().name.clone(),
+            descriptor: key.ref1().clone(),
+            pt_size: *key.ref2(),
+            found: font.is_some(),
+        };
+        if !fonts.contains(&font) {
+            fonts.push(font);
+        }
+    }
+    fonts.sort_by(|a, b| (&a.family, a.pt_size).cmp(&(&b.family, b.pt_size)));
+    fonts
+}
+
+/// Returns the result of `lookup` for the first of `families` that it finds a font for.
+fn first_available<T>(families: &[String], lookup: |&str| -> Option<T>) -> Option<T> {
+    for family in families.iter() {
+        match lookup(family.as_slice()) {
+            Some(font) => return Some(font),
+            None => {}
+        }
+    }
+    None
+}
+
+/// The key under which last resort fonts are cached. This is the layout font cache key without
+/// the family, since there is only one last resort template for each descriptor.
+type FallbackFontCacheKey = (FontTemplateDescriptor, Au, font_variant::T, bool,
+                             text_rendering::T, font_feature_settings::T, font_synthesis::T);
+
+fn fallback_font_cache_key(descriptor: &FontTemplateDescriptor,
+                           pt_size: Au,
+                           variant: font_variant::T,
+                           ligatures: bool,
+                           text_rendering: text_rendering::T,
+                           feature_settings: &font_feature_settings::T,
+                           synthesis: font_synthesis::T)
+                           -> FallbackFontCacheKey {
+    (descriptor.clone(), pt_size, variant, ligatures, text_rendering, feature_settings.clone(),
+     synthesis)
+}
+
+/// The default number of last resort fonts that a `FontContext` keeps around.
+pub static DEFAULT_FALLBACK_FONT_CACHE_SIZE: uint = 16;
+
+/// Everything that tells render fonts created from the same template apart.
+#[deriving(Clone, PartialEq)]
+struct RenderFontCacheKey {
     pt_size: Au,
     identifier: String,
-    font: Rc<RefCell<ScaledFont>>,
+    descriptor: FontTemplateDescriptor,
+    synthetic_bold: bool,
+    synthetic_oblique: bool,
+    feature_settings: font_feature_settings::T,
+    subpixel_positioning: bool,
+    hinting: HintingMode,
+}
+
+/// A cached azure font (per render task) that
+/// can be shared by multiple text runs. The font is freed once no text run uses it.
+struct RenderFontCacheEntry<F> {
+    key: RenderFontCacheKey,
+    font: Weak<F>,
+    /// The approximate number of bytes taken up by the font.
+    cost: uint,
+}
+
+/// Counts of how often the caches of a `FontContext` could answer a lookup themselves.
+#[deriving(Clone, PartialEq, Show)]
+pub struct FontCacheStats {
+    pub layout_cache_hits: uint,
+    pub layout_cache_misses: uint,
+    pub fallback_cache_hits: uint,
+    pub fallback_cache_misses: uint,
+    pub render_cache_hits: uint,
+    pub render_cache_misses: uint,
+    /// The number of font groups found in the font group cache rather than built from fonts.
+    pub font_group_cache_hits: uint,
+    /// The number of font templates requested from the font cache task.
+    pub font_cache_task_round_trips: uint,
+}
+
+impl FontCacheStats {
+    fn new() -> FontCacheStats {
+        FontCacheStats {
+            layout_cache_hits: 0,
+            layout_cache_misses: 0,
+            fallback_cache_hits: 0,
+            fallback_cache_misses: 0,
+            render_cache_hits: 0,
+            render_cache_misses: 0,
+            font_group_cache_hits: 0,
+            font_cache_task_round_trips: 0,
+        }
+    }
+}
+
+/// A font in the layout font cache of a `FontContext`, as listed by `cached_layout_fonts`.
+#[deriving(Clone, PartialEq)]
+pub struct CachedLayoutFont {
+    pub family: String,
+    pub descriptor: FontTemplateDescriptor,
+    pub pt_size: Au,
+    /// Whether the font cache task had a template for the family. Families that it didn't have
+    /// are cached too, so that it isn't asked again.
+    pub found: bool,
+}
+
+/// The render fonts of a `FontContext`, least recently used first. Fonts are evicted once their
+/// total cost exceeds the budget. This is generic over the font type so that it doesn't need
+/// real azure fonts to be tested.
+struct RenderFontCache<F> {
+    entries: Vec<RenderFontCacheEntry<F>>,
+    total_cost: uint,
+    budget: uint,
+    hits: uint,
+    misses: uint,
+}
+
+impl<F: Send + Sync> RenderFontCache<F> {
+    fn new(budget: uint) -> RenderFontCache<F> {
+        RenderFontCache {
+            entries: vec!(),
+            total_cost: 0,
+            budget: budget,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached font for the given key, if any, marking it as the most recently used.
+    /// Entries whose fonts have been freed are dropped along the way.
+    fn find(&mut self, key: &RenderFontCacheKey) -> Option<Arc<F>> {
+        let mut total_cost = self.total_cost;
+        self.entries.retain(|entry| {
+            let alive = entry.font.upgrade().is_some();
+            if !alive {
+                total_cost -= entry.cost;
+            }
+            alive
+        });
+        self.total_cost = total_cost;
+
+        let index = self.entries.iter().position(|entry| entry.key == *key);
+        match index {
+            Some(index) => {
+                self.hits += 1;
+                let entry = self.entries.remove(index).unwrap();
+                let font = entry.font.upgrade().unwrap();
+                self.entries.push(entry);
+                Some(font)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Removes the cached fonts created from any of the templates with the given identifiers.
+    fn remove_identifiers(&mut self, identifiers: &[String]) {
+        let mut total_cost = self.total_cost;
+        self.entries.retain(|entry| {
+            let stale = identifiers.contains(&entry.key.identifier);
+            if stale {
+                total_cost -= entry.cost;
+            }
+            !stale
+        });
+        self.total_cost = total_cost;
+    }
+
+    /// Removes all the cached fonts.
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.total_cost = 0;
+    }
+
+    /// Returns an empty cache with the same budget as this one, and no hits or misses so far.
+    fn empty_copy(&self) -> RenderFontCache<F> {
+        RenderFontCache::new(self.budget)
+    }
+
+    /// Adds a font, then evicts the least recently used fonts until the cache is back within its
+    /// budget. The font just added is never evicted, even if it exceeds the budget on its own.
+    /// The cache doesn't keep the font alive.
+    fn insert(&mut self, key: RenderFontCacheKey, font: &Arc<F>, cost: uint) {
+        self.entries.push(RenderFontCacheEntry {
+            key: key,
+            font: font.downgrade(),
+            cost: cost,
+        });
+        self.total_cost += cost;
+
+        while self.total_cost > self.budget && self.entries.len() > 1 {
+            let evicted = self.entries.remove(0).unwrap();
+            self.total_cost -= evicted.cost;
+        }
+    }
+}
+
+/// Render fonts shared by the font contexts of several render tasks, so that they don't each
+/// create the same fonts. Like the cache of each context, this doesn't keep the fonts alive.
+pub struct SharedRenderFontCache {
+    fonts: RenderFontCache<ScaledFont>,
+}
+
+impl SharedRenderFontCache {
+    /// Creates a shared cache whose fonts take up about `budget` bytes at most.
+    pub fn new(budget: uint) -> SharedRenderFontCache {
+        SharedRenderFontCache {
+            fonts: RenderFontCache::new(budget),
+        }
+    }
+}
+
+/// Returns the render font for the given key from the local cache, or else from the shared one,
+/// if any. The font is only created if neither has it, and is then added to both.
+fn find_or_create_render_font<F: Send + Sync>(local: &mut RenderFontCache<F>,
+                                              shared: Option<&mut RenderFontCache<F>>,
+                                              key: RenderFontCacheKey,
+                                              cost: uint,
+                                              create: || -> F)
+                                              -> Arc<F> {
+    match local.find(&key) {
+        Some(font) => return font,
+        None => {}
+    }
+
+    let font = match shared {
+        Some(shared) => {
+            match shared.find(&key) {
+                Some(font) => font,
+                None => {
+                    let font = Arc::new(create());
+                    shared.insert(key.clone(), &font, cost);
+                    font
+                }
+            }
+        }
+        None => Arc::new(create()),
+    };
+    local.insert(key, &font, cost);
+    font
+}
+
+/// Everything that tells apart the glyphs of the same text shaped with different fonts.
+#[deriving(Clone, PartialEq)]
+struct ShapeCacheKey {
+    identifier: String,
+    pt_size: Au,
+    true_small_caps: bool,
+    ligatures: bool,
+    text_rendering: text_rendering::T,
+    feature_settings: font_feature_settings::T,
+    hinting: HintingMode,
+    text: String,
+}
+
+fn shape_cache_key(font: &Font, text: &str) -> ShapeCacheKey {
+    ShapeCacheKey {
+        identifier: font.handle.get_template().identifier.clone(),
+        pt_size: font.actual_pt_size,
+        true_small_caps: font.true_small_caps,
+        ligatures: font.ligatures,
+        text_rendering: font.text_rendering,
+        feature_settings: font.feature_settings.clone(),
+        hinting: font.handle.hinting,
+        text: text.to_string(),
+    }
+}
+
+/// The default number of shaped runs that a `SharedShapeCache` keeps around.
+pub static DEFAULT_SHAPE_CACHE_SIZE: uint = 256;
+
+/// The most recently shaped runs, least recently used first. This is generic over the glyph
+/// type so that it can be tested without shaping real text.
+struct ShapeCache<G> {
+    runs: LRUCache<ShapeCacheKey, Arc<G>>,
+    hits: uint,
+    misses: uint,
+}
+
+impl<G: Send + Sync> ShapeCache<G> {
+    fn new(size: uint) -> ShapeCache<G> {
+        ShapeCache {
+            runs: LRUCache::new(size),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached glyphs for the given key, or else the result of `shape`, which is then
+    /// cached.
+    fn find_or_shape(&mut self, key: ShapeCacheKey, shape: || -> Arc<G>) -> Arc<G> {
+        match self.runs.find(&key) {
+            Some(glyphs) => {
+                self.hits += 1;
+                return glyphs
+            }
+            None => {}
+        }
+        self.misses += 1;
+        let glyphs = shape();
+        self.runs.insert(key, glyphs.clone());
+        glyphs
+    }
+}
+
+/// Shaped runs shared by the font contexts of several tasks, so that repeated strings are only
+/// shaped once. The shape cache of each font is still consulted on a miss.
+pub struct SharedShapeCache {
+    runs: ShapeCache<GlyphStore>,
+}
+
+impl SharedShapeCache {
+    /// Creates a shared cache that keeps `size` shaped runs at most.
+    pub fn new(size: uint) -> SharedShapeCache {
+        SharedShapeCache {
+            runs: ShapeCache::new(size),
+        }
+    }
+}
+
+/// Text shaped by `FontContext::shape_run`, together with the font that shaped it.
+pub struct ShapedRun {
+    pub font: Rc<RefCell<Font>>,
+    pub glyphs: Arc<GlyphStore>,
+}
+
+/// The number of font groups that a `FontContext` keeps around for recently used styles.
+static FONT_GROUP_CACHE_SIZE: uint = 8;
+
+/// The font groups of the most recently used styles, least recently used first. Styles are
+/// compared by pointer first, and then by value, so that equal styles share a font group even
+/// when they weren't shared by the style system. This is generic so that it can be tested without
+/// real styles.
+struct FontGroupCache<S, G> {
+    entries: Vec<(Arc<S>, Rc<G>)>,
+    capacity: uint,
+}
+
+impl<S: 'static + Send + Sync + PartialEq, G> FontGroupCache<S, G> {
+    fn new(capacity: uint) -> FontGroupCache<S, G> {
+        FontGroupCache {
+            entries: vec!(),
+            capacity: capacity,
+        }
+    }
+
+    /// Returns the font group of the given style, if cached, marking it as the most recently
+    /// used.
+    fn find(&mut self, style: &Arc<S>) -> Option<Rc<G>> {
+        let mut index = self.entries.iter().position(|&(ref cached_style, _)| {
+            arc_ptr_eq(style, cached_style)
+        });
+
+        // An equal style that isn't the same instance shares the font group, and takes the place
+        // of the cached style so that it is found by pointer from now on.
+        if index.is_none() {
+            index = self.entries.iter().position(|&(ref cached_style, _)| {
+                **cached_style == **style
+            });
+        }
+        match index {
+            Some(index) => {
+                let (_, font_group) = self.entries.remove(index).unwrap();
+                self.entries.push((style.clone(), font_group.clone()));
+                Some(font_group)
+            }
+            None => None,
+        }
+    }
+
+    /// Adds the font group of a style, evicting the least recently used one if the cache is full.
+    fn insert(&mut self, style: Arc<S>, font_group: Rc<G>) {
+        self.entries.push((style, font_group));
+        if self.entries.len() > self.capacity {
+            self.entries.remove(0);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
 }
 
 /// The FontContext represents the per-thread/task state necessary for
@@ -68,187 +701,655 @@ pub struct FontContext {
     platform_handle: FontContextHandle,
     font_cache_task: FontCacheTask,
 
-    /// TODO: See bug https://github.com/servo/servo/issues/3300.
-    layout_font_cache: Vec<LayoutFontCacheEntry>,
-    fallback_font_cache: Vec<FallbackFontCacheEntry>,
+    /// The layout fonts created so far. `None` records that the font cache task has no template
+    /// for the family, so that it isn't asked again.
+    layout_font_cache: HashMap<LayoutFontCacheKey, Option<Rc<RefCell<Font>>>>,
+
+    /// The most recently used last resort fonts. Evicted fonts are dropped along with the cache's
+    /// reference to them.
+    fallback_font_cache: LRUCache<FallbackFontCacheKey, Rc<RefCell<Font>>>,
+
+    /// Weak references, so that render fonts are freed along with the last text run using them.
+    render_font_cache: RenderFontCache<ScaledFont>,
+
+    /// The render font cache shared with other render tasks, if any. It is consulted before
+    /// creating a render font that isn't in `render_font_cache`.
+    shared_render_font_cache: Option<Arc<Mutex<SharedRenderFontCache>>>,
+
+    font_group_cache: FontGroupCache<SpecifiedFontStyle, FontGroup>,
+
+    /// How much lighter than requested a font must be to be emboldened synthetically.
+    synthetic_bold_threshold: uint,
+
+    /// The scale of fake small caps relative to the font size.
+    small_caps_scale: f64,
+
+    /// Hit and miss counts of the layout and fallback caches. The render cache keeps its own.
+    stats: FontCacheStats,
+
+    /// The families to try, in order, before the last resort font when none of the families of
+    /// a style are available.
+    fallback_families: Vec<String>,
+
+    /// The family to try first, before `fallback_families`, for text in each script.
+    script_fallback_families: HashMap<Script, String>,
+
+    /// The size below which fonts are never rendered, whatever their style asks for.
+    minimum_font_size: Option<Au>,
+
+    /// The identifier of the last resort font most recently used for a style, for diagnostics.
+    last_fallback_identifier: Option<String>,
 
-    /// Strong reference as the render FontContext is (for now) recycled
-    /// per frame. TODO: Make this weak when incremental redraw is done.
-    render_font_cache: Vec<RenderFontCacheEntry>,
+    /// The shape cache shared with other font contexts, if any. See `shape_run`.
+    shared_shape_cache: Option<Arc<Mutex<SharedShapeCache>>>,
 
-    last_style: Option<Arc<SpecifiedFontStyle>>,
-    last_fontgroup: Option<Rc<FontGroup>>,
+    /// Whether glyphs may be drawn at fractional pixel positions, rather than snapped to whole
+    /// pixels.
+    subpixel_positioning: bool,
+
+    /// How the glyphs of the fonts created by this context are hinted.
+    hinting: HintingMode,
+}
+
+/// How a `FontContext` loads, caches and renders fonts.
+#[deriving(Clone)]
+pub struct FontContextOptions {
+    /// About how many bytes the render fonts of the context may take up at most.
+    pub render_font_cache_budget: uint,
+    /// A render font cache shared with other render tasks, if any. Render fonts are looked up in
+    /// it before being created.
+    pub shared_render_font_cache: Option<Arc<Mutex<SharedRenderFontCache>>>,
+    /// The scale of fake small caps relative to the font size, clamped to between 0.5 and 1.0,
+    /// or `SMALL_CAPS_SCALE_FACTOR` if `None`.
+    pub small_caps_scale: Option<f64>,
+    /// The families that text in none of the families of its style tries, in order, before the
+    /// last resort font.
+    pub fallback_families: Vec<String>,
+    /// The size that smaller fonts are rendered at instead, if any.
+    pub minimum_font_size: Option<Au>,
+    /// Whether glyphs may be drawn at fractional pixel positions rather than snapped to whole
+    /// pixels.
+    pub subpixel_positioning: bool,
+    /// How the glyphs of both layout and render fonts are hinted.
+    pub hinting: HintingMode,
+}
+
+impl Default for FontContextOptions {
+    fn default() -> FontContextOptions {
+        FontContextOptions {
+            render_font_cache_budget: DEFAULT_RENDER_FONT_CACHE_BUDGET,
+            shared_render_font_cache: None,
+            small_caps_scale: None,
+            fallback_families: vec!(),
+            minimum_font_size: None,
+            subpixel_positioning: true,
+            hinting: DEFAULT_HINTING_MODE,
+        }
+    }
 }
 
 impl FontContext {
-    pub fn new(font_cache_task: FontCacheTask) -> FontContext {
+    /// Creates a font context that loads fonts from `font_cache_task` as `options` asks.
+    pub fn new(font_cache_task: FontCacheTask, options: FontContextOptions) -> FontContext {
+        let small_caps_scale = options.small_caps_scale.unwrap_or(SMALL_CAPS_SCALE_FACTOR)
+                                                       .max(MIN_SMALL_CAPS_SCALE_FACTOR)
+                                                       .min(MAX_SMALL_CAPS_SCALE_FACTOR);
         let handle = FontContextHandle::new();
         FontContext {
             platform_handle: handle,
             font_cache_task: font_cache_task,
-            layout_font_cache: vec!(),
-            fallback_font_cache: vec!(),
-            render_font_cache: vec!(),
-            last_style: None,
-            last_fontgroup: None,
+            layout_font_cache: HashMap::new(),
+            fallback_font_cache: LRUCache::new(DEFAULT_FALLBACK_FONT_CACHE_SIZE),
+            render_font_cache: RenderFontCache::new(options.render_font_cache_budget),
+            shared_render_font_cache: options.shared_render_font_cache,
+            font_group_cache: FontGroupCache::new(FONT_GROUP_CACHE_SIZE),
+            synthetic_bold_threshold: DEFAULT_SYNTHETIC_BOLD_THRESHOLD,
+            small_caps_scale: small_caps_scale,
+            stats: FontCacheStats::new(),
+            fallback_families: options.fallback_families,
+            script_fallback_families: HashMap::new(),
+            minimum_font_size: options.minimum_font_size,
+            last_fallback_identifier: None,
+            shared_shape_cache: None,
+            subpixel_positioning: options.subpixel_positioning,
+            hinting: options.hinting,
         }
     }
 
-    /// Create a font for use in layout calculations.
+    /// Returns a font context for a render task that shares the font cache task and platform
+    /// handle of this one, and its configuration, but none of its cached fonts. Its render cache
+    /// can then be recycled every frame without dropping the layout fonts of this context. The
+    /// fallback font cache of the new context has the default size.
+    ///
+    /// The platform handle isn't sendable, so the new context has to be used on this task.
+    pub fn clone_for_render(&self) -> FontContext {
+        FontContext {
+            platform_handle: self.platform_handle.clone(),
+            font_cache_task: self.font_cache_task.clone(),
+            layout_font_cache: HashMap::new(),
+            fallback_font_cache: LRUCache::new(DEFAULT_FALLBACK_FONT_CACHE_SIZE),
+            render_font_cache: self.render_font_cache.empty_copy(),
+            shared_render_font_cache: self.shared_render_font_cache.clone(),
+            font_group_cache: FontGroupCache::new(self.font_group_cache.capacity),
+            synthetic_bold_threshold: self.synthetic_bold_threshold,
+            small_caps_scale: self.small_caps_scale,
+            stats: FontCacheStats::new(),
+            fallback_families: self.fallback_families.clone(),
+            script_fallback_families: self.script_fallback_families.clone(),
+            minimum_font_size: self.minimum_font_size,
+            last_fallback_identifier: None,
+            shared_shape_cache: self.shared_shape_cache.clone(),
+            subpixel_positioning: self.subpixel_positioning,
+            hinting: self.hinting,
+        }
+    }
+
+    /// Returns true if glyphs may be drawn at fractional pixel positions.
+    pub fn subpixel_positioning(&self) -> bool {
+        self.subpixel_positioning
+    }
+
+    /// Returns how the glyphs of the fonts created by this context are hinted.
+    pub fn hinting(&self) -> HintingMode {
+        self.hinting
+    }
+
+    /// Makes text in `script` whose style names no available family fall back to `family`
+    /// before the generic fallback families. See `get_layout_font_group_for_script`.
+    pub fn set_fallback_for_script(&mut self, script: Script, family: String) {
+        self.script_fallback_families.insert(script, family);
+    }
+
+    /// Sets how many last resort fonts are kept around at most. This drops the cached ones.
+    pub fn set_fallback_font_cache_size(&mut self, size: uint) {
+        self.fallback_font_cache = LRUCache::new(size);
+    }
+
+    /// Makes `shape_run` consult the given shape cache, which may be shared with other font
+    /// contexts, before shaping text itself.
+    pub fn set_shared_shape_cache(&mut self, shared_shape_cache: Arc<Mutex<SharedShapeCache>>) {
+        self.shared_shape_cache = Some(shared_shape_cache);
+    }
+
+    /// Sets how many weight units lighter than requested a font must be for it to be emboldened
+    /// synthetically. This drops the cached layout fonts, which were created with the old value.
+    pub fn set_synthetic_bold_threshold(&mut self, threshold: uint) {
+        self.synthetic_bold_threshold = threshold;
+        self.clear_caches();
+    }
+
+    /// Create a font for use in layout calculations. The font keeps `pt_size` as its requested
+    /// size even if it is rendered at the minimum font size.
     fn create_layout_font(&self, template: Arc<FontTemplateData>,
                             descriptor: FontTemplateDescriptor, pt_size: Au,
-                            variant: font_variant::T) -> Font {
-        // TODO: (Bug #3463): Currently we only support fake small-caps
-        // rendering. We should also support true small-caps (where the
-        // font supports it) in the future.
-        let actual_pt_size = match variant {
-            font_variant::small_caps => pt_size.scale_by(SMALL_CAPS_SCALE_FACTOR),
-            font_variant::normal => pt_size,
+                            variant: font_variant::T,
+                            ligatures: bool,
+                            text_rendering: text_rendering::T,
+                            feature_settings: font_feature_settings::T,
+                            synthesis: font_synthesis::T)
+                            -> Result<Font, FontCreationError> {
+        // The style doesn't know which codepoints the face is limited to; only the template
+        // does, as its `@font-face` rule gave them.
+        let mut descriptor = descriptor;
+        descriptor.unicode_range = template.unicode_range.clone();
+        let rendered_pt_size = apply_minimum_font_size(pt_size, self.minimum_font_size);
+        let is_color = template.is_color();
+        let mut handle: FontHandle = try!(handle_or_error(FontHandleMethods::new_from_template(
+            &self.platform_handle, template.clone(), Some(rendered_pt_size))));
+        handle.set_hinting(self.hinting);
+        let supports_small_caps = match variant {
+            font_variant::small_caps => has_true_small_caps(handle.get_table_for_tag(GSUB)),
+            font_variant::normal => false,
         };
-
-        let handle: FontHandle = FontHandleMethods::new_from_template(&self.platform_handle,
-                                    template, Some(actual_pt_size)).unwrap();
+        let (actual_pt_size, true_small_caps) =
+            actual_pt_size_for_variant(rendered_pt_size, variant, supports_small_caps,
+                                       self.small_caps_scale);
+        if actual_pt_size != rendered_pt_size {
+            handle = try!(handle_or_error(FontHandleMethods::new_from_template(
+                &self.platform_handle, template, Some(actual_pt_size))));
+            handle.set_hinting(self.hinting);
+        }
         let metrics = handle.get_metrics();
+        let (synthetic_bold, synthetic_oblique) =
+            synthetic_bold_and_oblique(&descriptor, handle.boldness(), handle.is_italic(),
+                                       self.synthetic_bold_threshold, synthesis);
 
-        Font {
+        Ok(Font {
             handle: handle,
             shaper: None,
             variant: variant,
+            true_small_caps: true_small_caps,
+            synthetic_bold: synthetic_bold,
+            synthetic_oblique: synthetic_oblique,
+            synthesis: synthesis,
+            ligatures: ligatures,
+            is_color: is_color,
+            text_rendering: text_rendering,
+            feature_settings: feature_settings,
             descriptor: descriptor,
             requested_pt_size: pt_size,
             actual_pt_size: actual_pt_size,
             metrics: metrics,
             shape_cache: HashCache::new(),
             glyph_advance_cache: HashCache::new(),
+        })
+    }
+
+    /// Returns the layout font for the given family, from the cache if possible and otherwise by
+    /// asking the font cache task for a template. Returns `None` if the family has no template or
+    /// no font could be created from it, and an error if the font cache task has died.
+    fn find_or_create_layout_font(&mut self,
+                                  family: &str,
+                                  desc: &FontTemplateDescriptor,
+                                  pt_size: Au,
+                                  variant: font_variant::T,
+                                  ligatures: bool,
+                                  text_rendering: text_rendering::T,
+                                  feature_settings: &font_feature_settings::T,
+                                  synthesis: font_synthesis::T)
+                                  -> Result<Option<Rc<RefCell<Font>>>, FontCacheError> {
+        let key = layout_font_cache_key(family, desc, pt_size, variant, ligatures, text_rendering,
+                                        feature_settings, synthesis, self.hinting);
+        match self.layout_font_cache.find(&key) {
+            Some(cached_font) => {
+                // The variant decides the size that small caps are rendered at, so the key must
+                // never match a font created for another variant.
+                debug_assert!(cached_font.as_ref().map_or(true, |font| {
+                    font.borrow().variant == variant
+                }));
+                self.stats.layout_cache_hits += 1;
+                return Ok(cached_font.clone());
+            }
+            None => {}
+        }
+
+        self.stats.layout_cache_misses += 1;
+        self.stats.font_cache_task_round_trips += 1;
+        let font_template = try!(self.font_cache_task.get_font_template(family.to_string(),
+                                                                        desc.clone()));
+        let layout_font = font_template.and_then(|font_template| {
+            match self.create_layout_font(font_template, desc.clone(), pt_size, variant,
+                                          ligatures, text_rendering, feature_settings.clone(),
+                                          synthesis) {
+                Ok(layout_font) => Some(Rc::new(RefCell::new(layout_font))),
+                Err(error) => {
+                    debug!("Unable to create a font for family {}: {}", family, error);
+                    None
+                }
+            }
+        });
+        self.layout_font_cache.insert(key, layout_font.clone());
+        Ok(layout_font)
+    }
+
+    /// Loads the layout font for the given family ahead of time, so that the first layout that
+    /// uses it doesn't have to wait for the font cache task. Only the normal variant is loaded,
+    /// with ligatures, `text-rendering: auto`, synthesis allowed and without any other font
+    /// features. The last resort font for the same descriptor and size is loaded too, since
+    /// every font group keeps one. Fonts that are already cached aren't loaded again. Fails if
+    /// the font cache task has died.
+    pub fn preload_font(&mut self, family: &str, descriptor: FontTemplateDescriptor, pt_size: Au)
+                        -> Result<(), FontCacheError> {
+        let feature_settings = vec!();
+        let synthesis = font_synthesis::T { weight: true, style: true };
+        let key = layout_font_cache_key(family, &descriptor, pt_size, font_variant::normal, true,
+                                        text_rendering::auto, &feature_settings, synthesis,
+                                        self.hinting);
+        if !self.layout_font_cache.contains_key(&key) {
+            try!(self.find_or_create_layout_font(family, &descriptor, pt_size,
+                                                 font_variant::normal, true, text_rendering::auto,
+                                                 &feature_settings, synthesis));
+        }
+        let fallback_key = fallback_font_cache_key(&descriptor, pt_size, font_variant::normal,
+                                                   true, text_rendering::auto,
+                                                   &feature_settings, synthesis);
+        if self.fallback_font_cache.find(&fallback_key).is_none() {
+            try!(self.find_or_create_last_resort_font(&descriptor, pt_size, font_variant::normal,
+                                                      true, text_rendering::auto,
+                                                      &feature_settings, synthesis));
         }
+        Ok(())
     }
 
     /// Create a group of fonts for use in layout calculations. May return
     /// a cached font if this font instance has already been used by
     /// this context.
+    ///
+    /// Fails if the font cache task has died, in which case no fonts can be loaded, or if not
+    /// even the last resort font could be created; callers should skip the text that needed
+    /// them.
     pub fn get_layout_font_group_for_style(&mut self, style: Arc<SpecifiedFontStyle>)
-                                            -> Rc<FontGroup> {
-        let matches = match self.last_style {
-            Some(ref last_style) => arc_ptr_eq(&style, last_style),
+                                            -> Result<Rc<FontGroup>, FontCacheError> {
+        self.get_layout_font_group_for_script(style, None)
+    }
+
+    /// Like `get_layout_font_group_for_style`, for text whose dominant script is `script`. If a
+    /// fallback family was set for the script, it is tried before the generic fallback families.
+    /// Font groups are cached by style alone, so groups for such scripts are not cached, although
+    /// their fonts are.
+    pub fn get_layout_font_group_for_script(&mut self,
+                                            style: Arc<SpecifiedFontStyle>,
+                                            script: Option<Script>)
+                                            -> Result<Rc<FontGroup>, FontCacheError> {
+        let has_script_fallback = match script {
+            Some(ref script) => self.script_fallback_families.contains_key(script),
             None => false,
         };
-        if matches {
-            return self.last_fontgroup.as_ref().unwrap().clone();
+        if !has_script_fallback {
+            match self.font_group_cache.find(&style) {
+                Some(font_group) => {
+                    self.stats.font_group_cache_hits += 1;
+                    return Ok(font_group)
+                }
+                None => {}
+            }
         }
 
         // TODO: The font context holds a strong ref to the cached fonts
         // so they will never be released. Find out a good time to drop them.
 
         let desc = FontTemplateDescriptor::new(style.font_weight,
+                                               style.font_stretch,
                                                style.font_style == font_style::italic);
+        let ligatures = style.font_variant_ligatures != font_variant_ligatures::none;
         let mut fonts = SmallVec8::new();
 
         for family in style.font_family.iter() {
-            // GWTODO: Check on real pages if this is faster as Vec() or HashMap().
-            let mut cache_hit = false;
-            for cached_font_entry in self.layout_font_cache.iter() {
-                if cached_font_entry.family.as_slice() == family.name() {
-                    match cached_font_entry.font {
-                        None => {
-                            cache_hit = true;
-                            break;
-                        }
-                        Some(ref cached_font_ref) => {
-                            let cached_font = cached_font_ref.borrow();
-                            if cached_font.descriptor == desc &&
-                               cached_font.requested_pt_size == style.font_size &&
-                               cached_font.variant == style.font_variant {
-                                fonts.push((*cached_font_ref).clone());
-                                cache_hit = true;
-                                break;
-                            }
-                        }
-                    }
-                }
+            match try!(self.find_or_create_layout_font(family.name(), &desc, style.font_size,
+                                                       style.font_variant,
+                                                       ligatures,
+                                                       style.text_rendering,
+                                                       &style.font_feature_settings,
+                                                       style.font_synthesis)) {
+                Some(layout_font) => fonts.push(layout_font),
+                None => {}
             }
+        }
 
-            if !cache_hit {
-                let font_template = self.font_cache_task.get_font_template(family.name()
-                                                                                 .to_string(),
-                                                                           desc.clone());
-                match font_template {
-                    Some(font_template) => {
-                        let layout_font = self.create_layout_font(font_template,
-                                                                  desc.clone(),
-                                                                  style.font_size,
-                                                                  style.font_variant);
-                        let layout_font = Rc::new(RefCell::new(layout_font));
-                        self.layout_font_cache.push(LayoutFontCacheEntry {
-                            family: family.name().to_string(),
-                            font: Some(layout_font.clone()),
-                        });
-                        fonts.push(layout_font);
-                    }
-                    None => {
-                        self.layout_font_cache.push(LayoutFontCacheEntry {
-                            family: family.name().to_string(),
-                            font: None,
-                        });
+        // If unable to create any of the specified fonts, try the configured fallback families.
+        if fonts.len() == 0 {
+            let fallback_families =
+                fallback_families_for_script(&self.script_fallback_families, script,
+                                             self.fallback_families.as_slice());
+            let mut font_cache_error = None;
+            let fallback_font = first_available(fallback_families.as_slice(), |family| {
+                let layout_font = self.find_or_create_layout_font(family, &desc, style.font_size,
+                                                                  style.font_variant, ligatures,
+                                                                  style.text_rendering,
+                                                                  &style.font_feature_settings,
+                                                                  style.font_synthesis);
+                match layout_font {
+                    Ok(layout_font) => layout_font,
+                    Err(error) => {
+                        font_cache_error = Some(error);
+                        None
                     }
                 }
+            });
+            match font_cache_error {
+                Some(error) => return Err(error),
+                None => {}
+            }
+            match fallback_font {
+                Some(layout_font) => fonts.push(layout_font),
+                None => {}
             }
         }
 
-        // If unable to create any of the specified fonts, create one from the
-        // list of last resort fonts for this platform.
+        // Failing that, use the last resort font for this platform. The group also keeps it for
+        // the characters that none of its fonts has a glyph for.
+        let last_resort = try!(self.find_or_create_last_resort_font(&desc, style.font_size,
+                                                                    style.font_variant,
+                                                                    ligatures,
+                                                                    style.text_rendering,
+                                                                    &style.font_feature_settings,
+                                                                    style.font_synthesis));
         if fonts.len() == 0 {
-            let mut cache_hit = false;
-            for cached_font_entry in self.fallback_font_cache.iter() {
-                let cached_font = cached_font_entry.font.borrow();
-                if cached_font.descriptor == desc &&
-                            cached_font.requested_pt_size == style.font_size &&
-                            cached_font.variant == style.font_variant {
-                    fonts.push(cached_font_entry.font.clone());
-                    cache_hit = true;
-                    break;
+            match last_resort {
+                Some(ref layout_font) => {
+                    self.last_fallback_identifier =
+                        Some(layout_font.borrow().handle.get_template().identifier.clone());
+                    fonts.push(layout_font.clone());
                 }
+                None => {}
             }
+        }
 
-            if !cache_hit {
-                let font_template = self.font_cache_task.get_last_resort_font_template(desc.clone());
-                let layout_font = self.create_layout_font(font_template,
-                                                          desc.clone(),
-                                                          style.font_size,
-                                                          style.font_variant);
-                let layout_font = Rc::new(RefCell::new(layout_font));
-                self.fallback_font_cache.push(FallbackFontCacheEntry {
-                    font: layout_font.clone(),
-                });
-                fonts.push(layout_font);
+        if fonts.len() == 0 {
+            return Err(NoFontAvailable)
+        }
+
+        let font_group = Rc::new(FontGroup::new(fonts, last_resort));
+        if !has_script_fallback {
+            self.font_group_cache.insert(style, font_group.clone());
+        }
+        Ok(font_group)
+    }
+
+    /// Returns the font of the given style's group that renders `codepoint`: the first font in
+    /// the group that has a glyph for it, or else the last resort font (see
+    /// `FontGroup::font_for_char`). Fails if the font cache task has died.
+    pub fn font_for_codepoint(&mut self, style: Arc<SpecifiedFontStyle>, codepoint: char)
+                              -> Result<Rc<RefCell<Font>>, FontCacheError> {
+        let font_group = try!(self.get_layout_font_group_for_style(style));
+        Ok(font_group.font_for_char(codepoint))
+    }
+
+    /// Returns the metrics of the primary font for the given style, i.e. the first font of its
+    /// font group, which may be a fallback or last resort font. Fails if the font cache task has
+    /// died.
+    pub fn metrics_for_style(&mut self, style: &Arc<SpecifiedFontStyle>)
+                             -> Result<FontMetrics, FontCacheError> {
+        let font_group = try!(self.get_layout_font_group_for_style(style.clone()));
+        let metrics = font_group.primary().borrow().metrics.clone();
+        Ok(metrics)
+    }
+
+    /// Shapes `text` with the primary font of the given style. The shared shape cache, if any, is
+    /// consulted first; on a miss, the font shapes the text, using its own shape cache. Fails if
+    /// the font cache task has died.
+    pub fn shape_run(&mut self, style: Arc<SpecifiedFontStyle>, text: &str)
+                     -> Result<ShapedRun, FontCacheError> {
+        let font = try!(self.get_layout_font_group_for_style(style)).primary();
+        let is_whitespace = text.chars().all(|c| c.is_whitespace());
+        let glyphs = match self.shared_shape_cache {
+            Some(ref shared) => {
+                let key = shape_cache_key(&*font.borrow(), text);
+                shared.lock().runs.find_or_shape(key, || {
+                    font.borrow_mut().shape_text(text, is_whitespace)
+                })
             }
+            None => font.borrow_mut().shape_text(text, is_whitespace),
+        };
+        Ok(ShapedRun {
+            font: font,
+            glyphs: glyphs,
+        })
+    }
+
+    /// Returns the last resort layout font for the given style, from the fallback cache if
+    /// possible. Returns `None` if no font could be created from the last resort template, and
+    /// an error if the font cache task has died.
+    fn find_or_create_last_resort_font(&mut self,
+                                       desc: &FontTemplateDescriptor,
+                                       pt_size: Au,
+                                       variant: font_variant::T,
+                                       ligatures: bool,
+                                       text_rendering: text_rendering::T,
+                                       feature_settings: &font_feature_settings::T,
+                                       synthesis: font_synthesis::T)
+                                       -> Result<Option<Rc<RefCell<Font>>>, FontCacheError> {
+        let key = fallback_font_cache_key(desc, pt_size, variant, ligatures, text_rendering,
+                                          feature_settings, synthesis);
+        match self.fallback_font_cache.find(&key) {
+            Some(cached_font) => {
+                self.stats.fallback_cache_hits += 1;
+                return Ok(Some(cached_font))
+            }
+            None => {}
         }
 
-        let font_group = Rc::new(FontGroup::new(fonts));
-        self.last_style = Some(style);
-        self.last_fontgroup = Some(font_group.clone());
-        font_group
+        self.stats.fallback_cache_misses += 1;
+        self.stats.font_cache_task_round_trips += 1;
+        let font_template = match self.font_cache_task.get_last_resort_font_template(desc.clone()) {
+            Ok(font_template) => font_template,
+            Err(NoFontAvailable) => {
+                debug!("The font cache task has no last resort font");
+                return Ok(None)
+            }
+            Err(error) => return Err(error),
+        };
+        match self.create_layout_font(font_template, desc.clone(), pt_size, variant, ligatures,
+                                      text_rendering, feature_settings.clone(), synthesis) {
+            Ok(layout_font) => {
+                let layout_font = Rc::new(RefCell::new(layout_font));
+                self.fallback_font_cache.insert(key, layout_font.clone());
+                Ok(Some(layout_font))
+            }
+            Err(error) => {
+                debug!("Unable to create the last resort font: {}", error);
+                Ok(None)
+            }
+        }
     }
 
     /// Create a render font for use with azure. May return a cached
     /// reference if already used by this font context.
+    ///
+    /// Azure can't embolden or slant a `ScaledFont`, so the glyphs of synthetic bold and oblique
+    /// fonts are transformed when drawn instead, and glyphs are snapped to whole pixels when
+    /// drawn if subpixel positioning is off. Nor does it take font features, which only affect
+    /// which glyphs the shaper picks, or a hinting mode. Render fonts are still cached apart by
+    /// descriptor, synthesis, `feature_settings`, positioning and hinting, so that a font is never
+    /// handed out for the wrong style.
     pub fn get_render_font_from_template(&mut self,
                                          template: &Arc<FontTemplateData>,
-                                         pt_size: Au)
-                                         -> Rc<RefCell<ScaledFont>> {
-        for cached_font in self.render_font_cache.iter() {
-            if cached_font.pt_size == pt_size &&
-               cached_font.identifier == template.identifier {
-                return cached_font.font.clone();
+                                         pt_size: Au,
+                                         descriptor: &FontTemplateDescriptor,
+                                         synthetic_bold: bool,
+                                         synthetic_oblique: bool,
+                                         feature_settings: &font_feature_settings::T)
+                                         -> Arc<ScaledFont> {
+        let key = RenderFontCacheKey {
+            pt_size: pt_size,
+            identifier: template.identifier.clone(),
+            descriptor: descriptor.clone(),
+            synthetic_bold: synthetic_bold,
+            synthetic_oblique: synthetic_oblique,
+            feature_settings: feature_settings.clone(),
+            subpixel_positioning: self.subpixel_positioning,
+            hinting: self.hinting,
+        };
+        let cost = estimated_render_font_cost(pt_size);
+        let create = || create_scaled_font(template, pt_size);
+        match self.shared_render_font_cache {
+            Some(ref shared) => {
+                let mut shared = shared.lock();
+                find_or_create_render_font(&mut self.render_font_cache,
+                                           Some(&mut shared.fonts),
+                                           key,
+                                           cost,
+                                           create)
+            }
+            None => {
+                find_or_create_render_font(&mut self.render_font_cache, None, key, cost, create)
             }
         }
+    }
 
-        let render_font = Rc::new(RefCell::new(create_scaled_font(template, pt_size)));
-        self.render_font_cache.push(RenderFontCacheEntry{
-            font: render_font.clone(),
-            pt_size: pt_size,
-            identifier: template.identifier.clone(),
+    /// Creates the render fonts of every font in `group` at `pt_size` ahead of time, so that
+    /// painting the group's text doesn't have to. Fonts already in the render cache are reused.
+    ///
+    /// The render cache only holds weak references, so the fonts stay warm only as long as the
+    /// returned ones are kept alive.
+    pub fn warm_render_cache(&mut self, group: &FontGroup, pt_size: Au) -> Vec<Arc<ScaledFont>> {
+        group.fonts.iter().chain(group.last_resort.iter()).map(|font| {
+            let font = font.borrow();
+            self.get_render_font_from_template(&font.handle.get_template(),
+                                               pt_size,
+                                               &font.descriptor,
+                                               font.synthetic_bold,
+                                               font.synthetic_oblique,
+                                               &font.feature_settings)
+        }).collect()
+    }
+
+    /// Fills the advance cache of `font` with the glyphs of `chars`, e.g. digits and ASCII at
+    /// startup, so that the first layout of common text doesn't have to ask the platform font.
+    pub fn warm_advances(&mut self, font: &Rc<RefCell<Font>>, chars: &[char]) {
+        font.borrow_mut().warm_advances(chars)
+    }
+
+    /// Approximates the number of bytes taken up by the fonts in the layout, fallback and render
+    /// caches of this context, e.g. for about:memory. The render fonts of a shared render font
+    /// cache are left out, since they don't belong to any one context.
+    pub fn estimated_memory_usage(&self) -> uint {
+        let layout_fonts = self.layout_font_cache.values().filter_map(|font| font.as_ref());
+        let fallback_fonts = self.fallback_font_cache.iter().map(|&(_, ref font)| font);
+        let templates = layout_fonts.chain(fallback_fonts).map(|font| {
+            let template = font.borrow().handle.get_template();
+            (template.identifier.clone(), template.byte_len())
         });
-        render_font
+        estimated_layout_fonts_cost(templates) + self.render_font_cache.total_cost
+    }
+
+    /// Drops all the fonts cached by this context, e.g. in response to memory pressure. Fonts that
+    /// are still in use elsewhere stay alive; later lookups go back to the font cache task.
+    pub fn clear_caches(&mut self) {
+        self.layout_font_cache.clear();
+        self.fallback_font_cache.evict_all();
+        self.render_font_cache.clear();
+        self.font_group_cache.clear();
+    }
+
+    /// Drops the cached layout fonts of the given family, and the render fonts created from
+    /// their templates, e.g. once a web font for that family has finished downloading. The next
+    /// lookup of the family asks the font cache task again. Font groups are all dropped, since
+    /// any of them may use the stale fonts; fonts of other families stay cached.
+    pub fn invalidate_family(&mut self, family: &str) {
+        let identifiers: Vec<String> =
+            remove_family(&mut self.layout_font_cache, family).into_iter().filter_map(|font| {
+                font.map(|font| font.borrow().handle.get_template().identifier.clone())
+            }).collect();
+        self.render_font_cache.remove_identifiers(identifiers.as_slice());
+        match self.shared_render_font_cache {
+            Some(ref shared) => shared.lock().fonts.remove_identifiers(identifiers.as_slice()),
+            None => {}
+        }
+        self.font_group_cache.clear();
+    }
+
+    /// Returns the distinct families of the fonts in the layout font cache, in order, including
+    /// those that the font cache task had no template for. See `cached_layout_fonts`.
+    pub fn cached_families(&self) -> Vec<String> {
+        let mut families: Vec<String> = self.cached_layout_fonts().into_iter().map(|font| {
+            font.family
+        }).collect();
+        families.dedup();
+        families
+    }
+
+    /// Lists the fonts that this context has resolved for layout so far, by family and then
+    /// size, e.g. for a font inspector. Families that the font cache task had no template for are
+    /// listed as not found.
+    pub fn cached_layout_fonts(&self) -> Vec<CachedLayoutFont> {
+        list_layout_fonts(&self.layout_font_cache)
+    }
+
+    /// Returns how often each of the caches of this context has been hit or missed so far.
+    pub fn cache_stats(&self) -> FontCacheStats {
+        FontCacheStats {
+            render_cache_hits: self.render_font_cache.hits,
+            render_cache_misses: self.render_font_cache.misses,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Returns the identifier of the last resort font that was most recently picked because none
+    /// of the families of a style were available, if any. This helps to find out which face
+    /// ends up drawing text whose glyphs are missing.
+    pub fn last_fallback_identifier(&self) -> Option<String> {
+        self.last_fallback_identifier.clone()
     }
 
     /// Returns a reference to the font cache task.
@@ -256,3 +1357,1101 @@ impl FontContext {
         self.font_cache_task.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::{FontGroupCache, LayoutFontCacheKey, RenderFontCache, RenderFontCacheKey};
+    use super::FamilyKey;
+    use super::{ShapeCache, ShapeCacheKey};
+    use super::SMALL_CAPS_SCALE_FACTOR;
+    use super::{fallback_font_cache_key, layout_font_cache_key, remove_family};
+    use super::list_layout_fonts;
+    use super::{ArabicScript, HanScript, LatinScript, fallback_families_for_script};
+    use super::{DEFAULT_SYNTHETIC_BOLD_THRESHOLD, actual_pt_size_for_variant};
+    use super::apply_minimum_font_size;
+    use super::{estimated_render_font_cost, needs_synthetic_bold, needs_synthetic_oblique};
+    use super::{LAYOUT_FONT_BASE_COST, estimated_layout_fonts_cost};
+    use super::synthetic_bold_and_oblique;
+    use super::find_or_create_render_font;
+    use super::{FontContext, FontContextOptions};
+    use font::{DEFAULT_HINTING_MODE, FullHinting, HintingMode, NoHinting, SlightHinting};
+    use font::{FontGroup, FontHandleMethods, SpecifiedFontStyle};
+    use font_cache_task::{AddWebFont, Exit, FontCacheTask, GetFontTemplate, GetFontTemplateReply};
+    use font_cache_task::{GetLastResortFontTemplate, NoFontAvailable};
+
+    use font_template::{FontTemplateDescriptor, UnicodeRange};
+    use platform::font_template::FontTemplateData;
+    use servo_util::cache::{Cache, LRUCache};
+    use servo_util::geometry::Au;
+    use servo_util::smallvec::SmallVec;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::default::Default;
+    use std::rc::Rc;
+    use servo_util::arc_ptr_eq;
+    use sync::{Arc, Mutex};
+    use style::computed_values::{font_stretch, font_synthesis, font_variant, font_weight};
+    use style::computed_values::{font_style, font_variant_ligatures};
+    use style::computed_values::font_family::FamilyName;
+    use style::style_structs;
+    use style::computed_values::text_rendering;
+    use style::computed_values::font_feature_settings::FeatureTagValue;
+
+    fn stretched_font_cache_key(family: &str, weight: font_weight::T, stretch: font_stretch::T)
+                                -> LayoutFontCacheKey {
+        layout_font_cache_key(family, &FontTemplateDescriptor::new(weight, stretch, false),
+                              Au::from_px(16), font_variant::normal, true, text_rendering::auto,
+                              &vec!(), all_synthesis(), DEFAULT_HINTING_MODE)
+    }
+
+    fn all_synthesis() -> font_synthesis::T {
+        font_synthesis::T { weight: true, style: true }
+    }
+
+    /// The Ahem test font.
+    static AHEM: &'static [u8] = include_bin!("../../tests/ref/fonts/ahem/ahem.ttf");
+    static GLYPHICONS: &'static [u8] =
+        include_bin!("../../etc/layout_viewer/fonts/glyphicons-halflings-regular.ttf");
+
+    /// Bytes that no platform can create a font from.
+    static BROKEN: &'static [u8] = &[0, 0, 0, 0, 0, 0, 0, 0];
+
+    /// A GSUB table whose feature list holds only `smcp`.
+    static SMCP_GSUB: &'static [u8] = &[0, 1, 0, 0,     // version 1.0
+                                        0, 0,           // script list
+                                        0, 10,          // feature list
+                                        0, 0,           // lookup list
+                                        0, 1, b's', b'm', b'c', b'p', 0, 0];
+
+    /// Returns a copy of the font file `font_data` with `table` added under `tag`. The new record
+    /// goes at the end of the table directory, which moves the other tables along by 16 bytes,
+    /// and the table itself at the end of the file.
+    fn with_table(font_data: &[u8], tag: &[u8], table: &[u8]) -> Vec<u8> {
+        fn push_u32(buf: &mut Vec<u8>, value: uint) {
+            buf.push_all([(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                          value as u8]);
+        }
+        let read_u32 = |offset: uint| {
+            font_data.slice(offset, offset + 4).iter().fold(0u, |value, &byte| {
+                (value << 8) | byte as uint
+            })
+        };
+        let table_count = (font_data[4] as uint << 8) | font_data[5] as uint;
+        let directory_end = 12 + table_count * 16;
+        let mut tables = font_data.slice_from(directory_end).to_vec();
+        while tables.len() % 4 != 0 {
+            tables.push(0);
+        }
+
+        let mut font = font_data.slice_to(4).to_vec();
+        font.push_all([((table_count + 1) >> 8) as u8, (table_count + 1) as u8]);
+        font.push_all(font_data.slice(6, 12));
+        for i in range(0, table_count) {
+            let record = 12 + i * 16;
+            font.push_all(font_data.slice(record, record + 8));
+            push_u32(&mut font, read_u32(record + 8) + 16);
+            font.push_all(font_data.slice(record + 12, record + 16));
+        }
+        font.push_all(tag);
+        push_u32(&mut font, 0);
+        push_u32(&mut font, directory_end + 16 + tables.len());
+        push_u32(&mut font, table.len());
+        font.push_all(tables.as_slice());
+        font.push_all(table);
+        font
+    }
+
+    fn template(identifier: &str, bytes: &[u8]) -> Arc<FontTemplateData> {
+        Arc::new(FontTemplateData::new(identifier, Some(bytes.to_vec())))
+    }
+
+    /// Returns a template whose `@font-face` rule limits it to `unicode_range`.
+    fn ranged_template(identifier: &str, bytes: &[u8], unicode_range: Vec<UnicodeRange>)
+                       -> Arc<FontTemplateData> {
+        let mut data = FontTemplateData::new(identifier, Some(bytes.to_vec()));
+        data.unicode_range = Some(unicode_range);
+        Arc::new(data)
+    }
+
+    /// Returns a font cache task that only knows the templates of `families`, and whose last
+    /// resort template is `last_resort`.
+    fn test_font_cache_task(families: Vec<(&'static str, Arc<FontTemplateData>)>,
+                            last_resort: Arc<FontTemplateData>)
+                            -> FontCacheTask {
+        let (chan, port) = channel();
+        spawn(proc() {
+            for command in port.iter() {
+                match command {
+                    GetFontTemplate(family, _, reply_chan) => {
+                        let template = families.iter()
+                                               .find(|&&(name, _)| name == family.as_slice())
+                                               .map(|&(_, ref template)| template.clone());
+                        reply_chan.send(GetFontTemplateReply(template));
+                    }
+                    GetLastResortFontTemplate(_, reply_chan) => {
+                        reply_chan.send(GetFontTemplateReply(Some(last_resort.clone())));
+                    }
+                    AddWebFont(_, _, _, reply_chan) | Exit(reply_chan) => reply_chan.send(()),
+                }
+            }
+        });
+        FontCacheTask::from_chan(chan)
+    }
+
+    fn test_font_context(font_cache_task: FontCacheTask) -> FontContext {
+        FontContext::new(font_cache_task, FontContextOptions::default())
+    }
+
+    /// Returns a font context that only knows Ahem, and whose last resort font is Ahem too.
+    fn ahem_font_context() -> FontContext {
+        test_font_context(test_font_cache_task(vec!(("Ahem", template("ahem.ttf", AHEM))),
+                                               template("last-resort.ttf", AHEM)))
+    }
+
+    /// Returns the layout font group of the initial style with the given families.
+    fn font_group(font_context: &mut FontContext, families: &[&str]) -> Rc<FontGroup> {
+        font_context.get_layout_font_group_for_style(style_with_families(families))
+                    .ok()
+                    .expect("no font group")
+    }
+
+    /// Returns the initial font style, with the given font families.
+    fn style_with_families(families: &[&str]) -> Arc<SpecifiedFontStyle> {
+        Arc::new(style_structs::Font {
+            font_family: families.iter().map(|family| FamilyName(family.to_string())).collect(),
+            font_style: font_style::normal,
+            font_variant: font_variant::normal,
+            font_variant_ligatures: font_variant_ligatures::normal,
+            font_stretch: font_stretch::normal,
+            text_rendering: text_rendering::auto,
+            font_feature_settings: vec!(),
+            font_synthesis: all_synthesis(),
+            font_weight: font_weight::Weight400,
+            font_size: Au::from_px(16),
+        })
+    }
+
+    /// Returns the initial font style in small caps, with the given font families.
+    fn small_caps_style_with_families(families: &[&str]) -> Arc<SpecifiedFontStyle> {
+        let mut style = (*style_with_families(families)).clone();
+        style.font_variant = font_variant::small_caps;
+        Arc::new(style)
+    }
+
+    fn regular_font_cache_key(family: &str, weight: font_weight::T) -> LayoutFontCacheKey {
+        stretched_font_cache_key(family, weight, font_stretch::normal)
+    }
+
+    fn regular_descriptor() -> FontTemplateDescriptor {
+        FontTemplateDescriptor::new(font_weight::Weight400, font_stretch::normal, false)
+    }
+
+    fn render_font_key(pt_size: Au, identifier: &str, feature_settings: &Vec<FeatureTagValue>)
+                       -> RenderFontCacheKey {
+        RenderFontCacheKey {
+            pt_size: pt_size,
+            identifier: identifier.to_string(),
+            descriptor: regular_descriptor(),
+            synthetic_bold: false,
+            synthetic_oblique: false,
+            feature_settings: feature_settings.clone(),
+            subpixel_positioning: true,
+            hinting: DEFAULT_HINTING_MODE,
+        }
+    }
+
+    fn shape_key(text: &str) -> ShapeCacheKey {
+        ShapeCacheKey {
+            identifier: "serif.ttf".to_string(),
+            pt_size: Au::from_px(16),
+            true_small_caps: false,
+            ligatures: true,
+            text_rendering: text_rendering::auto,
+            feature_settings: vec!(),
+            hinting: DEFAULT_HINTING_MODE,
+            text: text.to_string(),
+        }
+    }
+
+    #[bench]
+    fn bench_layout_font_cache_lookup(b: &mut test::Bencher) {
+        // With the old linear scan, each lookup compared against every cached family in turn.
+        let mut cache = HashMap::new();
+        for i in range(0u, 1000) {
+            cache.insert(regular_font_cache_key(format!("family-{}", i).as_slice(),
+                                               font_weight::Weight400), i);
+        }
+        let key = regular_font_cache_key("family-999", font_weight::Weight400);
+        b.iter(|| {
+            test::black_box(cache.find(&key));
+        });
+    }
+
+    #[test]
+    fn test_layout_font_cache_key_matches_descriptor_equality() {
+        let mut cache = HashMap::new();
+        cache.insert(regular_font_cache_key("serif", font_weight::Weight400), ());
+        assert!(cache.contains_key(&regular_font_cache_key("serif", font_weight::Weight300)));
+        assert!(!cache.contains_key(&regular_font_cache_key("serif", font_weight::Weight700)));
+        assert!(!cache.contains_key(&regular_font_cache_key("sans-serif", font_weight::Weight400)));
+    }
+
+    #[test]
+    fn test_font_stretch_keeps_layout_fonts_apart() {
+        let mut cache = HashMap::new();
+        cache.insert(stretched_font_cache_key("serif", font_weight::Weight400,
+                                              font_stretch::condensed), ());
+        assert!(cache.contains_key(&stretched_font_cache_key("serif", font_weight::Weight400,
+                                                             font_stretch::condensed)));
+        assert!(!cache.contains_key(&regular_font_cache_key("serif", font_weight::Weight400)));
+
+        // The fallback cache compares descriptors directly.
+        let condensed = FontTemplateDescriptor::new(font_weight::Weight400,
+                                                    font_stretch::condensed,
+                                                    false);
+        assert!(condensed != FontTemplateDescriptor::new(font_weight::Weight400,
+                                                         font_stretch::normal,
+                                                         false));
+    }
+
+    #[test]
+    fn test_cached_fonts_of_both_families_are_listed() {
+        let mut cache = HashMap::new();
+        cache.insert(regular_font_cache_key("serif", font_weight::Weight400), Some(()));
+        cache.insert(regular_font_cache_key("serif", font_weight::Weight700), Some(()));
+        cache.insert(regular_font_cache_key("missing", font_weight::Weight400), None);
+
+        let fonts = list_layout_fonts(&cache);
+        let families: Vec<&str> = fonts.iter().map(|font| font.family.as_slice()).collect();
+        assert_eq!(families, vec!("missing", "serif", "serif"));
+        assert!(!fonts[0].found);
+        assert!(fonts[1].found && fonts[2].found);
+        assert!(fonts.iter().all(|font| font.pt_size == Au::from_px(16)));
+        assert!(fonts[1].descriptor != fonts[2].descriptor);
+    }
+
+    #[test]
+    fn test_resolved_families_are_listed() {
+        let mut font_context = ahem_font_context();
+        assert!(font_context.cached_families().is_empty());
+        font_group(&mut font_context, ["Missing", "Ahem"]);
+
+        assert_eq!(font_context.cached_families(), vec!("Ahem".to_string(), "Missing".to_string()));
+        let fonts = font_context.cached_layout_fonts();
+        assert_eq!(fonts.len(), 2);
+        assert!(fonts[0].found && !fonts[1].found);
+        assert!(fonts.iter().all(|font| {
+            font.pt_size == Au::from_px(16) && font.descriptor == regular_descriptor()
+        }));
+    }
+
+    /// Returns the layout font cache key of a regular font of `family`, as if its name hashed to
+    /// `hash`.
+    fn font_cache_key_with_hash(family: &str, hash: u64) -> LayoutFontCacheKey {
+        let (_, descriptor, pt_size, variant, ligatures, text_rendering, feature_settings,
+             synthesis, hinting) = regular_font_cache_key(family, font_weight::Weight400);
+        (FamilyKey { hash: hash, name: family.to_string() }, descriptor, pt_size, variant,
+         ligatures, text_rendering, feature_settings, synthesis, hinting)
+    }
+
+    #[test]
+    fn test_families_with_colliding_hashes_are_cached_apart() {
+        let mut cache = HashMap::new();
+        cache.insert(font_cache_key_with_hash("serif", 42), "serif");
+        cache.insert(font_cache_key_with_hash("sans-serif", 42), "sans-serif");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.find(&font_cache_key_with_hash("serif", 42)), Some(&"serif"));
+        assert_eq!(cache.find(&font_cache_key_with_hash("sans-serif", 42)), Some(&"sans-serif"));
+
+        let serif = FamilyKey { hash: 42, name: "serif".to_string() };
+        assert!(serif.matches(42, "serif"));
+        assert!(!serif.matches(42, "sans-serif"));
+        assert!(!serif.matches(43, "serif"));
+
+        // Real keys hash the family name.
+        assert!(FamilyKey::new("serif") == FamilyKey::new("serif"));
+        assert!(FamilyKey::new("serif") != FamilyKey::new("sans-serif"));
+    }
+
+    #[test]
+    fn test_fallback_font_cache_evicts_least_recently_used() {
+        let descriptor = regular_descriptor();
+        let key = |px: int| {
+            fallback_font_cache_key(&descriptor, Au::from_px(px), font_variant::normal, true,
+                                    text_rendering::auto, &vec!(), all_synthesis())
+        };
+        let mut cache = LRUCache::new(2);
+        let small = Rc::new(RefCell::new(12u));
+        let evicted = small.downgrade();
+        cache.insert(key(12), small);
+        cache.insert(key(16), Rc::new(RefCell::new(16u)));
+        assert!(cache.find(&key(16)).is_some());
+        cache.insert(key(24), Rc::new(RefCell::new(24u)));
+
+        assert!(cache.find(&key(12)).is_none());
+        assert!(evicted.upgrade().is_none());
+        assert_eq!(*cache.find(&key(16)).unwrap().borrow(), 16);
+        assert_eq!(*cache.find(&key(24)).unwrap().borrow(), 24);
+    }
+
+    #[test]
+    fn test_preloaded_font_is_found_by_layout_lookup() {
+        let mut font_context = ahem_font_context();
+        assert!(font_context.preload_font("Ahem", regular_descriptor(), Au::from_px(16)).is_ok());
+        let preloaded = font_context.cache_stats();
+        assert_eq!(preloaded.font_cache_task_round_trips, 2);
+
+        // Unstyled text uses the normal variant that was preloaded, and its last resort font.
+        let primary = font_group(&mut font_context, ["Ahem"]).primary();
+        assert_eq!(primary.borrow().handle.get_template().identifier, "ahem.ttf".to_string());
+        let stats = font_context.cache_stats();
+        assert_eq!(stats.font_cache_task_round_trips, preloaded.font_cache_task_round_trips);
+        assert_eq!(stats.layout_cache_hits, preloaded.layout_cache_hits + 1);
+        assert_eq!(stats.layout_cache_misses, preloaded.layout_cache_misses);
+        assert_eq!(stats.fallback_cache_hits, preloaded.fallback_cache_hits + 1);
+        assert_eq!(stats.font_group_cache_hits, 0);
+
+        // Looking the group up again finds it whole, without looking up any of its fonts.
+        font_group(&mut font_context, ["Ahem"]);
+        let regrouped = font_context.cache_stats();
+        assert_eq!(regrouped.font_group_cache_hits, 1);
+        assert_eq!(regrouped.layout_cache_hits, stats.layout_cache_hits);
+        assert_eq!(regrouped.fallback_cache_hits, stats.fallback_cache_hits);
+
+        // Preloading a cached font again is a no-op.
+        assert!(font_context.preload_font("Ahem", regular_descriptor(), Au::from_px(16)).is_ok());
+        assert_eq!(font_context.cache_stats().font_cache_task_round_trips,
+                   preloaded.font_cache_task_round_trips);
+    }
+
+    #[test]
+    fn test_scripts_fall_back_to_their_own_families() {
+        let generic = vec!("DejaVu Sans".to_string());
+        let mut script_families = HashMap::new();
+        script_families.insert(HanScript, "Noto Sans CJK".to_string());
+        script_families.insert(ArabicScript, "Noto Naskh Arabic".to_string());
+
+        assert_eq!(fallback_families_for_script(&script_families, Some(HanScript),
+                                                generic.as_slice()),
+                   vec!("Noto Sans CJK".to_string(), "DejaVu Sans".to_string()));
+        assert_eq!(fallback_families_for_script(&script_families, Some(ArabicScript),
+                                                generic.as_slice()),
+                   vec!("Noto Naskh Arabic".to_string(), "DejaVu Sans".to_string()));
+
+        // Scripts without a family of their own use the generic list.
+        assert_eq!(fallback_families_for_script(&script_families, Some(LatinScript),
+                                                generic.as_slice()),
+                   generic);
+        assert_eq!(fallback_families_for_script(&script_families, None, generic.as_slice()),
+                   generic);
+    }
+
+    #[test]
+    fn test_font_variants_never_share_cached_fonts() {
+        let descriptor = regular_descriptor();
+        let key = |variant: font_variant::T| {
+            layout_font_cache_key("serif", &descriptor, Au::from_px(16), variant, true,
+                                  text_rendering::auto, &vec!(), all_synthesis(),
+                                  DEFAULT_HINTING_MODE)
+        };
+        let mut cache = HashMap::new();
+        cache.insert(key(font_variant::normal), font_variant::normal);
+        assert!(cache.find(&key(font_variant::small_caps)).is_none());
+        cache.insert(key(font_variant::small_caps), font_variant::small_caps);
+        assert!(*cache.find(&key(font_variant::normal)).unwrap() == font_variant::normal);
+        assert!(*cache.find(&key(font_variant::small_caps)).unwrap() == font_variant::small_caps);
+
+        let fallback_key = |variant: font_variant::T| {
+            fallback_font_cache_key(&descriptor, Au::from_px(16), variant, true,
+                                    text_rendering::auto, &vec!(), all_synthesis())
+        };
+        assert!(fallback_key(font_variant::normal) != fallback_key(font_variant::small_caps));
+    }
+
+    #[test]
+    fn test_font_context_never_hands_out_fonts_of_another_variant() {
+        let mut font_context = ahem_font_context();
+        let mut primary_font = |style: Arc<SpecifiedFontStyle>| {
+            let font_group = font_context.get_layout_font_group_for_style(style)
+                                         .ok()
+                                         .expect("no font group");
+            font_group.primary()
+        };
+
+        // Ahem has no `smcp` glyphs, so its small caps are rendered smaller.
+        let pt_size = Au::from_px(16);
+        for _ in range(0u, 2) {
+            let normal = primary_font(style_with_families(["Ahem"]));
+            assert!(normal.borrow().variant == font_variant::normal);
+            assert_eq!(normal.borrow().actual_pt_size, pt_size);
+
+            let small_caps = primary_font(small_caps_style_with_families(["Ahem"]));
+            assert!(small_caps.borrow().variant == font_variant::small_caps);
+            assert_eq!(small_caps.borrow().actual_pt_size,
+                       pt_size.scale_by(SMALL_CAPS_SCALE_FACTOR));
+        }
+    }
+
+    #[test]
+    fn test_configured_fallback_family_is_preferred() {
+        let font_cache_task = test_font_cache_task(vec!(("Ahem", template("ahem.ttf", AHEM))),
+                                                   template("last-resort.ttf", AHEM));
+        let options = FontContextOptions {
+            fallback_families: vec!("Missing too".to_string(), "Ahem".to_string()),
+            ..Default::default()
+        };
+        let mut font_context = FontContext::new(font_cache_task, options);
+        let font_group = font_group(&mut font_context, ["Missing"]);
+        assert_eq!(font_group.primary().borrow().handle.get_template().identifier,
+                   "ahem.ttf".to_string());
+    }
+
+    #[test]
+    fn test_alternating_styles_hit_font_group_cache() {
+        let mut cache: FontGroupCache<uint, uint> = FontGroupCache::new(8);
+        let (body, heading) = (Arc::new(16), Arc::new(32));
+        cache.insert(body.clone(), Rc::new(1));
+        cache.insert(heading.clone(), Rc::new(2));
+        for _ in range(0u, 4) {
+            assert_eq!(cache.find(&body).map(|group| *group), Some(1));
+            assert_eq!(cache.find(&heading).map(|group| *group), Some(2));
+        }
+
+        assert!(cache.find(&Arc::new(8)).is_none());
+    }
+
+    #[test]
+    fn test_equal_styles_share_font_group() {
+        let mut cache: FontGroupCache<uint, uint> = FontGroupCache::new(8);
+        let (style, equal_style) = (Arc::new(16), Arc::new(16));
+        let font_group = Rc::new(1);
+        cache.insert(style.clone(), font_group.clone());
+
+        let shared = cache.find(&equal_style).unwrap();
+        assert!(&*shared as *const uint == &*font_group as *const uint);
+
+        // The equal style is then found by pointer, in place of the style it was equal to.
+        assert_eq!(cache.entries.len(), 1);
+        let (ref cached_style, _) = cache.entries[0];
+        assert!(arc_ptr_eq(cached_style, &equal_style));
+    }
+
+    #[test]
+    fn test_font_group_cache_evicts_least_recently_used() {
+        let mut cache: FontGroupCache<uint, uint> = FontGroupCache::new(2);
+        let styles = Vec::from_fn(3, |i| Arc::new(i));
+        cache.insert(styles[0].clone(), Rc::new(0));
+        cache.insert(styles[1].clone(), Rc::new(1));
+        assert!(cache.find(&styles[0]).is_some());
+        cache.insert(styles[2].clone(), Rc::new(2));
+        assert!(cache.find(&styles[0]).is_some());
+        assert!(cache.find(&styles[1]).is_none());
+        assert!(cache.find(&styles[2]).is_some());
+    }
+
+    #[test]
+    fn test_font_group_survives_a_broken_last_resort_font() {
+        let font_cache_task = test_font_cache_task(vec!(("Ahem", template("ahem.ttf", AHEM))),
+                                                   template("broken.ttf", BROKEN));
+        let mut font_context = test_font_context(font_cache_task);
+        let font_group = font_group(&mut font_context, ["Missing", "Ahem"]);
+        assert_eq!(font_group.fonts.len(), 1);
+        assert!(font_group.last_resort.is_none());
+        assert_eq!(font_group.primary().borrow().handle.get_template().identifier,
+                   "ahem.ttf".to_string());
+    }
+
+    #[test]
+    fn test_font_groups_skip_faces_that_fail_to_load() {
+        let broken = template("broken.ttf", BROKEN);
+        let font_cache_task = test_font_cache_task(vec!(("Broken", broken.clone()),
+                                                        ("Ahem", template("ahem.ttf", AHEM))),
+                                                   broken);
+        let mut font_context = test_font_context(font_cache_task);
+        let font_group = font_group(&mut font_context, ["Broken", "Ahem"]);
+        let primary = font_group.primary();
+        assert_eq!(primary.borrow().handle.get_template().identifier, "ahem.ttf".to_string());
+    }
+
+    #[test]
+    fn test_style_without_any_font_is_an_error() {
+        // Not even the last resort font can be created.
+        let broken = template("broken.ttf", BROKEN);
+        let font_cache_task = test_font_cache_task(vec!(("Broken", broken.clone())), broken);
+        let mut font_context = test_font_context(font_cache_task);
+        let style = style_with_families(["Broken", "Missing"]);
+        assert!(font_context.get_layout_font_group_for_style(style).err() ==
+                Some(NoFontAvailable));
+    }
+
+    #[test]
+    fn test_last_resort_font_identifier_is_recorded() {
+        let mut font_context = ahem_font_context();
+        font_group(&mut font_context, ["Ahem"]);
+        assert_eq!(font_context.last_fallback_identifier(), None);
+
+        font_group(&mut font_context, ["Missing"]);
+        assert_eq!(font_context.last_fallback_identifier(), Some("last-resort.ttf".to_string()));
+    }
+
+    #[test]
+    fn test_metrics_for_style_are_those_of_the_primary_font() {
+        let mut font_context = ahem_font_context();
+        for style in [style_with_families(["Ahem"]), style_with_families(["Missing"])].iter() {
+            let metrics = font_context.metrics_for_style(style).ok().expect("no metrics");
+            let font_group = font_context.get_layout_font_group_for_style(style.clone())
+                                         .ok()
+                                         .expect("no font group");
+            let handle_metrics = font_group.primary().borrow().handle.get_metrics();
+            assert!(metrics.ascent > Au(0));
+            assert_eq!(metrics.ascent, handle_metrics.ascent);
+            assert_eq!(metrics.descent, handle_metrics.descent);
+        }
+    }
+
+    #[test]
+    fn test_small_caps_use_font_feature_when_available() {
+        let pt_size = Au::from_px(20);
+        let scale = SMALL_CAPS_SCALE_FACTOR;
+        assert_eq!(actual_pt_size_for_variant(pt_size, font_variant::small_caps, true, scale),
+                   (pt_size, true));
+        assert_eq!(actual_pt_size_for_variant(pt_size, font_variant::small_caps, false, scale),
+                   (pt_size.scale_by(SMALL_CAPS_SCALE_FACTOR), false));
+        assert_eq!(actual_pt_size_for_variant(pt_size, font_variant::normal, true, scale),
+                   (pt_size, false));
+    }
+
+    #[test]
+    #[cfg(target_os="linux")]
+    #[cfg(target_os="android")]
+    fn test_font_advertising_smcp_gets_true_small_caps() {
+        use super::has_true_small_caps;
+        use platform::font::FontTable;
+
+        let has_smcp = has_true_small_caps(Some(FontTable::new(SMCP_GSUB.to_vec())));
+        assert!(has_smcp);
+        let pt_size = Au::from_px(20);
+        assert_eq!(actual_pt_size_for_variant(pt_size, font_variant::small_caps, has_smcp,
+                                              SMALL_CAPS_SCALE_FACTOR),
+                   (pt_size, true));
+
+        // Fonts without a GSUB table fake small caps.
+        assert!(!has_true_small_caps(None));
+    }
+
+    #[test]
+    fn test_small_caps_are_decided_per_font_in_group() {
+        // The first family has `smcp` glyphs and the second, e.g. for another script, doesn't.
+        let smcp = with_table(AHEM, b"GSUB", SMCP_GSUB);
+        let families = vec!(("Smcp", template("smcp.ttf", smcp.as_slice())),
+                            ("Ahem", template("ahem.ttf", AHEM)));
+        let font_cache_task = test_font_cache_task(families, template("last-resort.ttf", AHEM));
+        let mut font_context = test_font_context(font_cache_task);
+        let style = small_caps_style_with_families(["Smcp", "Ahem"]);
+        let font_group = font_context.get_layout_font_group_for_style(style)
+                                     .ok()
+                                     .expect("no font group");
+        let modes: Vec<(bool, Au)> = font_group.fonts.iter().map(|font| {
+            let font = font.borrow();
+            (font.true_small_caps, font.actual_pt_size)
+        }).collect();
+        let pt_size = Au::from_px(16);
+        assert_eq!(modes, vec!((true, pt_size),
+                               (false, pt_size.scale_by(SMALL_CAPS_SCALE_FACTOR))));
+    }
+
+    #[test]
+    fn test_only_fonts_with_color_tables_are_color_fonts() {
+        // An empty `COLR` table: version 0, no base glyphs and no layers.
+        let colr = with_table(AHEM, b"COLR", [0u8, ..14]);
+        let families = vec!(("Color", template("color.ttf", colr.as_slice())),
+                            ("Ahem", template("ahem.ttf", AHEM)));
+        let font_cache_task = test_font_cache_task(families, template("last-resort.ttf", AHEM));
+        let mut font_context = test_font_context(font_cache_task);
+        let font_group = font_group(&mut font_context, ["Color", "Ahem"]);
+        let is_color: Vec<bool> = font_group.fonts.iter().map(|font| {
+            font.borrow().is_color
+        }).collect();
+        assert_eq!(is_color, vec!(true, false));
+    }
+
+    #[test]
+    fn test_faces_with_disjoint_unicode_ranges_share_a_font_group() {
+        let basic_latin = vec!(UnicodeRange::new(0x0, 0x7f));
+        let latin1 = vec!(UnicodeRange::new(0x80, 0xff));
+        let families = vec!(("Latin", ranged_template("latin.ttf", AHEM, basic_latin.clone())),
+                            ("Latin1", ranged_template("latin1.ttf", AHEM, latin1.clone())));
+        let font_cache_task = test_font_cache_task(families, template("last-resort.ttf", AHEM));
+        let mut font_context = test_font_context(font_cache_task);
+        let font_group = font_group(&mut font_context, ["Latin", "Latin1"]);
+        let ranges: Vec<Option<Vec<UnicodeRange>>> = font_group.fonts.iter().map(|font| {
+            font.borrow().descriptor.unicode_range.clone()
+        }).collect();
+        assert_eq!(ranges, vec!(Some(basic_latin), Some(latin1)));
+        let font_for_char = |codepoint: char| {
+            font_group.font_for_char(codepoint).borrow().handle.get_template().identifier.clone()
+        };
+        assert_eq!(font_for_char('a'), "latin.ttf".to_string());
+        assert_eq!(font_for_char('\xe9'), "latin1.ttf".to_string());
+    }
+
+    #[test]
+    fn test_chars_route_to_the_font_with_a_glyph_for_them() {
+        let families = vec!(("Glyphicons", template("glyphicons.ttf", GLYPHICONS)),
+                            ("Ahem", template("ahem.ttf", AHEM)));
+        let font_cache_task = test_font_cache_task(families, template("last-resort.ttf", AHEM));
+        let mut font_context = test_font_context(font_cache_task);
+        let font_group = font_group(&mut font_context, ["Glyphicons", "Ahem"]);
+        let font_for_char = |codepoint: char| {
+            font_group.font_for_char(codepoint).borrow().handle.get_template().identifier.clone()
+        };
+        // Only Glyphicons has the envelope, and only Ahem has the letters.
+        assert_eq!(font_for_char('\u2709'), "glyphicons.ttf".to_string());
+        assert_eq!(font_for_char('a'), "ahem.ttf".to_string());
+        // Neither has a glyph for this one, so it's left to the last resort font.
+        assert_eq!(font_for_char('\u4e2d'), "last-resort.ttf".to_string());
+    }
+
+    #[test]
+    fn test_custom_small_caps_scale() {
+        let pt_size = Au::from_px(20);
+        let (default_size, _) = actual_pt_size_for_variant(pt_size, font_variant::small_caps,
+                                                           false, SMALL_CAPS_SCALE_FACTOR);
+        let (custom_size, _) = actual_pt_size_for_variant(pt_size, font_variant::small_caps,
+                                                          false, 0.6);
+        assert_eq!(custom_size, pt_size.scale_by(0.6));
+        assert!(custom_size != default_size);
+    }
+
+    #[test]
+    fn test_font_context_options_are_applied() {
+        let font_cache_task = test_font_cache_task(vec!(), template("last-resort.ttf", AHEM));
+        let options = FontContextOptions {
+            small_caps_scale: Some(0.2),
+            subpixel_positioning: false,
+            hinting: NoHinting,
+            ..Default::default()
+        };
+        let font_context = FontContext::new(font_cache_task, options);
+        assert_eq!(font_context.small_caps_scale, 0.5);
+        assert!(!font_context.subpixel_positioning());
+        assert_eq!(font_context.hinting(), NoHinting);
+    }
+
+    #[test]
+    fn test_minimum_font_size_floors_actual_size() {
+        let font_cache_task = test_font_cache_task(vec!(("Ahem", template("ahem.ttf", AHEM))),
+                                                   template("last-resort.ttf", AHEM));
+        let options = FontContextOptions {
+            minimum_font_size: Some(Au::from_px(12)),
+            ..Default::default()
+        };
+        let mut font_context = FontContext::new(font_cache_task, options);
+        let mut style = (*style_with_families(["Ahem"])).clone();
+        style.font_size = Au::from_px(6);
+        let font_group = font_context.get_layout_font_group_for_style(Arc::new(style))
+                                     .ok()
+                                     .expect("no font group");
+        let primary = font_group.primary();
+        assert!(primary.borrow().actual_pt_size >= Au::from_px(12));
+        assert_eq!(primary.borrow().requested_pt_size, Au::from_px(6));
+
+        assert_eq!(apply_minimum_font_size(Au::from_px(20), Some(Au::from_px(12))),
+                   Au::from_px(20));
+        assert_eq!(apply_minimum_font_size(Au::from_px(6), None), Au::from_px(6));
+    }
+
+    #[test]
+    fn test_regular_font_requested_as_bold_is_synthesized() {
+        let threshold = DEFAULT_SYNTHETIC_BOLD_THRESHOLD;
+        assert!(needs_synthetic_bold(font_weight::Weight700, font_weight::Weight400, threshold));
+        assert!(!needs_synthetic_bold(font_weight::Weight700, font_weight::Weight700, threshold));
+        assert!(!needs_synthetic_bold(font_weight::Weight500, font_weight::Weight400, threshold));
+        assert!(!needs_synthetic_bold(font_weight::Weight400, font_weight::Weight700, threshold));
+        assert!(needs_synthetic_bold(font_weight::Weight500, font_weight::Weight400, 100));
+    }
+
+    #[test]
+    fn test_upright_font_requested_as_italic_is_synthesized() {
+        assert!(needs_synthetic_oblique(true, false));
+        assert!(!needs_synthetic_oblique(true, true));
+        assert!(!needs_synthetic_oblique(false, false));
+        assert!(!needs_synthetic_oblique(false, true));
+    }
+
+    #[test]
+    fn test_disabled_synthesis_uses_the_available_face() {
+        let threshold = DEFAULT_SYNTHETIC_BOLD_THRESHOLD;
+        let bold_italic = FontTemplateDescriptor::new(font_weight::Weight700,
+                                                      font_stretch::normal,
+                                                      true);
+        assert_eq!(synthetic_bold_and_oblique(&bold_italic, font_weight::Weight400, false,
+                                              threshold, all_synthesis()),
+                   (true, true));
+
+        let no_synthesis = font_synthesis::T { weight: false, style: false };
+        assert_eq!(synthetic_bold_and_oblique(&bold_italic, font_weight::Weight400, false,
+                                              threshold, no_synthesis),
+                   (false, false));
+        let weight_only = font_synthesis::T { weight: true, style: false };
+        assert_eq!(synthetic_bold_and_oblique(&bold_italic, font_weight::Weight400, false,
+                                              threshold, weight_only),
+                   (true, false));
+
+        // The layout font cache keeps fonts created without synthesis apart.
+        let descriptor = regular_descriptor();
+        let mut cache = HashMap::new();
+        cache.insert(regular_font_cache_key("serif", font_weight::Weight400), ());
+        assert!(!cache.contains_key(&layout_font_cache_key("serif", &descriptor,
+                                                           Au::from_px(16),
+                                                           font_variant::normal, true,
+                                                           text_rendering::auto, &vec!(),
+                                                           no_synthesis, DEFAULT_HINTING_MODE)));
+    }
+
+    #[test]
+    fn test_render_font_cache_stays_within_budget() {
+        let budget = estimated_render_font_cost(Au::from_px(24)) * 8;
+        let mut cache: RenderFontCache<()> = RenderFontCache::new(budget);
+        let mut fonts = vec!();
+        for i in range(0i, 200) {
+            let pt_size = Au::from_px(8 + i % 17);
+            let cost = estimated_render_font_cost(pt_size);
+            let font = Arc::new(());
+            let key = render_font_key(pt_size, format!("font-{}", i).as_slice(), &vec!());
+            cache.insert(key, &font, cost);
+            fonts.push(font);
+            assert!(cache.total_cost <= budget);
+        }
+        assert!(cache.entries.len() >= 8);
+        assert!(cache.entries.len() < 200);
+    }
+
+    #[test]
+    fn test_invalidating_family_keeps_other_families() {
+        let mut layout_cache = HashMap::new();
+        layout_cache.insert(regular_font_cache_key("webfont", font_weight::Weight400), 1u);
+        layout_cache.insert(regular_font_cache_key("webfont", font_weight::Weight700), 2u);
+        layout_cache.insert(regular_font_cache_key("serif", font_weight::Weight400), 3u);
+        let mut removed = remove_family(&mut layout_cache, "webfont");
+        removed.sort();
+        assert_eq!(removed, vec!(1, 2));
+
+        // The next lookup of the invalidated family misses and goes back to the font cache task.
+        assert!(!layout_cache.contains_key(&regular_font_cache_key("webfont",
+                                                                   font_weight::Weight400)));
+        assert!(layout_cache.contains_key(&regular_font_cache_key("serif",
+                                                                  font_weight::Weight400)));
+
+        let pt_size = Au::from_px(16);
+        let mut render_cache: RenderFontCache<()> = RenderFontCache::new(1024 * 1024);
+        let (stale_font, other_font) = (Arc::new(()), Arc::new(()));
+        render_cache.insert(render_font_key(pt_size, "webfont.woff", &vec!()), &stale_font, 100);
+        render_cache.insert(render_font_key(pt_size, "serif.ttf", &vec!()), &other_font, 100);
+        render_cache.remove_identifiers(vec!("webfont.woff".to_string()).as_slice());
+        assert!(render_cache.find(&render_font_key(pt_size, "webfont.woff", &vec!())).is_none());
+        assert!(render_cache.find(&render_font_key(pt_size, "serif.ttf", &vec!())).is_some());
+        assert_eq!(render_cache.total_cost, 100);
+    }
+
+    #[test]
+    fn test_render_font_cache_clear() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<()> = RenderFontCache::new(1024 * 1024);
+        let font = Arc::new(());
+        cache.insert(render_font_key(pt_size, "a", &vec!()), &font, 100);
+        cache.clear();
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.total_cost, 0);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_none());
+    }
+
+    #[test]
+    fn test_cleared_font_context_loads_fonts_again() {
+        let mut font_context = ahem_font_context();
+        font_group(&mut font_context, ["Ahem"]);
+        assert!(font_context.estimated_memory_usage() > 0);
+
+        font_context.clear_caches();
+        assert!(font_context.layout_font_cache.is_empty());
+        assert_eq!(font_context.fallback_font_cache.iter().count(), 0);
+        assert!(font_context.render_font_cache.entries.is_empty());
+        assert!(font_context.font_group_cache.entries.is_empty());
+        assert_eq!(font_context.estimated_memory_usage(), 0);
+
+        // The next lookup asks the font cache task again.
+        let round_trips = font_context.cache_stats().font_cache_task_round_trips;
+        let font_group = font_group(&mut font_context, ["Ahem"]);
+        assert_eq!(font_group.primary().borrow().handle.get_template().identifier,
+                   "ahem.ttf".to_string());
+        assert_eq!(font_context.cache_stats().font_cache_task_round_trips, round_trips + 2);
+    }
+
+    #[test]
+    fn test_render_font_cache_copy_starts_empty() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<()> = RenderFontCache::new(4096);
+        let font = Arc::new(());
+        cache.insert(render_font_key(pt_size, "a", &vec!()), &font, 100);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_some());
+
+        let mut copy = cache.empty_copy();
+        assert!(copy.entries.is_empty());
+        assert_eq!(copy.total_cost, 0);
+        assert_eq!(copy.budget, 4096);
+        assert_eq!((copy.hits, copy.misses), (0, 0));
+        assert!(copy.find(&render_font_key(pt_size, "a", &vec!())).is_none());
+
+        // The original keeps its fonts.
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_some());
+    }
+
+    #[test]
+    fn test_render_font_cache_counts_repeat_lookup_as_hit() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<()> = RenderFontCache::new(1024 * 1024);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_none());
+        let font = Arc::new(());
+        cache.insert(render_font_key(pt_size, "a", &vec!()), &font, 100);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_some());
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_warmed_render_fonts_are_hit_later() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let mut created = 0u;
+
+        // Warming a group of two fonts creates one render font for each.
+        let warmed: Vec<Arc<uint>> = vec!("a", "b").iter().map(|identifier| {
+            find_or_create_render_font(&mut cache,
+                                       None,
+                                       render_font_key(pt_size, *identifier, &vec!()),
+                                       100,
+                                       || { created += 1; created })
+        }).collect();
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(created, 2);
+
+        // Painting later finds them instead of creating them again.
+        let hits = cache.hits;
+        for (identifier, font) in vec!("a", "b").iter().zip(warmed.iter()) {
+            let found = find_or_create_render_font(&mut cache,
+                                                   None,
+                                                   render_font_key(pt_size, *identifier, &vec!()),
+                                                   100,
+                                                   || { created += 1; created });
+            assert!(arc_ptr_eq(&found, font));
+        }
+        assert_eq!(created, 2);
+        assert_eq!(cache.hits, hits + 2);
+    }
+
+    #[test]
+    fn test_subpixel_positioning_separates_render_fonts() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let mut created = 0u;
+        let subpixel_key = render_font_key(pt_size, "a", &vec!());
+        let snapped_key = RenderFontCacheKey {
+            subpixel_positioning: false,
+            ..render_font_key(pt_size, "a", &vec!())
+        };
+
+        let subpixel = find_or_create_render_font(&mut cache, None, subpixel_key, 100,
+                                                  || { created += 1; created });
+        let snapped = find_or_create_render_font(&mut cache, None, snapped_key, 100,
+                                                 || { created += 1; created });
+        assert!(!arc_ptr_eq(&subpixel, &snapped));
+        assert_eq!(created, 2);
+        assert_eq!(cache.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_memory_usage_grows_with_cached_fonts() {
+        let no_templates: Vec<(String, uint)> = vec!();
+        assert_eq!(estimated_layout_fonts_cost(no_templates.into_iter()), 0);
+
+        // Two sizes of the same template share its data.
+        let serif = vec!(("serif.ttf".to_string(), 50000u), ("serif.ttf".to_string(), 50000u));
+        assert_eq!(estimated_layout_fonts_cost(serif.clone().into_iter()),
+                   2 * LAYOUT_FONT_BASE_COST + 50000);
+        let mut more = serif;
+        more.push(("sans.ttf".to_string(), 30000u));
+        assert_eq!(estimated_layout_fonts_cost(more.into_iter()),
+                   3 * LAYOUT_FONT_BASE_COST + 80000);
+
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let font = Arc::new(1u);
+        cache.insert(render_font_key(Au::from_px(16), "serif.ttf", &vec!()), &font, 100);
+        assert_eq!(cache.total_cost, 100);
+        cache.clear();
+        assert_eq!(cache.total_cost, 0);
+    }
+
+    #[test]
+    fn test_hinting_modes_are_cached_apart() {
+        assert_eq!(DEFAULT_HINTING_MODE, FullHinting);
+
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let mut created = 0u;
+        let mut fonts = vec!();
+        for &hinting in [NoHinting, SlightHinting, FullHinting].iter() {
+            let key = RenderFontCacheKey {
+                hinting: hinting,
+                ..render_font_key(pt_size, "a", &vec!())
+            };
+            fonts.push(find_or_create_render_font(&mut cache, None, key, 100,
+                                                  || { created += 1; created }));
+        }
+        assert_eq!(created, 3);
+        assert!(!arc_ptr_eq(&fonts[0], &fonts[1]));
+        assert!(!arc_ptr_eq(&fonts[1], &fonts[2]));
+
+        // Fonts created with the default mode are still found under the keys they always had.
+        let default_font = find_or_create_render_font(&mut cache, None,
+                                                      render_font_key(pt_size, "a", &vec!()),
+                                                      100, || { created += 1; created });
+        assert!(arc_ptr_eq(&default_font, &fonts[2]));
+        assert_eq!(created, 3);
+
+        let descriptor = regular_descriptor();
+        let layout_key = |hinting: HintingMode| {
+            layout_font_cache_key("serif", &descriptor, pt_size, font_variant::normal, true,
+                                  text_rendering::auto, &vec!(), all_synthesis(), hinting)
+        };
+        let mut layout_cache = HashMap::new();
+        layout_cache.insert(regular_font_cache_key("serif", font_weight::Weight400), ());
+        assert!(layout_cache.contains_key(&layout_key(FullHinting)));
+        assert!(!layout_cache.contains_key(&layout_key(NoHinting)));
+        assert!(!layout_cache.contains_key(&layout_key(SlightHinting)));
+    }
+
+    #[test]
+    fn test_render_font_cache_drops_freed_fonts() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let font = Arc::new(1u);
+        cache.insert(render_font_key(pt_size, "a", &vec!()), &font, 100);
+        drop(font);
+
+        // The caller recreates the font instead of getting the freed one back.
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_none());
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.total_cost, 0);
+        let font = Arc::new(2u);
+        cache.insert(render_font_key(pt_size, "a", &vec!()), &font, 100);
+        assert_eq!(*cache.find(&render_font_key(pt_size, "a", &vec!())).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_render_font_cache_keeps_feature_settings_apart() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let tabular = vec!(FeatureTagValue { tag: "tnum".to_string(), value: 1 });
+        let no_ligatures = vec!(FeatureTagValue { tag: "liga".to_string(), value: 0 });
+        let tabular_font = Arc::new(1u);
+        let no_ligatures_font = Arc::new(2u);
+        cache.insert(render_font_key(pt_size, "a", &tabular), &tabular_font, 100);
+        assert!(cache.find(&render_font_key(pt_size, "a", &no_ligatures)).is_none());
+        cache.insert(render_font_key(pt_size, "a", &no_ligatures), &no_ligatures_font, 100);
+        assert_eq!(*cache.find(&render_font_key(pt_size, "a", &tabular)).unwrap(), 1);
+        assert_eq!(*cache.find(&render_font_key(pt_size, "a", &no_ligatures)).unwrap(), 2);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_none());
+    }
+
+    #[test]
+    fn test_render_font_cache_keeps_descriptors_apart() {
+        let pt_size = Au::from_px(16);
+        let mut cache: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let regular = render_font_key(pt_size, "a", &vec!());
+        let mut synthetic_bold = regular.clone();
+        synthetic_bold.descriptor = FontTemplateDescriptor::new(font_weight::Weight700,
+                                                                font_stretch::normal,
+                                                                false);
+        synthetic_bold.synthetic_bold = true;
+        let regular_font = Arc::new(1u);
+        let synthetic_bold_font = Arc::new(2u);
+        cache.insert(regular.clone(), &regular_font, 100);
+        assert!(cache.find(&synthetic_bold).is_none());
+        cache.insert(synthetic_bold.clone(), &synthetic_bold_font, 100);
+        assert_eq!(*cache.find(&regular).unwrap(), 1);
+        assert_eq!(*cache.find(&synthetic_bold).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_render_font_cache_evicts_least_recently_used() {
+        let pt_size = Au::from_px(16);
+        let cost = estimated_render_font_cost(pt_size);
+        let mut cache: RenderFontCache<()> = RenderFontCache::new(cost * 2);
+        let fonts = Vec::from_fn(3, |_| Arc::new(()));
+        cache.insert(render_font_key(pt_size, "a", &vec!()), &fonts[0], cost);
+        cache.insert(render_font_key(pt_size, "b", &vec!()), &fonts[1], cost);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_some());
+        cache.insert(render_font_key(pt_size, "c", &vec!()), &fonts[2], cost);
+        assert!(cache.find(&render_font_key(pt_size, "a", &vec!())).is_some());
+        assert!(cache.find(&render_font_key(pt_size, "b", &vec!())).is_none());
+        assert!(cache.find(&render_font_key(pt_size, "c", &vec!())).is_some());
+    }
+
+    #[test]
+    fn test_shared_render_font_cache_hands_out_same_font() {
+        let pt_size = Au::from_px(16);
+        let shared: Arc<Mutex<RenderFontCache<uint>>> =
+            Arc::new(Mutex::new(RenderFontCache::new(1024 * 1024)));
+        let mut first_task: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let mut second_task: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let mut created = 0u;
+
+        let first = find_or_create_render_font(&mut first_task,
+                                               Some(&mut *shared.lock()),
+                                               render_font_key(pt_size, "a", &vec!()),
+                                               100,
+                                               || { created += 1; created });
+        let second = find_or_create_render_font(&mut second_task,
+                                                Some(&mut *shared.lock()),
+                                                render_font_key(pt_size, "a", &vec!()),
+                                                100,
+                                                || { created += 1; created });
+        assert!(arc_ptr_eq(&first, &second));
+        assert_eq!(created, 1);
+
+        // Without a shared cache, each task creates its own font.
+        let mut unshared_task: RenderFontCache<uint> = RenderFontCache::new(1024 * 1024);
+        let unshared = find_or_create_render_font(&mut unshared_task,
+                                                  None,
+                                                  render_font_key(pt_size, "a", &vec!()),
+                                                  100,
+                                                  || { created += 1; created });
+        assert!(!arc_ptr_eq(&first, &unshared));
+    }
+
+    #[test]
+    fn test_repeated_run_hits_shared_shape_cache() {
+        let mut cache: ShapeCache<uint> = ShapeCache::new(8);
+        let mut shaped = 0u;
+        for _ in range(0u, 3) {
+            let glyphs = cache.find_or_shape(shape_key("Home"), || {
+                shaped += 1;
+                Arc::new(4u)
+            });
+            assert_eq!(*glyphs, 4);
+        }
+        assert_eq!(shaped, 1);
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+
+        // Other text, or the same text in a different font, is shaped again.
+        cache.find_or_shape(shape_key("About"), || Arc::new(5u));
+        let mut bigger = shape_key("Home");
+        bigger.pt_size = Au::from_px(24);
+        cache.find_or_shape(bigger, || Arc::new(4u));
+        let mut unhinted = shape_key("Home");
+        unhinted.hinting = NoHinting;
+        cache.find_or_shape(unhinted, || Arc::new(4u));
+        assert_eq!(cache.misses, 4);
+    }
+}
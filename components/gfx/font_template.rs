@@ -2,14 +2,36 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use style::computed_values::font_weight;
+use style::computed_values::{font_stretch, font_weight};
 use platform::font_context::FontContextHandle;
 use platform::font::FontHandle;
 use platform::font_template::FontTemplateData;
 
+use std::hash::{Hash, Writer};
 use sync::{Arc, Weak};
 use font::FontHandleMethods;
 
+/// A range of codepoints, inclusive at both ends, as given by the `unicode-range` descriptor of
+/// an `@font-face` rule.
+#[deriving(Clone, PartialEq, Show)]
+pub struct UnicodeRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl UnicodeRange {
+    pub fn new(first: u32, last: u32) -> UnicodeRange {
+        UnicodeRange {
+            first: first,
+            last: last,
+        }
+    }
+
+    pub fn contains(&self, codepoint: char) -> bool {
+        self.first <= codepoint as u32 && codepoint as u32 <= self.last
+    }
+}
+
 /// Describes how to select a font from a given family.
 /// This is very basic at the moment and needs to be
 /// expanded or refactored when we support more of the
@@ -17,14 +39,29 @@ use font::FontHandleMethods;
 #[deriving(Clone)]
 pub struct FontTemplateDescriptor {
     pub weight: font_weight::T,
+    pub stretch: font_stretch::T,
     pub italic: bool,
+    /// The codepoints that the face is restricted to, or `None` if it covers everything. This
+    /// plays no part in matching faces to styles, so it isn't compared or hashed.
+    pub unicode_range: Option<Vec<UnicodeRange>>,
 }
 
 impl FontTemplateDescriptor {
-    pub fn new(weight: font_weight::T, italic: bool) -> FontTemplateDescriptor {
+    pub fn new(weight: font_weight::T, stretch: font_stretch::T, italic: bool)
+               -> FontTemplateDescriptor {
         FontTemplateDescriptor {
             weight: weight,
+            stretch: stretch,
             italic: italic,
+            unicode_range: None,
+        }
+    }
+
+    /// Returns true if the face may be used for the given codepoint.
+    pub fn covers(&self, codepoint: char) -> bool {
+        match self.unicode_range {
+            None => true,
+            Some(ref ranges) => ranges.iter().any(|range| range.contains(codepoint)),
         }
     }
 }
@@ -32,15 +69,28 @@ impl FontTemplateDescriptor {
 impl PartialEq for FontTemplateDescriptor {
     fn eq(&self, other: &FontTemplateDescriptor) -> bool {
         self.weight.is_bold() == other.weight.is_bold() &&
+        self.stretch == other.stretch &&
         self.italic == other.italic
     }
 }
 
+impl Eq for FontTemplateDescriptor {}
+
+/// Hashes only what `eq` compares, so that descriptors that are equal hash equally.
+impl<S: Writer> Hash<S> for FontTemplateDescriptor {
+    fn hash(&self, state: &mut S) {
+        self.weight.is_bold().hash(state);
+        self.stretch.hash(state);
+        self.italic.hash(state);
+    }
+}
+
 /// This describes all the information needed to create
 /// font instance handles. It contains a unique
 /// FontTemplateData structure that is platform specific.
 pub struct FontTemplate {
     identifier: String,
+    unicode_range: Option<Vec<UnicodeRange>>,
     descriptor: Option<FontTemplateDescriptor>,
     weak_ref: Option<Weak<FontTemplateData>>,
     strong_ref: Option<Arc<FontTemplateData>>,      // GWTODO: Add code path to unset the strong_ref for web fonts!
@@ -51,9 +101,14 @@ pub struct FontTemplate {
 /// is common, regardless of the number of instances of
 /// this font handle per thread.
 impl FontTemplate {
-    pub fn new(identifier: &str, maybe_bytes: Option<Vec<u8>>) -> FontTemplate {
+    pub fn new(identifier: &str, maybe_bytes: Option<Vec<u8>>,
+               unicode_range: Option<Vec<UnicodeRange>>) -> FontTemplate {
         let maybe_data = match maybe_bytes {
-            Some(_) => Some(FontTemplateData::new(identifier, maybe_bytes)),
+            Some(_) => {
+                let mut data = FontTemplateData::new(identifier, maybe_bytes);
+                data.unicode_range = unicode_range.clone();
+                Some(data)
+            }
             None => None,
         };
 
@@ -69,6 +124,7 @@ impl FontTemplate {
 
         FontTemplate {
             identifier: identifier.to_string(),
+            unicode_range: unicode_range,
             descriptor: None,
             weak_ref: maybe_weak_ref,
             strong_ref: maybe_strong_ref,
@@ -89,8 +145,8 @@ impl FontTemplate {
         // so that we can do font matching against it again in the future
         // without having to reload the font (unless it is an actual match).
         match self.descriptor {
-            Some(actual_desc) => {
-                if *requested_desc == actual_desc {
+            Some(ref actual_desc) => {
+                if requested_desc == actual_desc {
                     Some(self.get_data())
                 } else {
                     None
@@ -103,6 +159,7 @@ impl FontTemplate {
                     match handle {
                         Ok(handle) => {
                             let actual_desc = FontTemplateDescriptor::new(handle.boldness(),
+                                                handle.stretchiness(),
                                                 handle.is_italic());
                             let desc_match = actual_desc == *requested_desc;
 
@@ -148,7 +205,9 @@ impl FontTemplate {
             Some(data) => data,
             None => {
                 assert!(self.strong_ref.is_none());
-                let template_data = Arc::new(FontTemplateData::new(self.identifier.as_slice(), None));
+                let mut template_data = FontTemplateData::new(self.identifier.as_slice(), None);
+                template_data.unicode_range = self.unicode_range.clone();
+                let template_data = Arc::new(template_data);
                 self.weak_ref = Some(template_data.downgrade());
                 template_data
             }
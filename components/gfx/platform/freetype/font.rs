@@ -6,17 +6,18 @@ extern crate freetype;
 
 use font::{FontHandleMethods, FontMetrics, FontTableMethods};
 use font::{FontTableTag, FractionalPixel};
+use font::{DEFAULT_HINTING_MODE, FullHinting, HintingMode, NoHinting, SlightHinting};
 use servo_util::geometry::Au;
 use servo_util::geometry;
 use platform::font_context::FontContextHandle;
 use text::glyph::GlyphId;
 use text::util::{float_to_fixed, fixed_to_float};
-use style::computed_values::font_weight;
+use style::computed_values::{font_stretch, font_weight};
 use platform::font_template::FontTemplateData;
 
 use freetype::freetype::{FT_Get_Char_Index, FT_Get_Postscript_Name};
 use freetype::freetype::{FT_Load_Glyph, FT_Set_Char_Size};
-use freetype::freetype::{FT_Get_Kerning, FT_Get_Sfnt_Table};
+use freetype::freetype::{FT_Get_Kerning, FT_Get_Sfnt_Table, FT_Load_Sfnt_Table};
 use freetype::freetype::{FT_New_Memory_Face, FT_Done_Face};
 use freetype::freetype::{FTErrorMethods, FT_F26Dot6, FT_Face, FT_FaceRec};
 use freetype::freetype::{FT_GlyphSlot, FT_Library, FT_Long, FT_ULong};
@@ -39,11 +40,32 @@ fn fixed_to_float_ft(f: i32) -> f64 {
     fixed_to_float(6, f)
 }
 
-pub struct FontTable;
+// FreeType's `FT_LOAD_NO_HINTING` and `FT_LOAD_TARGET_LIGHT` glyph load flags.
+static LOAD_NO_HINTING: i32 = 1 << 1;
+static LOAD_TARGET_LIGHT: i32 = 1 << 16;
+
+/// Returns the flags to load glyphs with for the given hinting mode.
+fn load_flags(hinting: HintingMode) -> i32 {
+    match hinting {
+        NoHinting => LOAD_NO_HINTING,
+        SlightHinting => LOAD_TARGET_LIGHT,
+        FullHinting => 0,
+    }
+}
+
+pub struct FontTable {
+    data: Vec<u8>,
+}
+
+impl FontTable {
+    pub fn new(data: Vec<u8>) -> FontTable {
+        FontTable { data: data }
+    }
+}
 
 impl FontTableMethods for FontTable {
-    fn with_buffer(&self, _blk: |*const u8, uint|) {
-        fail!()
+    fn with_buffer(&self, blk: |*const u8, uint|) {
+        blk(self.data.as_ptr(), self.data.len());
     }
 }
 
@@ -52,7 +74,8 @@ pub struct FontHandle {
     // if the font is created using FT_Memory_Face.
     pub font_data: Arc<FontTemplateData>,
     pub face: FT_Face,
-    pub handle: FontContextHandle
+    pub handle: FontContextHandle,
+    pub hinting: HintingMode,
 }
 
 #[unsafe_destructor]
@@ -86,7 +109,8 @@ impl FontHandleMethods for FontHandle {
               let handle = FontHandle {
                   face: face,
                   font_data: template.clone(),
-                  handle: fctx.clone()
+                  handle: fctx.clone(),
+                  hinting: DEFAULT_HINTING_MODE,
               };
               Ok(handle)
             }
@@ -156,6 +180,26 @@ impl FontHandleMethods for FontHandle {
             }
         }
     }
+    fn stretchiness(&self) -> font_stretch::T {
+        unsafe {
+            let os2 = FT_Get_Sfnt_Table(self.face, ft_sfnt_os2) as *mut TT_OS2;
+            let valid = os2.is_not_null() && (*os2).version != 0xffff;
+            if !valid {
+                return font_stretch::normal
+            }
+            match (*os2).usWidthClass {
+                1 => font_stretch::ultra_condensed,
+                2 => font_stretch::extra_condensed,
+                3 => font_stretch::condensed,
+                4 => font_stretch::semi_condensed,
+                6 => font_stretch::semi_expanded,
+                7 => font_stretch::expanded,
+                8 => font_stretch::extra_expanded,
+                9 => font_stretch::ultra_expanded,
+                _ => font_stretch::normal,
+            }
+        }
+    }
 
     fn glyph_index(&self,
                        codepoint: char) -> Option<GlyphId> {
@@ -185,7 +229,7 @@ impl FontHandleMethods for FontHandle {
                            glyph: GlyphId) -> Option<FractionalPixel> {
         assert!(self.face.is_not_null());
         unsafe {
-            let res =  FT_Load_Glyph(self.face, glyph as FT_UInt, 0);
+            let res =  FT_Load_Glyph(self.face, glyph as FT_UInt, load_flags(self.hinting));
             if res.succeeded() {
                 let void_glyph = (*self.face).glyph;
                 let slot: FT_GlyphSlot = mem::transmute(void_glyph);
@@ -259,8 +303,24 @@ impl FontHandleMethods for FontHandle {
         return metrics;
     }
 
-    fn get_table_for_tag(&self, _: FontTableTag) -> Option<FontTable> {
-        None
+    fn get_table_for_tag(&self, tag: FontTableTag) -> Option<FontTable> {
+        let tag = tag as FT_ULong;
+        unsafe {
+            // With a null buffer, FreeType only reports the length of the table.
+            let mut len: FT_ULong = 0;
+            if !FT_Load_Sfnt_Table(self.face, tag, 0, ptr::null_mut(), &mut len).succeeded() {
+                return None
+            }
+            let mut data = Vec::from_elem(len as uint, 0u8);
+            if !FT_Load_Sfnt_Table(self.face, tag, 0, data.as_mut_ptr(), &mut len).succeeded() {
+                return None
+            }
+            Some(FontTable::new(data))
+        }
+    }
+
+    fn set_hinting(&mut self, hinting: HintingMode) {
+        self.hinting = hinting;
     }
 }
 
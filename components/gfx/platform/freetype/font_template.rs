@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use font::font_data_has_color_tables;
+use font_template::UnicodeRange;
 use std::io;
 use std::io::File;
 
@@ -12,6 +14,8 @@ use std::io::File;
 pub struct FontTemplateData {
     pub bytes: Vec<u8>,
     pub identifier: String,
+    /// The codepoints that the `@font-face` rule limits this font to, if any.
+    pub unicode_range: Option<Vec<UnicodeRange>>,
 }
 
 impl FontTemplateData {
@@ -30,6 +34,17 @@ impl FontTemplateData {
         FontTemplateData {
             bytes: bytes,
             identifier: identifier.to_string(),
+            unicode_range: None,
         }
     }
+
+    /// Returns true if the font has color glyphs.
+    pub fn is_color(&self) -> bool {
+        font_data_has_color_tables(self.bytes.as_slice())
+    }
+
+    /// Returns the number of bytes of font data that this template holds.
+    pub fn byte_len(&self) -> uint {
+        self.bytes.len()
+    }
 }
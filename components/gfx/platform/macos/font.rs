@@ -10,12 +10,12 @@ extern crate core_text;
 
 use font::{FontHandleMethods, FontMetrics, FontTableMethods};
 use font::FontTableTag;
-use font::FractionalPixel;
+use font::{DEFAULT_HINTING_MODE, FractionalPixel, FullHinting, HintingMode};
 use servo_util::geometry::{Au, px_to_pt};
 use servo_util::geometry;
 use platform::macos::font_context::FontContextHandle;
 use text::glyph::GlyphId;
-use style::computed_values::font_weight;
+use style::computed_values::{font_stretch, font_weight};
 use platform::font_template::FontTemplateData;
 
 use core_foundation::base::CFIndex;
@@ -54,6 +54,9 @@ impl FontTableMethods for FontTable {
 pub struct FontHandle {
     pub font_data: Arc<FontTemplateData>,
     pub ctfont: CTFont,
+    /// Core Text never hints outlines, so this only decides whether advances are rounded to whole
+    /// pixels, as the FreeType fonts of other platforms are when fully hinted.
+    pub hinting: HintingMode,
 }
 
 impl FontHandleMethods for FontHandle {
@@ -70,6 +73,7 @@ impl FontHandleMethods for FontHandle {
                 Ok(FontHandle {
                     font_data: template.clone(),
                     ctfont: ctfont.clone_with_font_size(size),
+                    hinting: DEFAULT_HINTING_MODE,
                 })
             }
             None => {
@@ -110,6 +114,22 @@ impl FontHandleMethods for FontHandle {
         return font_weight::Weight900;
     }
 
+    fn stretchiness(&self) -> font_stretch::T {
+        // -1.0 to 1.0
+        let normalized = self.ctfont.all_traits().normalized_width();
+        // 0.0 to 9.0
+        let normalized = (normalized + 1.0) / 2.0 * 9.0;
+        if normalized < 1.0 { return font_stretch::ultra_condensed; }
+        if normalized < 2.0 { return font_stretch::extra_condensed; }
+        if normalized < 3.0 { return font_stretch::condensed; }
+        if normalized < 4.0 { return font_stretch::semi_condensed; }
+        if normalized < 5.0 { return font_stretch::normal; }
+        if normalized < 6.0 { return font_stretch::semi_expanded; }
+        if normalized < 7.0 { return font_stretch::expanded; }
+        if normalized < 8.0 { return font_stretch::extra_expanded; }
+        return font_stretch::ultra_expanded;
+    }
+
     fn glyph_index(&self, codepoint: char) -> Option<GlyphId> {
         let characters: [UniChar,  ..1] = [codepoint as UniChar];
         let mut glyphs: [CGGlyph, ..1] = [0 as CGGlyph];
@@ -140,7 +160,11 @@ impl FontHandleMethods for FontHandle {
                                                           &glyphs[0],
                                                           ptr::null_mut(),
                                                           1);
-        Some(advance as FractionalPixel)
+        let advance = advance as FractionalPixel;
+        match self.hinting {
+            FullHinting => Some(advance.round()),
+            _ => Some(advance),
+        }
     }
 
     fn get_metrics(&self) -> FontMetrics {
@@ -188,5 +212,9 @@ impl FontHandleMethods for FontHandle {
             Some(FontTable::wrap(data))
         })
     }
+
+    fn set_hinting(&mut self, hinting: HintingMode) {
+        self.hinting = hinting;
+    }
 }
 
@@ -6,6 +6,8 @@ use core_graphics::data_provider::CGDataProvider;
 use core_graphics::font::CGFont;
 use core_text::font::CTFont;
 use core_text;
+use font::{COLOR_FONT_TABLES, font_table_tag};
+use font_template::UnicodeRange;
 
 /// Platform specific font representation for mac.
 /// The identifier is a PostScript font name. The
@@ -14,6 +16,8 @@ use core_text;
 pub struct FontTemplateData {
     pub ctfont: Option<CTFont>,
     pub identifier: String,
+    /// The codepoints that the `@font-face` rule limits this font to, if any.
+    pub unicode_range: Option<Vec<UnicodeRange>>,
 }
 
 impl FontTemplateData {
@@ -35,6 +39,37 @@ impl FontTemplateData {
         FontTemplateData {
             ctfont: ctfont,
             identifier: identifier.to_string(),
+            unicode_range: None,
         }
     }
+
+    /// Returns true if the font has color glyphs.
+    pub fn is_color(&self) -> bool {
+        match self.ctfont {
+            Some(ref ctfont) => {
+                COLOR_FONT_TABLES.iter().any(|&tag| ctfont.get_font_table(tag).is_some())
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of bytes of font data that this template holds. Core Text owns the font
+    /// data, so this adds up the sizes of the tables that hold the glyphs and their metrics,
+    /// which make up nearly all of a font file.
+    pub fn byte_len(&self) -> uint {
+        static FONT_DATA_TABLES: [&'static str, ..10] =
+            ["cmap", "glyf", "loca", "CFF ", "hmtx", "vmtx", "kern", "GPOS", "GSUB", "name"];
+        let ctfont = match self.ctfont {
+            Some(ref ctfont) => ctfont,
+            None => return 0,
+        };
+        let tags = FONT_DATA_TABLES.iter().map(|&tag| font_table_tag(tag))
+                                   .chain(COLOR_FONT_TABLES.iter().map(|&tag| tag));
+        tags.fold(0, |byte_len, tag| {
+            match ctfont.get_font_table(tag) {
+                Some(table) => byte_len + table.len() as uint,
+                None => byte_len,
+            }
+        })
+    }
 }
@@ -3,7 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use display_list::{SidewaysLeft, SidewaysRight, TextDisplayItem, Upright};
-use font_context::FontContext;
+use font::{HintingMode, NoHinting};
+use font_context::{FontContext, SYNTHETIC_OBLIQUE_ANGLE};
 use style::computed_values::border_style;
 
 use azure::azure_hl::{B8G8R8A8, A8, Color, ColorPattern, ColorPatternRef, DrawOptions};
@@ -23,6 +24,7 @@ use servo_net::image::base::Image;
 use servo_util::geometry::Au;
 use servo_util::opts;
 use servo_util::range::Range;
+use std::f64::consts;
 use std::num::Zero;
 use std::ptr;
 use sync::Arc;
@@ -402,35 +404,42 @@ impl<'a> RenderContext<'a>  {
     pub fn draw_text(&mut self,
                      text: &TextDisplayItem,
                      current_transform: &Matrix2D<AzFloat>) {
-        // Optimization: Don’t set a transform matrix for upright text, and pass a start point to
-        // `draw_text_into_context`.
+        // Optimization: Don’t set a transform matrix for upright, non-oblique text, and pass a
+        // start point to `draw_text_into_context`.
         //
-        // For sideways text, it’s easier to do the rotation such that its center (the baseline’s
-        // start point) is at (0, 0) coordinates.
-        let baseline_origin = match text.orientation {
-            Upright => text.baseline_origin,
-            SidewaysLeft => {
-                let x = text.baseline_origin.x.to_subpx() as AzFloat;
-                let y = text.baseline_origin.y.to_subpx() as AzFloat;
-                self.draw_target.set_transform(&current_transform.mul(&Matrix2D::new(0., -1.,
-                                                                                     1., 0.,
-                                                                                     x, y)));
-                Zero::zero()
-            }
-            SidewaysRight => {
-                let x = text.baseline_origin.x.to_subpx() as AzFloat;
-                let y = text.baseline_origin.y.to_subpx() as AzFloat;
-                self.draw_target.set_transform(&current_transform.mul(&Matrix2D::new(0., 1.,
-                                                                                     -1., 0.,
-                                                                                     x, y)));
+        // For sideways or synthetic oblique text, it’s easier to do the rotation and the shear
+        // such that their center (the baseline’s start point) is at (0, 0) coordinates.
+        let x = text.baseline_origin.x.to_subpx() as AzFloat;
+        let y = text.baseline_origin.y.to_subpx() as AzFloat;
+        let orientation_transform = match text.orientation {
+            Upright => Matrix2D::new(1., 0., 0., 1., x, y),
+            SidewaysLeft => Matrix2D::new(0., -1., 1., 0., x, y),
+            SidewaysRight => Matrix2D::new(0., 1., -1., 0., x, y),
+        };
+        let text_transform = if text.text_run.synthetic_oblique {
+            let skew = (SYNTHETIC_OBLIQUE_ANGLE * consts::PI / 180.0).tan() as AzFloat;
+            let shear = Matrix2D::new(1., 0., -skew, 1., 0., 0.);
+            Some(shear.mul(&orientation_transform))
+        } else if text.orientation != Upright {
+            Some(orientation_transform)
+        } else {
+            None
+        };
+        let baseline_origin = match text_transform {
+            None => text.baseline_origin,
+            Some(ref text_transform) => {
+                self.draw_target.set_transform(&current_transform.mul(text_transform));
                 Zero::zero()
             }
         };
 
         self.font_ctx
             .get_render_font_from_template(&text.text_run.font_template,
-                                           text.text_run.actual_pt_size)
-            .borrow()
+                                           text.text_run.actual_pt_size,
+                                           &text.text_run.descriptor,
+                                           text.text_run.synthetic_bold,
+                                           text.text_run.synthetic_oblique,
+                                           &text.text_run.feature_settings)
             .draw_text_into_context(self,
                                     &*text.text_run,
                                     &text.range,
@@ -439,7 +448,7 @@ impl<'a> RenderContext<'a>  {
                                     opts::get().enable_text_antialiasing);
 
         // Undo the transform, only when we did one.
-        if text.orientation != Upright {
+        if text_transform.is_some() {
             self.draw_target.set_transform(current_transform)
         }
     }
@@ -481,6 +490,16 @@ trait ScaledFontExtensionMethods {
                               antialias: bool);
 }
 
+/// How far, as a fraction of the font size, the second strike of synthetic bold text is offset.
+static SYNTHETIC_BOLD_OFFSET_RATIO: AzFloat = 0.03;
+
+/// Returns whether glyphs are drawn at whole pixels horizontally and vertically. Hinted outlines
+/// are fitted vertically to whole-pixel baselines, so those are kept even when glyphs may
+/// otherwise sit at fractional positions.
+fn glyph_snapping(subpixel_positioning: bool, hinting: HintingMode) -> (bool, bool) {
+    (!subpixel_positioning, !subpixel_positioning || hinting != NoHinting)
+}
+
 impl ScaledFontExtensionMethods for ScaledFont {
     fn draw_text_into_context(&self,
                               rctx: &RenderContext,
@@ -505,6 +524,9 @@ impl ScaledFontExtensionMethods for ScaledFont {
             fields: fields,
         };
 
+        // Azure can't snap or hint glyphs itself, so their positions are rounded here.
+        let (snap_x, snap_y) = glyph_snapping(rctx.font_ctx.subpixel_positioning(),
+                                              rctx.font_ctx.hinting());
         let mut origin = baseline_origin.clone();
         let mut azglyphs = vec!();
         azglyphs.reserve(range.length().to_uint());
@@ -513,11 +535,15 @@ impl ScaledFontExtensionMethods for ScaledFont {
             for (_i, glyph) in glyphs.iter_glyphs_for_char_range(&slice_range) {
                 let glyph_advance = glyph.advance();
                 let glyph_offset = glyph.offset().unwrap_or(Zero::zero());
+                let position = origin + glyph_offset;
+                let x = if snap_x { Au::from_px(position.x.to_nearest_px()) } else { position.x };
+                let y = if snap_y { Au::from_px(position.y.to_nearest_px()) } else { position.y };
+                let position = Point2D(x, y);
                 let azglyph = struct__AzGlyph {
                     mIndex: glyph.id() as uint32_t,
                     mPosition: struct__AzPoint {
-                        x: (origin.x + glyph_offset.x).to_subpx() as AzFloat,
-                        y: (origin.y + glyph_offset.y).to_subpx() as AzFloat
+                        x: position.x.to_subpx() as AzFloat,
+                        y: position.y.to_subpx() as AzFloat
                     }
                 };
                 origin = Point2D(origin.x + glyph_advance, origin.y);
@@ -528,19 +554,30 @@ impl ScaledFontExtensionMethods for ScaledFont {
         let azglyph_buf_len = azglyphs.len();
         if azglyph_buf_len == 0 { return; } // Otherwise the Quartz backend will assert.
 
-        let mut glyphbuf = struct__AzGlyphBuffer {
-            mGlyphs: azglyphs.as_mut_ptr(),
-            mNumGlyphs: azglyph_buf_len as uint32_t
-        };
+        // Fake bold by drawing the glyphs a second time, slightly to the right.
+        let strike_count = if run.synthetic_bold { 2u } else { 1u };
+        let bold_offset = run.actual_pt_size.to_subpx() as AzFloat * SYNTHETIC_BOLD_OFFSET_RATIO;
+        for strike in range(0, strike_count) {
+            if strike > 0 {
+                for azglyph in azglyphs.iter_mut() {
+                    azglyph.mPosition.x += bold_offset;
+                }
+            }
 
-        unsafe {
-            // TODO(Issue #64): this call needs to move into azure_hl.rs
-            AzDrawTargetFillGlyphs(target.azure_draw_target,
-                                   self.get_ref(),
-                                   &mut glyphbuf,
-                                   azure_pattern,
-                                   &mut options,
-                                   ptr::null_mut());
+            let mut glyphbuf = struct__AzGlyphBuffer {
+                mGlyphs: azglyphs.as_mut_ptr(),
+                mNumGlyphs: azglyph_buf_len as uint32_t
+            };
+
+            unsafe {
+                // TODO(Issue #64): this call needs to move into azure_hl.rs
+                AzDrawTargetFillGlyphs(target.azure_draw_target,
+                                       self.get_ref(),
+                                       &mut glyphbuf,
+                                       azure_pattern,
+                                       &mut options,
+                                       ptr::null_mut());
+            }
         }
     }
 }
@@ -8,7 +8,7 @@ use buffer_map::BufferMap;
 use display_list::optimizer::DisplayListOptimizer;
 use display_list::DisplayList;
 use font_cache_task::FontCacheTask;
-use font_context::FontContext;
+use font_context::{FontContext, FontContextOptions};
 use render_context::RenderContext;
 
 use azure::azure_hl::{B8G8R8A8, Color, DrawTarget, SkiaBackend, StolenGLResources};
@@ -35,6 +35,7 @@ use servo_util::task_state;
 use servo_util::time::{TimeProfilerChan, profile};
 use servo_util::time;
 use std::comm::{Receiver, Sender, channel};
+use std::default::Default;
 use std::mem;
 use std::task::TaskBuilder;
 use sync::Arc;
@@ -434,7 +435,8 @@ impl WorkerThread {
             native_graphics_context: native_graphics_metadata.map(|metadata| {
                 NativePaintingGraphicsContext::from_metadata(&metadata)
             }),
-            font_context: box FontContext::new(font_cache_task.clone()),
+            font_context: box FontContext::new(font_cache_task.clone(),
+                                               FontContextOptions::default()),
             time_profiler_sender: time_profiler_sender,
         }
     }
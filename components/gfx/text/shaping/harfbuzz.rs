@@ -4,14 +4,15 @@
 
 extern crate harfbuzz;
 
-use font::{Font, FontHandleMethods, FontTableMethods, FontTableTag};
+use font::{Font, FontHandleMethods, FontTableMethods, FontTableTag, CLIG, KERN, LIGA, SMCP};
+use font::font_table_tag;
 use platform::font::FontTable;
 use text::glyph::{CharIndex, GlyphStore, GlyphId, GlyphData};
 use text::shaping::ShaperMethods;
 use text::util::{float_to_fixed, fixed_to_float};
 
 use geom::Point2D;
-use harfbuzz::{HB_MEMORY_MODE_READONLY, HB_DIRECTION_LTR};
+use harfbuzz::{HB_MEMORY_MODE_DUPLICATE, HB_DIRECTION_LTR};
 use harfbuzz::{hb_blob_create, hb_face_create_for_tables};
 use harfbuzz::{hb_blob_t};
 use harfbuzz::{hb_bool_t};
@@ -20,7 +21,7 @@ use harfbuzz::{hb_buffer_destroy};
 use harfbuzz::{hb_buffer_get_glyph_positions};
 use harfbuzz::{hb_buffer_set_direction};
 use harfbuzz::{hb_face_destroy};
-use harfbuzz::{hb_face_t, hb_font_t};
+use harfbuzz::{hb_face_t, hb_feature_t, hb_font_t};
 use harfbuzz::{hb_font_create};
 use harfbuzz::{hb_font_destroy, hb_buffer_create};
 use harfbuzz::{hb_font_funcs_create};
@@ -43,6 +44,8 @@ use std::mem;
 use std::char;
 use std::cmp;
 use std::ptr;
+use style::computed_values::font_feature_settings::FeatureTagValue;
+use style::computed_values::text_rendering;
 
 static NO_GLYPH: i32 = -1;
 static CONTINUATION_BYTE: i32 = -2;
@@ -135,6 +138,8 @@ pub struct Shaper {
     hb_face: *mut hb_face_t,
     hb_font: *mut hb_font_t,
     hb_funcs: *mut hb_font_funcs_t,
+    /// The OpenType features to enable for every run shaped with this font.
+    features: Vec<hb_feature_t>,
 }
 
 #[unsafe_destructor]
@@ -180,14 +185,67 @@ impl Shaper {
             hb_font_funcs_set_glyph_h_kerning_func(hb_funcs, glyph_h_kerning_func, ptr::null_mut(), ptr::null_mut());
             hb_font_set_funcs(hb_font, hb_funcs, font_ptr as *mut c_void, None);
 
+            let features = Shaper::features(font.true_small_caps,
+                                            font.ligatures,
+                                            font.text_rendering,
+                                            font.feature_settings.as_slice());
+
             Shaper {
                 hb_face: hb_face,
                 hb_font: hb_font,
                 hb_funcs: hb_funcs,
+                features: features,
             }
         }
     }
 
+    /// Returns the features to shape with. `text-rendering: optimizeSpeed` turns kerning and
+    /// ligatures off, while `optimizeLegibility` asks for them explicitly. `font-feature-settings`
+    /// come last, so that they override the features implied by other properties.
+    fn features(true_small_caps: bool,
+                ligatures: bool,
+                text_rendering: text_rendering::T,
+                feature_settings: &[FeatureTagValue])
+                -> Vec<hb_feature_t> {
+        let mut features = vec!();
+        if true_small_caps {
+            features.push(Shaper::feature(SMCP, 1));
+        }
+        match text_rendering {
+            text_rendering::optimizespeed => {
+                features.push(Shaper::feature(KERN, 0));
+                features.push(Shaper::feature(LIGA, 0));
+                features.push(Shaper::feature(CLIG, 0));
+            }
+            text_rendering::optimizelegibility => {
+                features.push(Shaper::feature(KERN, 1));
+                if ligatures {
+                    features.push(Shaper::feature(LIGA, 1));
+                    features.push(Shaper::feature(CLIG, 1));
+                }
+            }
+            text_rendering::auto | text_rendering::geometricprecision => {}
+        }
+        if !ligatures && text_rendering != text_rendering::optimizespeed {
+            features.push(Shaper::feature(LIGA, 0));
+            features.push(Shaper::feature(CLIG, 0));
+        }
+        for setting in feature_settings.iter() {
+            features.push(Shaper::feature(font_table_tag(setting.tag.as_slice()), setting.value));
+        }
+        features
+    }
+
+    /// Returns a feature setting that applies to the whole buffer.
+    fn feature(tag: FontTableTag, value: u32) -> hb_feature_t {
+        hb_feature_t {
+            tag: tag as hb_tag_t,
+            value: value,
+            start: 0,
+            end: -1 as c_uint,
+        }
+    }
+
     fn float_to_fixed(f: f64) -> i32 {
         float_to_fixed(16, f)
     }
@@ -211,7 +269,10 @@ impl ShaperMethods for Shaper {
                                0,
                                text.len() as c_int);
 
-            hb_shape(self.hb_font, hb_buffer, ptr::null_mut(), 0);
+            hb_shape(self.hb_font,
+                     hb_buffer,
+                     self.features.as_ptr() as *mut hb_feature_t,
+                     self.features.len() as c_uint);
             self.save_glyph_results(text, glyphs, hb_buffer);
             hb_buffer_destroy(hb_buffer);
         }
@@ -517,10 +578,11 @@ extern fn get_font_table_func(_: *mut hb_face_t, tag: hb_tag_t, user_data: *mut
 
                 let mut blob: *mut hb_blob_t = ptr::null_mut();
                 (*skinny_font_table_ptr).with_buffer(|buf: *const u8, len: uint| {
+                    // The table is dropped on return, so HarfBuzz has to copy its data.
                     // HarfBuzz calls `destroy_blob_func` when the buffer is no longer needed.
                     blob = hb_blob_create(buf as *const c_char,
                                           len as c_uint,
-                                          HB_MEMORY_MODE_READONLY,
+                                          HB_MEMORY_MODE_DUPLICATE,
                                           mem::transmute(skinny_font_table_ptr),
                                           destroy_blob_func);
                 });
@@ -539,3 +601,56 @@ extern fn get_font_table_func(_: *mut hb_face_t, tag: hb_tag_t, user_data: *mut
 extern fn destroy_blob_func(_: *mut c_void) {
     // TODO: Previous code here was broken. Rewrite.
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Shaper;
+
+    use font::{CLIG, FontTableTag, KERN, LIGA, SMCP};
+    use style::computed_values::font_feature_settings::FeatureTagValue;
+    use style::computed_values::text_rendering;
+
+    fn tags_and_values(true_small_caps: bool, ligatures: bool, settings: &[FeatureTagValue])
+                       -> Vec<(FontTableTag, u32)> {
+        tags_and_values_for(true_small_caps, ligatures, text_rendering::auto, settings)
+    }
+
+    fn tags_and_values_for(true_small_caps: bool,
+                           ligatures: bool,
+                           rendering: text_rendering::T,
+                           settings: &[FeatureTagValue])
+                           -> Vec<(FontTableTag, u32)> {
+        Shaper::features(true_small_caps, ligatures, rendering, settings).iter().map(|feature| {
+            (feature.tag as FontTableTag, feature.value)
+        }).collect()
+    }
+
+    #[test]
+    fn test_disabling_ligatures_turns_off_liga() {
+        assert_eq!(tags_and_values(false, true, []), vec!());
+        assert_eq!(tags_and_values(false, false, []), vec!((LIGA, 0), (CLIG, 0)));
+        assert_eq!(tags_and_values(true, false, []), vec!((SMCP, 1), (LIGA, 0), (CLIG, 0)));
+    }
+
+    #[test]
+    fn test_feature_settings_come_last() {
+        let settings = vec!(FeatureTagValue { tag: "liga".to_string(), value: 1 });
+        assert_eq!(tags_and_values(false, false, settings.as_slice()),
+                   vec!((LIGA, 0), (CLIG, 0), (LIGA, 1)));
+    }
+
+    #[test]
+    fn test_text_rendering_modes_configure_shaper_differently() {
+        let speed = tags_and_values_for(false, true, text_rendering::optimizespeed, []);
+        let legibility = tags_and_values_for(false, true, text_rendering::optimizelegibility, []);
+        let auto = tags_and_values_for(false, true, text_rendering::auto, []);
+        assert_eq!(speed, vec!((KERN, 0), (LIGA, 0), (CLIG, 0)));
+        assert_eq!(legibility, vec!((KERN, 1), (LIGA, 1), (CLIG, 1)));
+        assert_eq!(auto, vec!());
+
+        // Ligatures that are already off are not turned off twice.
+        assert_eq!(tags_and_values_for(false, false, text_rendering::optimizespeed, []), speed);
+        assert_eq!(tags_and_values_for(false, false, text_rendering::optimizelegibility, []),
+                   vec!((KERN, 1), (LIGA, 0), (CLIG, 0)));
+    }
+}
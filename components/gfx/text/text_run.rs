@@ -7,9 +7,11 @@ use servo_util::geometry::Au;
 use servo_util::range::Range;
 use servo_util::vec::{Comparator, FullBinarySearchMethods};
 use std::slice::Items;
+use style::computed_values::font_feature_settings;
 use sync::Arc;
 use text::glyph::{CharIndex, GlyphStore};
 use font::FontHandleMethods;
+use font_template::FontTemplateDescriptor;
 use platform::font_template::FontTemplateData;
 
 /// A single "paragraph" of text in one font size and style.
@@ -18,6 +20,14 @@ pub struct TextRun {
     pub text: Arc<String>,
     pub font_template: Arc<FontTemplateData>,
     pub actual_pt_size: Au,
+    /// The style that the font was picked for.
+    pub descriptor: FontTemplateDescriptor,
+    /// Whether the glyphs must be emboldened when drawn.
+    pub synthetic_bold: bool,
+    /// Whether the glyphs must be slanted when drawn.
+    pub synthetic_oblique: bool,
+    /// The OpenType features that the glyphs were shaped with.
+    pub feature_settings: font_feature_settings::T,
     pub font_metrics: FontMetrics,
     /// The glyph runs that make up this text run.
     pub glyphs: Arc<Vec<GlyphRun>>,
@@ -124,6 +134,10 @@ impl<'a> TextRun {
             font_metrics: font.metrics.clone(),
             font_template: font.handle.get_template(),
             actual_pt_size: font.actual_pt_size,
+            descriptor: font.descriptor.clone(),
+            synthetic_bold: font.synthetic_bold,
+            synthetic_oblique: font.synthetic_oblique,
+            feature_settings: font.feature_settings.clone(),
             glyphs: Arc::new(glyphs),
         };
         return run;
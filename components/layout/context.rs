@@ -8,7 +8,7 @@ use css::matching::{ApplicableDeclarationsCache, StyleSharingCandidateCache};
 
 use geom::{Rect, Size2D};
 use gfx::display_list::OpaqueNode;
-use gfx::font_context::FontContext;
+use gfx::font_context::{FontContext, FontContextOptions};
 use gfx::font_cache_task::FontCacheTask;
 use script::layout_interface::LayoutChan;
 use script_traits::UntrustedNodeAddress;
@@ -16,6 +16,7 @@ use servo_msg::constellation_msg::ConstellationChan;
 use servo_net::local_image_cache::LocalImageCache;
 use servo_util::geometry::Au;
 use sync::{Arc, Mutex};
+use std::default::Default;
 use std::mem;
 use style::Stylist;
 use url::Url;
@@ -34,7 +35,8 @@ fn create_or_get_local_context(shared_layout_context: &SharedLayoutContext) -> *
     let context = match maybe_context {
         None => {
             let context = box LocalLayoutContext {
-                font_context: FontContext::new(shared_layout_context.font_cache_task.clone()),
+                font_context: FontContext::new(shared_layout_context.font_cache_task.clone(),
+                                               FontContextOptions::default()),
                 applicable_declarations_cache: ApplicableDeclarationsCache::new(),
                 style_sharing_candidate_cache: StyleSharingCandidateCache::new(),
             };
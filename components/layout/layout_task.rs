@@ -10,7 +10,7 @@ use construct::FlowConstructionResult;
 use context::SharedLayoutContext;
 use flow::{mod, Flow, ImmutableFlowUtils, MutableFlowUtils, MutableOwnedFlowUtils};
 use flow_ref::FlowRef;
-use fragment::{Fragment, FragmentBoundsIterator};
+use fragment::{Fragment, FragmentBoundsIterator, ScannedTextFragment};
 use incremental::{LayoutDamageComputation, Reflow, ReflowEntireDocument, Repaint};
 use layout_debug;
 use parallel::UnsafeFlow;
@@ -20,6 +20,7 @@ use util::{LayoutDataAccess, LayoutDataWrapper, OpaqueNodeMethods, ToGfxColor};
 use wrapper::{LayoutNode, TLayoutNode, ThreadSafeLayoutNode};
 
 use collections::dlist::DList;
+use collections::{Deque, RingBuf};
 use encoding::EncodingRef;
 use encoding::all::UTF_8;
 use geom::point::Point2D;
@@ -35,36 +36,54 @@ use log;
 use script::dom::bindings::js::JS;
 use script::dom::node::{ElementNodeTypeId, LayoutDataRef, Node};
 use script::dom::element::{HTMLBodyElementTypeId, HTMLHtmlElementTypeId};
-use script::layout_interface::{
-    AddStylesheetMsg, ContentBoxResponse, ContentBoxesResponse, ContentBoxesQuery,
-    ContentBoxQuery, ExitNowMsg, GetRPCMsg, HitTestResponse, LayoutChan, LayoutRPC,
-    LoadStylesheetMsg, MouseOverResponse, Msg, NoQuery, PrepareToExitMsg, ReapLayoutDataMsg,
-    Reflow, ReflowForDisplay, ReflowMsg, ScriptLayoutChan, TrustedNodeAddress,
-};
+use script::layout_interface::{AddStylesheetMsg, BatchQuery, BatchQueryResponse, CancelReflowMsg};
+use script::layout_interface::{ClientRectQuery, ClientRectQueryResponse, ClientRectResponse};
+use script::layout_interface::{ContentBoxQuery, ContentBoxQueryResponse, ContentBoxResponse};
+use script::layout_interface::{ContentBoxesQuery, ContentBoxesQueryResponse, ContentBoxesResponse};
+use script::layout_interface::{ExitNowMsg, GetRPCMsg, HitTestAllResponse, HitTestResponse};
+use script::layout_interface::{LayoutChan, LayoutRPC, LoadStylesheetMsg, MouseOverResponse, Msg};
+use script::layout_interface::{NestedBatchQueryResponse, NoQuery, NoQueryResponse};
+use script::layout_interface::{OffsetParentQuery, OffsetParentQueryResponse, OffsetParentResponse};
+use script::layout_interface::{PrepareToExitMsg, PropertyId, QueryResponse, ReapLayoutDataMsg};
+use script::layout_interface::{Reflow, ReflowForDisplay, ReflowForImageUpdate, ReflowMsg};
+use script::layout_interface::{ReflowQueryType, ResolvedStyleQuery, ResolvedStyleQueryResponse};
+use script::layout_interface::{ResolvedStyleResponse, ScriptLayoutChan, ScrollContainerQuery};
+use script::layout_interface::{ScrollContainerQueryResponse, ScrollContainerResponse};
+use script::layout_interface::{ScrollDimensionsQuery, ScrollDimensionsQueryResponse};
+use script::layout_interface::{ScrollDimensionsResponse, TextRangeBoxQuery};
+use script::layout_interface::{TextRangeBoxQueryResponse, TextRangeBoxResponse, TrustedNodeAddress};
 use script_traits::{SendEventMsg, ReflowEvent, ReflowCompleteMsg, OpaqueScriptLayoutChannel};
 use script_traits::{ScriptControlChan, UntrustedNodeAddress};
 use servo_msg::compositor_msg::Scrollable;
 use servo_msg::constellation_msg::{ConstellationChan, PipelineId, Failure, FailureMsg};
 use servo_net::image_cache_task::{ImageCacheTask, ImageResponseMsg};
 use gfx::font_cache_task::{FontCacheTask};
+use gfx::font_template::UnicodeRange;
+use gfx::text::glyph::CharIndex;
 use servo_net::local_image_cache::{ImageResponder, LocalImageCache};
 use servo_net::resource_task::{ResourceTask, load_bytes_iter};
 use servo_util::geometry::Au;
 use servo_util::geometry;
 use servo_util::logical_geometry::LogicalPoint;
 use servo_util::opts;
+use servo_util::range::Range;
 use servo_util::smallvec::{SmallVec, SmallVec1, VecLike};
 use servo_util::task::spawn_named_with_send_on_failure;
 use servo_util::task_state;
 use servo_util::time::{TimeProfilerChan, profile};
 use servo_util::time;
 use servo_util::workqueue::WorkQueue;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::cmp::{max, min};
+use std::collections::HashSet;
 use std::comm::{channel, Sender, Receiver, Select};
 use std::mem;
 use std::ptr;
+use std::time::duration::Duration;
+use std_time::precise_time_ns;
 use style::{AuthorOrigin, Stylesheet, Stylist, TNode, iter_font_face_rules};
 use style::{Device, Screen};
+use style::computed_values::overflow;
 use sync::{Arc, Mutex, MutexGuard};
 use url::Url;
 
@@ -100,8 +119,29 @@ pub struct LayoutTaskData {
     /// A queued response for the union of the content boxes of a node.
     pub content_box_response: Rect<Au>,
 
-    /// A queued response for the content boxes of a node.
-    pub content_boxes_response: Vec<Rect<Au>>,
+    /// A queued response for the content boxes of a node, with the indices of their fragments.
+    pub content_boxes_response: Vec<(uint, Rect<Au>)>,
+
+    /// A queued response for the border box of a node, in document coordinates.
+    pub client_rect_response: Rect<Au>,
+
+    /// A queued response for the resolved value of a CSS property, if the node has a fragment.
+    pub resolved_style_response: Option<String>,
+
+    /// A queued response for the size of a node's scrolling box.
+    pub scroll_dimensions_response: Size2D<Au>,
+
+    /// A queued response for the offset parent of a node.
+    pub offset_parent_response: OffsetParentResponse,
+
+    /// A queued response for the union of the glyph boxes of a character range of a text node.
+    pub text_range_box_response: Rect<Au>,
+
+    /// A queued response for the nearest scroll container of a node.
+    pub scroll_container_response: Option<UntrustedNodeAddress>,
+
+    /// Queued responses for the queries of a batch, in order.
+    pub batch_query_responses: Vec<QueryResponse>,
 }
 
 /// Information needed by the layout task.
@@ -151,6 +191,10 @@ pub struct LayoutTask {
     /// The media queries device state.
     /// TODO: Handle updating this when window size changes etc.
     pub device: Device,
+
+    /// Script messages that were pulled off the port while checking whether a reflow had been
+    /// cancelled, and still have to be handled.
+    pub deferred_messages: RefCell<RingBuf<Msg>>,
 }
 
 struct LayoutImageResponder {
@@ -281,6 +325,7 @@ impl LayoutTask {
             font_cache_task: font_cache_task,
             first_reflow: Cell::new(true),
             device: device,
+            deferred_messages: RefCell::new(RingBuf::new()),
             rw_data: Arc::new(Mutex::new(
                 LayoutTaskData {
                     local_image_cache: local_image_cache,
@@ -293,6 +338,13 @@ impl LayoutTask {
                     stylesheet_dirty: false,
                     content_box_response: Rect::zero(),
                     content_boxes_response: Vec::new(),
+                    client_rect_response: Rect::zero(),
+                    resolved_style_response: None,
+                    scroll_dimensions_response: Size2D::zero(),
+                    offset_parent_response: OffsetParentResponse::empty(),
+                    text_range_box_response: Rect::zero(),
+                    scroll_container_response: None,
+                    batch_query_responses: vec!(),
               })),
         }
     }
@@ -334,6 +386,12 @@ impl LayoutTask {
             Script,
         }
 
+        let deferred_message = self.deferred_messages.borrow_mut().pop_front();
+        match deferred_message {
+            Some(msg) => return self.handle_script_request(msg, possibly_locked_rw_data),
+            None => {}
+        }
+
         let port_to_read = {
             let sel = Select::new();
             let mut port1 = sel.handle(&self.port);
@@ -407,10 +465,19 @@ impl LayoutTask {
                                    Box<LayoutRPC + Send>);
             },
             ReflowMsg(data) => {
-                profile(time::LayoutPerformCategory,
-                        Some((&data.url, data.iframe, self.first_reflow.get())),
-                        self.time_profiler_chan.clone(),
-                        || self.handle_reflow(&*data, possibly_locked_rw_data));
+                if self.reflow_was_cancelled(data.id) {
+                    debug!("layout: reflow {:u} was cancelled", data.id);
+                    self.notify_reflow_complete(&*data, Duration::zero());
+                } else {
+                    profile(time::LayoutPerformCategory,
+                            Some((&data.url, data.iframe, self.first_reflow.get())),
+                            self.time_profiler_chan.clone(),
+                            || self.handle_reflow(&*data, possibly_locked_rw_data));
+                }
+            },
+            CancelReflowMsg(reflow_id) => {
+                // The reflow has either already been performed or was never sent.
+                debug!("layout: ignoring stale cancellation of reflow {:u}", reflow_id);
             },
             ReapLayoutDataMsg(dead_layout_data) => {
                 unsafe {
@@ -432,6 +499,43 @@ impl LayoutTask {
         true
     }
 
+    /// Checks the messages queued behind a reflow for a cancellation of it. The port is drained
+    /// into the deferred messages first, since an earlier check may already have deferred the
+    /// cancellation; the cancellation is then removed and everything else is kept, in order, to
+    /// be handled afterwards.
+    fn reflow_was_cancelled(&self, reflow_id: uint) -> bool {
+        let mut deferred_messages = self.deferred_messages.borrow_mut();
+        loop {
+            match self.port.try_recv() {
+                Ok(msg) => deferred_messages.push_back(msg),
+                Err(_) => break,
+            }
+        }
+
+        let mut cancelled = false;
+        for _ in range(0, deferred_messages.len()) {
+            match deferred_messages.pop_front().unwrap() {
+                CancelReflowMsg(id) if id == reflow_id => cancelled = true,
+                msg => deferred_messages.push_back(msg),
+            }
+        }
+        cancelled
+    }
+
+    /// Tells script that the given reflow is done (or has been cancelled), and how long it took.
+    ///
+    /// FIXME(pcwalton): This should probably be *one* channel, but we can't fix this without
+    /// either select or a filtered recv() that only looks for messages of a given type.
+    fn notify_reflow_complete(&self, data: &Reflow, duration: Duration) {
+        data.script_join_chan.send(duration);
+        for completion_chan in data.completion_chans.iter() {
+            // Whoever asked to be notified may have stopped listening in the meantime.
+            let _ = completion_chan.send_opt(data.id);
+        }
+        let ScriptControlChan(ref chan) = data.script_chan;
+        chan.send(ReflowCompleteMsg(self.id, data.id));
+    }
+
     /// Enters a quiescent state in which no new messages except for `ReapLayoutDataMsg` will be
     /// processed until an `ExitNowMsg` is received. A pong is immediately sent on the given
     /// response channel.
@@ -501,8 +605,11 @@ impl LayoutTask {
                                     &mut Option<MutexGuard<'a, LayoutTaskData>>) {
         // Find all font-face rules and notify the font cache of them.
         // GWTODO: Need to handle unloading web fonts (when we handle unloading stylesheets!)
-        iter_font_face_rules(&sheet, &self.device, |family, src| {
-            self.font_cache_task.add_web_font(family.to_string(), (*src).clone());
+        iter_font_face_rules(&sheet, &self.device, |family, src, unicode_range| {
+            let unicode_range = unicode_range.as_ref().map(|ranges| {
+                ranges.iter().map(|&(first, last)| UnicodeRange::new(first, last)).collect()
+            });
+            self.font_cache_task.add_web_font(family.to_string(), (*src).clone(), unicode_range);
         });
         let mut rw_data = self.lock_rw_data(possibly_locked_rw_data);
         rw_data.stylist.add_stylesheet(sheet, AuthorOrigin, &self.device);
@@ -596,6 +703,62 @@ impl LayoutTask {
     fn verify_flow_tree(&self, _: &mut FlowRef) {
     }
 
+    /// Answers a single query, leaving the response in the corresponding field of `rw_data`.
+    fn process_query<'a>(&'a self,
+                         query: &ReflowQueryType,
+                         layout_root: &mut FlowRef,
+                         rw_data: &mut RWGuard<'a>) {
+        match *query {
+            ContentBoxQuery(node) =>
+                self.process_content_box_request(node, layout_root, rw_data),
+            ContentBoxesQuery(node) =>
+                self.process_content_boxes_request(node, layout_root, rw_data),
+            ClientRectQuery(node) =>
+                self.process_client_rect_request(node, layout_root, rw_data),
+            ResolvedStyleQuery(node, ref property) =>
+                self.process_resolved_style_request(node, property, layout_root, rw_data),
+            ScrollDimensionsQuery(node) =>
+                self.process_scroll_dimensions_request(node, layout_root, rw_data),
+            OffsetParentQuery(node) =>
+                self.process_offset_parent_request(node, layout_root, rw_data),
+            TextRangeBoxQuery(node, start, end) =>
+                self.process_text_range_box_request(node, start, end, layout_root, rw_data),
+            ScrollContainerQuery(node) =>
+                self.process_scroll_container_request(node, layout_root, rw_data),
+            BatchQuery(_) => error!("layout: not answering a batch query within a batch"),
+            NoQuery => {},
+        }
+    }
+
+    /// Answers each query of a batch in turn, collecting the responses in order.
+    fn process_batch_query_request<'a>(&'a self,
+                                       queries: &[ReflowQueryType],
+                                       layout_root: &mut FlowRef,
+                                       rw_data: &mut RWGuard<'a>) {
+        let mut responses = Vec::with_capacity(queries.len());
+        for query in queries.iter() {
+            self.process_query(query, layout_root, rw_data);
+            responses.push(match *query {
+                NoQuery => NoQueryResponse,
+                ContentBoxQuery(_) => ContentBoxQueryResponse(rw_data.content_box_response),
+                ContentBoxesQuery(_) =>
+                    ContentBoxesQueryResponse(rw_data.content_boxes_response.clone()),
+                ClientRectQuery(_) => ClientRectQueryResponse(rw_data.client_rect_response),
+                ResolvedStyleQuery(..) =>
+                    ResolvedStyleQueryResponse(rw_data.resolved_style_response.clone()),
+                ScrollDimensionsQuery(_) =>
+                    ScrollDimensionsQueryResponse(rw_data.scroll_dimensions_response),
+                OffsetParentQuery(_) =>
+                    OffsetParentQueryResponse(rw_data.offset_parent_response.clone()),
+                TextRangeBoxQuery(..) => TextRangeBoxQueryResponse(rw_data.text_range_box_response),
+                ScrollContainerQuery(_) =>
+                    ScrollContainerQueryResponse(rw_data.scroll_container_response),
+                BatchQuery(_) => NestedBatchQueryResponse,
+            });
+        }
+        rw_data.batch_query_responses = responses;
+    }
+
     fn process_content_box_request<'a>(&'a self,
                                        requested_node: TrustedNodeAddress,
                                        layout_root: &mut FlowRef,
@@ -616,6 +779,174 @@ impl LayoutTask {
         rw_data.content_boxes_response = iterator.rects;
     }
 
+    fn process_client_rect_request<'a>(&'a self,
+                                       requested_node: TrustedNodeAddress,
+                                       layout_root: &mut FlowRef,
+                                       rw_data: &mut RWGuard<'a>) {
+        let requested_node: OpaqueNode = OpaqueNodeMethods::from_script_node(requested_node);
+        let mut iterator = UnioningFragmentBoundsIterator::new(requested_node);
+        sequential::iterate_through_flow_tree_fragment_bounds(layout_root, &mut iterator);
+        rw_data.client_rect_response = iterator.rect;
+    }
+
+    fn process_resolved_style_request<'a>(&'a self,
+                                          requested_node: TrustedNodeAddress,
+                                          property: &PropertyId,
+                                          layout_root: &mut FlowRef,
+                                          rw_data: &mut RWGuard<'a>) {
+        let requested_node: OpaqueNode = OpaqueNodeMethods::from_script_node(requested_node);
+        let mut iterator = ResolvedStyleFragmentBoundsIterator::new(requested_node,
+                                                                    (*property).clone());
+        sequential::iterate_through_flow_tree_fragment_bounds(layout_root, &mut iterator);
+        rw_data.resolved_style_response = iterator.result;
+    }
+
+    fn process_scroll_dimensions_request<'a>(&'a self,
+                                             requested_node: TrustedNodeAddress,
+                                             layout_root: &mut FlowRef,
+                                             rw_data: &mut RWGuard<'a>) {
+        let requested_node: OpaqueNode = OpaqueNodeMethods::from_script_node(requested_node);
+        rw_data.scroll_dimensions_response =
+            LayoutTask::find_scroll_dimensions(layout_root.deref_mut(), requested_node)
+                .unwrap_or(Size2D::zero());
+    }
+
+    /// Returns the size of the scrolling box of the block flow belonging to `node`: the larger of
+    /// its border box and its overflow area.
+    fn find_scroll_dimensions(flow: &mut Flow, node: OpaqueNode) -> Option<Size2D<Au>> {
+        if flow.is_block_like() {
+            let block = flow.as_block();
+            if block.fragment.node == node {
+                let writing_mode = block.base.writing_mode;
+                let border_box = block.fragment.border_box.size.to_physical(writing_mode);
+                let overflow = block.base.overflow.size.to_physical(writing_mode);
+                return Some(Size2D(max(border_box.width, overflow.width),
+                                   max(border_box.height, overflow.height)))
+            }
+        }
+
+        for kid in flow::child_iter(flow) {
+            match LayoutTask::find_scroll_dimensions(kid, node) {
+                found @ Some(_) => return found,
+                None => {}
+            }
+        }
+        None
+    }
+
+    fn process_offset_parent_request<'a>(&'a self,
+                                         requested_node: TrustedNodeAddress,
+                                         layout_root: &mut FlowRef,
+                                         rw_data: &mut RWGuard<'a>) {
+        let requested_node: OpaqueNode = OpaqueNodeMethods::from_script_node(requested_node);
+        rw_data.offset_parent_response =
+            LayoutTask::find_offset_parent(layout_root.deref_mut(), requested_node, None)
+                .unwrap_or(OffsetParentResponse::empty());
+    }
+
+    /// Searches for the block flow belonging to `node`, keeping track of the nearest positioned
+    /// ancestor on the way down. Fixed-position flows and flows without a positioned ancestor
+    /// have no offset parent.
+    fn find_offset_parent(flow: &mut Flow,
+                          node: OpaqueNode,
+                          positioned_ancestor: Option<(OpaqueNode, Point2D<Au>)>)
+                          -> Option<OffsetParentResponse> {
+        let mut positioned_ancestor = positioned_ancestor;
+        if flow.is_block_like() {
+            let is_positioned = flow.is_positioned();
+            let is_fixed = flow.is_fixed();
+            let block = flow.as_block();
+            let fragment_origin = block.base.child_fragment_absolute_position(&block.fragment);
+            let bounds = block.fragment.abs_bounds_from_origin(&fragment_origin);
+            if block.fragment.node == node {
+                let (node_address, parent_origin) = match positioned_ancestor {
+                    Some((parent, parent_origin)) if !is_fixed => {
+                        (Some(parent.to_untrusted_node_address()), parent_origin)
+                    }
+                    _ => (None, Point2D(Au(0), Au(0))),
+                };
+                return Some(OffsetParentResponse {
+                    node_address: node_address,
+                    rect: Rect(Point2D(bounds.origin.x - parent_origin.x,
+                                       bounds.origin.y - parent_origin.y),
+                               bounds.size),
+                })
+            }
+            if is_positioned {
+                positioned_ancestor = Some((block.fragment.node, bounds.origin));
+            }
+        }
+
+        for kid in flow::child_iter(flow) {
+            match LayoutTask::find_offset_parent(kid, node, positioned_ancestor) {
+                found @ Some(_) => return found,
+                None => {}
+            }
+        }
+        None
+    }
+
+    fn process_scroll_container_request<'a>(&'a self,
+                                            requested_node: TrustedNodeAddress,
+                                            layout_root: &mut FlowRef,
+                                            rw_data: &mut RWGuard<'a>) {
+        let requested_node: OpaqueNode = OpaqueNodeMethods::from_script_node(requested_node);
+        rw_data.scroll_container_response =
+            LayoutTask::find_scroll_container(layout_root.deref_mut(), requested_node, None)
+                .unwrap_or(None);
+    }
+
+    /// Searches for the block flow belonging to `node`, keeping track of the nearest ancestor
+    /// on the way down that scrolls its content: one with `overflow: auto` or `overflow: scroll`
+    /// whose content overflows its border box. Returns `None` if `node` has no block flow.
+    fn find_scroll_container(flow: &mut Flow,
+                             node: OpaqueNode,
+                             scroll_container: Option<OpaqueNode>)
+                             -> Option<Option<UntrustedNodeAddress>> {
+        let mut scroll_container = scroll_container;
+        if flow.is_block_like() {
+            let block = flow.as_block();
+            if block.fragment.node == node {
+                return Some(scroll_container.map(|container| {
+                    container.to_untrusted_node_address()
+                }))
+            }
+
+            let scrolls = match block.fragment.style.get_box().overflow {
+                overflow::auto | overflow::scroll => true,
+                overflow::visible | overflow::hidden => false,
+            };
+            let writing_mode = block.base.writing_mode;
+            let border_box = block.fragment.border_box.size.to_physical(writing_mode);
+            let overflow = block.base.overflow.size.to_physical(writing_mode);
+            let overflows = overflow.width > border_box.width ||
+                overflow.height > border_box.height;
+            if scrolls && overflows {
+                scroll_container = Some(block.fragment.node);
+            }
+        }
+
+        for kid in flow::child_iter(flow) {
+            match LayoutTask::find_scroll_container(kid, node, scroll_container) {
+                found @ Some(_) => return found,
+                None => {}
+            }
+        }
+        None
+    }
+
+    fn process_text_range_box_request<'a>(&'a self,
+                                          requested_node: TrustedNodeAddress,
+                                          start: uint,
+                                          end: uint,
+                                          layout_root: &mut FlowRef,
+                                          rw_data: &mut RWGuard<'a>) {
+        let requested_node: OpaqueNode = OpaqueNodeMethods::from_script_node(requested_node);
+        let mut iterator = TextRangeFragmentBoundsIterator::new(requested_node, start, end);
+        sequential::iterate_through_flow_tree_fragment_bounds(layout_root, &mut iterator);
+        rw_data.text_range_box_response = iterator.rect;
+    }
+
     fn build_display_list_for_reflow<'a>(&'a self,
                                          data: &Reflow,
                                          node: &mut LayoutNode,
@@ -627,9 +958,10 @@ impl LayoutTask {
                 Some((&data.url, data.iframe, self.first_reflow.get())),
                      self.time_profiler_chan.clone(),
                      || {
-            shared_layout_ctx.dirty =
-                flow::base(layout_root.deref()).position.to_physical(writing_mode,
-                                                                     rw_data.screen_size);
+            // Nothing outside the clip rect is visible, so it needs no display items.
+            let position = flow::base(layout_root.deref()).position;
+            let position = position.to_physical(writing_mode, rw_data.screen_size);
+            shared_layout_ctx.dirty = position.intersection(&data.clip).unwrap_or(Rect::zero());
             flow::mut_base(layout_root.deref_mut()).abs_position =
                 LogicalPoint::zero(writing_mode).to_physical(writing_mode,
                                                              rw_data.screen_size);
@@ -718,6 +1050,8 @@ impl LayoutTask {
     fn handle_reflow<'a>(&'a self,
                          data: &Reflow,
                          possibly_locked_rw_data: &mut Option<MutexGuard<'a, LayoutTaskData>>) {
+        let start_time = precise_time_ns();
+
         // FIXME: Isolate this transmutation into a "bridge" module.
         // FIXME(rust#16366): The following line had to be moved because of a
         // rustc bug. It should be in the next unsafe block.
@@ -728,7 +1062,8 @@ impl LayoutTask {
             mem::transmute(&mut node)
         };
 
-        debug!("layout: received layout request for: {:s}", data.url.serialize());
+        debug!("layout: received layout request for: {:s}, because of {:?}",
+               data.url.serialize(), data.reason);
         debug!("layout: parsed Node tree");
         if log_enabled!(log::DEBUG) {
             node.dump();
@@ -757,7 +1092,27 @@ impl LayoutTask {
                                                                      &data.url);
 
         // Handle conditions where the entire flow tree is invalid.
-        let needs_dirtying = rw_data.stylesheet_dirty;
+        let needs_dirtying = rw_data.stylesheet_dirty || data.needs_full_reflow;
+
+        // An image update changes no geometry, so the existing flow tree, if still valid, only
+        // needs to be painted again.
+        if data.goal == ReflowForImageUpdate && !needs_dirtying &&
+                current_screen_size == old_screen_size {
+            match self.try_get_layout_root(*node) {
+                Some(mut layout_root) => {
+                    debug!("layout: repainting for image update");
+                    self.build_display_list_for_reflow(data,
+                                                       node,
+                                                       &mut layout_root,
+                                                       &mut shared_layout_ctx,
+                                                       &mut rw_data);
+                    rw_data.generation += 1;
+                    self.notify_reflow_complete(data, duration_since(start_time));
+                    return
+                }
+                None => {}
+            }
+        }
 
         let mut needs_reflow = current_screen_size != old_screen_size;
 
@@ -839,7 +1194,7 @@ impl LayoutTask {
         });
 
         // Build the display list if necessary, and send it to the renderer.
-        if data.goal == ReflowForDisplay {
+        if data.goal == ReflowForDisplay || data.goal == ReflowForImageUpdate {
             self.build_display_list_for_reflow(data,
                                                node,
                                                &mut layout_root,
@@ -848,11 +1203,9 @@ impl LayoutTask {
         }
 
         match data.query_type {
-            ContentBoxQuery(node) =>
-                self.process_content_box_request(node, &mut layout_root, &mut rw_data),
-            ContentBoxesQuery(node) =>
-                self.process_content_boxes_request(node, &mut layout_root, &mut rw_data),
-            NoQuery => {},
+            BatchQuery(ref queries) =>
+                self.process_batch_query_request(queries.as_slice(), &mut layout_root, &mut rw_data),
+            ref query => self.process_query(query, &mut layout_root, &mut rw_data),
         }
 
         self.first_reflow.set(false);
@@ -868,12 +1221,7 @@ impl LayoutTask {
         rw_data.generation += 1;
 
         // Tell script that we're done.
-        //
-        // FIXME(pcwalton): This should probably be *one* channel, but we can't fix this without
-        // either select or a filtered recv() that only looks for messages of a given type.
-        data.script_join_chan.send(());
-        let ScriptControlChan(ref chan) = data.script_chan;
-        chan.send(ReflowCompleteMsg(self.id, data.id));
+        self.notify_reflow_complete(data, duration_since(start_time));
     }
 
     unsafe fn dirty_all_nodes(node: &mut LayoutNode) {
@@ -920,6 +1268,11 @@ impl LayoutTask {
     }
 }
 
+/// Returns the time elapsed since `start_time`, as returned by `precise_time_ns()`.
+fn duration_since(start_time: u64) -> Duration {
+    Duration::nanoseconds((precise_time_ns() - start_time) as i64)
+}
+
 struct LayoutRPCImpl(Arc<Mutex<LayoutTaskData>>);
 
 impl LayoutRPC for LayoutRPCImpl {
@@ -938,6 +1291,55 @@ impl LayoutRPC for LayoutRPCImpl {
         ContentBoxesResponse(rw_data.content_boxes_response.clone())
     }
 
+    /// Requests the border box of a node, in document coordinates.
+    fn client_rect(&self) -> ClientRectResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        ClientRectResponse(rw_data.client_rect_response)
+    }
+
+    /// Requests the resolved value of a CSS property, as in the `getComputedStyle()` call.
+    fn resolved_style(&self) -> ResolvedStyleResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        ResolvedStyleResponse(rw_data.resolved_style_response.clone())
+    }
+
+    /// Requests the dimensions of a node's scrolling box.
+    fn scroll_dimensions(&self) -> ScrollDimensionsResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        ScrollDimensionsResponse(rw_data.scroll_dimensions_response)
+    }
+
+    /// Requests the offset parent of a node and the node's position relative to it.
+    fn offset_parent(&self) -> OffsetParentResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        rw_data.offset_parent_response.clone()
+    }
+
+    /// Requests the union of the glyph boxes of a character range of a text node.
+    fn text_range_box(&self) -> TextRangeBoxResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        TextRangeBoxResponse(rw_data.text_range_box_response)
+    }
+
+    /// Requests the nearest scroll container of a node.
+    fn scroll_container(&self) -> ScrollContainerResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        ScrollContainerResponse(rw_data.scroll_container_response)
+    }
+
+    /// Requests the responses to all the queries of a batch, in order.
+    fn batch_query(&self) -> BatchQueryResponse {
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        BatchQueryResponse(rw_data.batch_query_responses.clone())
+    }
+
     /// Requests the node containing the point of interest
     fn hit_test(&self, _: TrustedNodeAddress, point: Point2D<f32>) -> Result<HitTestResponse, ()> {
         fn hit_test<'a,I>(point: Point2D<Au>, mut iterator: I)
@@ -968,6 +1370,83 @@ impl LayoutRPC for LayoutRPCImpl {
         Err(())
     }
 
+    fn hit_test_in(&self, root: TrustedNodeAddress, point: Point2D<f32>)
+                   -> Result<HitTestResponse, ()> {
+        let point = Point2D(Au::from_frac_px(point.x as f64), Au::from_frac_px(point.y as f64));
+        let &LayoutRPCImpl(ref rw_data) = self;
+        let rw_data = rw_data.lock();
+        let display_list = match rw_data.display_list {
+            None => return Err(()),
+            Some(ref display_list) => display_list,
+        };
+
+        // Only the items painted by the subtree are candidates, so collect its nodes first.
+        let mut root: JS<Node> = unsafe {
+            JS::from_trusted_node_address(root)
+        };
+        let root: &mut LayoutNode = unsafe {
+            mem::transmute(&mut root)
+        };
+        let subtree: HashSet<OpaqueNode> = root.traverse_preorder().map(|node| {
+            OpaqueNodeMethods::from_layout_node(&node)
+        }).collect();
+
+        for item in display_list.list.iter().rev() {
+            if subtree.contains(&item.base().node) &&
+                    geometry::rect_contains_point(item.base().clip_rect, point) &&
+                    geometry::rect_contains_point(item.bounds(), point) {
+                return Ok(HitTestResponse(item.base().node.to_untrusted_node_address()))
+            }
+        }
+        Err(())
+    }
+
+    fn hit_test_all(&self, node: TrustedNodeAddress, point: Point2D<f32>)
+                    -> Result<HitTestAllResponse, ()> {
+        fn hit_test_all<'a,I>(point: Point2D<Au>,
+                              mut iterator: I,
+                              result: &mut Vec<UntrustedNodeAddress>)
+                              where I: Iterator<&'a DisplayItem> {
+            for item in iterator {
+                // TODO(tikue): This check should really be performed by a method of `DisplayItem`.
+                if geometry::rect_contains_point(item.base().clip_rect, point) &&
+                        geometry::rect_contains_point(item.bounds(), point) {
+                    let node_address = item.base().node.to_untrusted_node_address();
+                    if !result.contains(&node_address) {
+                        result.push(node_address)
+                    }
+                }
+            }
+        }
+
+        let mut hit_test_list: Vec<UntrustedNodeAddress> = vec!();
+        let point = Point2D(Au::from_frac_px(point.x as f64), Au::from_frac_px(point.y as f64));
+        {
+            let &LayoutRPCImpl(ref rw_data) = self;
+            let rw_data = rw_data.lock();
+            match rw_data.display_list {
+                None => return Err(()),
+                Some(ref display_list) => {
+                    hit_test_all(point, display_list.list.iter().rev(), &mut hit_test_list);
+                }
+            };
+        }
+
+        // The root element may paint above its descendants (e.g. its outline), but it always
+        // belongs at the bottom of the stack.
+        let root: OpaqueNode = OpaqueNodeMethods::from_script_node(node);
+        let root = root.to_untrusted_node_address();
+        match hit_test_list.iter().position(|node_address| *node_address == root) {
+            Some(index) => {
+                hit_test_list.remove(index);
+                hit_test_list.push(root);
+            }
+            None => {}
+        }
+
+        Ok(HitTestAllResponse(hit_test_list))
+    }
+
     fn mouse_over(&self, _: TrustedNodeAddress, point: Point2D<f32>)
                   -> Result<MouseOverResponse, ()> {
         fn mouse_over_test<'a,I>(point: Point2D<Au>,
@@ -1033,7 +1512,12 @@ impl FragmentBoundsIterator for UnioningFragmentBoundsIterator {
 
 struct CollectingFragmentBoundsIterator {
     node_address: OpaqueNode,
-    rects: Vec<Rect<Au>>,
+    /// The bounds of the fragments of the node, in order, with the index of each fragment among
+    /// those of the node. Fragments with an empty border box, such as the whitespace left over at
+    /// the end of a wrapped line, are counted but not collected.
+    rects: Vec<(uint, Rect<Au>)>,
+    /// The number of fragments of the node processed so far.
+    fragments_seen: uint,
 }
 
 impl CollectingFragmentBoundsIterator {
@@ -1041,16 +1525,175 @@ impl CollectingFragmentBoundsIterator {
         CollectingFragmentBoundsIterator {
             node_address: node_address,
             rects: Vec::new(),
+            fragments_seen: 0,
         }
     }
 }
 
 impl FragmentBoundsIterator for CollectingFragmentBoundsIterator {
     fn process(&mut self, _: &Fragment, bounds: Rect<Au>) {
-        self.rects.push(bounds);
+        let fragment_index = self.fragments_seen;
+        self.fragments_seen += 1;
+        if bounds.size.width != Au(0) && bounds.size.height != Au(0) {
+            self.rects.push((fragment_index, bounds));
+        }
+    }
+
+    fn should_process(&mut self, fragment: &Fragment) -> bool {
+        self.node_address == fragment.node
+    }
+}
+
+/// Unions the boxes of the glyphs of a character range of a text node, across the fragments the
+/// node was split into by line breaking.
+///
+/// FIXME: Character offsets are counted from the text of the fragments, so they drift from DOM
+/// offsets wherever whitespace was compressed. Right-to-left and vertical text are measured as if
+/// they were left-to-right.
+struct TextRangeFragmentBoundsIterator {
+    node_address: OpaqueNode,
+    start: uint,
+    end: uint,
+    /// The number of characters of the node in the fragments processed so far.
+    chars_seen: uint,
+    rect: Rect<Au>,
+}
+
+impl TextRangeFragmentBoundsIterator {
+    fn new(node_address: OpaqueNode, start: uint, end: uint) -> TextRangeFragmentBoundsIterator {
+        TextRangeFragmentBoundsIterator {
+            node_address: node_address,
+            start: start,
+            end: end,
+            chars_seen: 0,
+            rect: Rect::zero(),
+        }
+    }
+}
+
+impl FragmentBoundsIterator for TextRangeFragmentBoundsIterator {
+    fn process(&mut self, fragment: &Fragment, bounds: Rect<Au>) {
+        let info = match fragment.specific {
+            ScannedTextFragment(ref info) => info,
+            _ => return,
+        };
+        let fragment_start = self.chars_seen;
+        let fragment_end = fragment_start + info.range.length().to_uint();
+        self.chars_seen = fragment_end;
+
+        let (start, end) = (max(self.start, fragment_start), min(self.end, fragment_end));
+        if start >= end {
+            return
+        }
+        let skipped = CharIndex((start - fragment_start) as int);
+        let offset = info.run.advance_for_range(&Range::new(info.range.begin(), skipped));
+        let width = info.run.advance_for_range(&Range::new(info.range.begin() + skipped,
+                                                           CharIndex((end - start) as int)));
+        let glyph_box = Rect(Point2D(bounds.origin.x + offset, bounds.origin.y),
+                             Size2D(width, bounds.size.height));
+        if self.rect.is_empty() {
+            self.rect = glyph_box;
+        } else {
+            self.rect = self.rect.union(&glyph_box);
+        }
     }
 
     fn should_process(&mut self, fragment: &Fragment) -> bool {
         self.node_address == fragment.node
     }
 }
+
+/// Computes the resolved value of a single CSS property from the first fragment of a node.
+///
+/// Only properties whose used value is known to layout are resolved here; anything else yields
+/// an empty string.
+struct ResolvedStyleFragmentBoundsIterator {
+    node_address: OpaqueNode,
+    property: PropertyId,
+    result: Option<String>,
+}
+
+impl ResolvedStyleFragmentBoundsIterator {
+    fn new(node_address: OpaqueNode, property: PropertyId) -> ResolvedStyleFragmentBoundsIterator {
+        ResolvedStyleFragmentBoundsIterator {
+            node_address: node_address,
+            property: property,
+            result: None,
+        }
+    }
+}
+
+impl FragmentBoundsIterator for ResolvedStyleFragmentBoundsIterator {
+    fn process(&mut self, fragment: &Fragment, bounds: Rect<Au>) {
+        let border_padding = fragment.border_padding.to_physical(fragment.style.writing_mode);
+        let value = match self.property.as_slice() {
+            "width" => {
+                let width = bounds.size.width - border_padding.left - border_padding.right;
+                format!("{}px", geometry::to_frac_px(width))
+            }
+            "height" => {
+                let height = bounds.size.height - border_padding.top - border_padding.bottom;
+                format!("{}px", geometry::to_frac_px(height))
+            }
+            "color" => {
+                let color = fragment.style.get_color().color;
+                format!("rgba({}, {}, {}, {})",
+                        (color.red * 255.0) as u8,
+                        (color.green * 255.0) as u8,
+                        (color.blue * 255.0) as u8,
+                        color.alpha)
+            }
+            _ => String::new(),
+        };
+        self.result = Some(value);
+    }
+
+    fn should_process(&mut self, fragment: &Fragment) -> bool {
+        self.result.is_none() && self.node_address == fragment.node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CollectingFragmentBoundsIterator;
+    use fragment::{Fragment, FragmentBoundsIterator, GenericFragment};
+    use incremental::RestyleDamage;
+
+    use geom::point::Point2D;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::display_list::OpaqueNode;
+    use servo_util::geometry::Au;
+    use style::cascade;
+    use sync::Arc;
+
+    fn line(y: int, width: int) -> Rect<Au> {
+        Rect(Point2D(Au(0), Au::from_px(y)), Size2D(Au::from_px(width), Au::from_px(20)))
+    }
+
+    #[test]
+    fn test_content_boxes_are_indexed_among_the_fragments_of_their_node() {
+        let (style, _) = cascade([], false, None, None);
+        let style = Arc::new(style);
+        let fragment = |node| {
+            Fragment::from_opaque_node_and_style(OpaqueNode(node),
+                                                 style.clone(),
+                                                 RestyleDamage::empty(),
+                                                 GenericFragment)
+        };
+
+        // An inline element wrapped onto two lines, with the empty fragment of the whitespace
+        // at the end of the first line and a fragment of a following node in between.
+        let fragments = [(fragment(1), line(0, 600)),
+                         (fragment(1), line(0, 0)),
+                         (fragment(2), line(0, 100)),
+                         (fragment(1), line(20, 150))];
+        let mut iterator = CollectingFragmentBoundsIterator::new(OpaqueNode(1));
+        for &(ref fragment, bounds) in fragments.iter() {
+            if iterator.should_process(fragment) {
+                iterator.process(fragment, bounds);
+            }
+        }
+        assert!(iterator.rects == vec!((0, line(0, 600)), (2, line(20, 150))));
+    }
+}
@@ -38,6 +38,7 @@ extern crate encoding;
 extern crate green;
 extern crate libc;
 extern crate sync;
+extern crate "time" as std_time;
 extern crate url;
 
 // Listed first because of macro definitions
@@ -114,6 +114,16 @@ impl TextRunScanner {
                 }
             }
 
+            // Without fonts, the text can't be laid out at all, so the run is skipped.
+            let fontgroup = match fontgroup {
+                Ok(fontgroup) => fontgroup,
+                Err(error) => {
+                    error!("TextRunScanner: skipping a text run without fonts: {}", error);
+                    self.clump = DList::new();
+                    return last_whitespace
+                }
+            };
+
             // First, transform/compress text of all the nodes.
             let mut run_text = String::new();
             for in_fragment in self.clump.iter() {
@@ -144,7 +154,8 @@ impl TextRunScanner {
                 self.clump = DList::new();
                 return last_whitespace
             }
-            Arc::new(box TextRun::new(&mut *fontgroup.fonts.get(0).borrow_mut(), run_text))
+            let primary = fontgroup.primary();
+            Arc::new(box TextRun::new(&mut *primary.borrow_mut(), run_text))
         };
 
         // Make new fragments with the run and adjusted text indices.
@@ -211,8 +222,13 @@ fn bounding_box_for_run_metrics(metrics: &RunMetrics, writing_mode: WritingMode)
 #[inline]
 pub fn font_metrics_for_style(font_context: &mut FontContext, font_style: Arc<FontStyle>)
                               -> FontMetrics {
-    let fontgroup = font_context.get_layout_font_group_for_style(font_style);
-    fontgroup.fonts.get(0).borrow().metrics.clone()
+    match font_context.metrics_for_style(&font_style) {
+        Ok(metrics) => metrics,
+        Err(error) => {
+            error!("Using zero font metrics for a font that couldn't be loaded: {}", error);
+            FontMetrics::zero()
+        }
+    }
 }
 
 /// Returns the line block-size needed by the given computed style and font size.
@@ -111,7 +111,8 @@ pub trait ScriptListener {
     fn scroll_fragment_point(&mut self,
                              pipeline_id: PipelineId,
                              layer_id: LayerId,
-                             point: Point2D<f32>);
+                             point: Point2D<f32>,
+                             smooth: bool);
     fn close(&mut self);
     fn dup(&mut self) -> Box<ScriptListener+'static>;
 }
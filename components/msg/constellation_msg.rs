@@ -38,6 +38,7 @@ pub struct Failure {
     pub subpage_id: Option<SubpageId>,
 }
 
+#[deriving(PartialEq)]
 pub struct WindowSizeData {
     /// The size of the initial layout viewport, before parsing an
     /// http://www.w3.org/TR/css-device-adapt/#initial-viewport
@@ -50,6 +51,21 @@ pub struct WindowSizeData {
     pub device_pixel_ratio: ScaleFactor<ViewportPx, DevicePixel, f32>,
 }
 
+impl WindowSizeData {
+    /// Returns true if `other` has a different device pixel ratio from this one, whether or not
+    /// the logical size changed too.
+    pub fn dpr_changed(&self, other: &WindowSizeData) -> bool {
+        self.device_pixel_ratio.get() != other.device_pixel_ratio.get()
+    }
+
+    /// Returns true if either viewport has a zero or negative dimension, so that there would be
+    /// nothing to lay out.
+    pub fn is_empty(&self) -> bool {
+        !(self.initial_viewport.width.get() > 0.0 && self.initial_viewport.height.get() > 0.0 &&
+          self.visible_viewport.width.get() > 0.0 && self.visible_viewport.height.get() > 0.0)
+    }
+}
+
 /// Messages from the compositor and script to the constellation.
 pub enum Msg {
     ExitMsg,
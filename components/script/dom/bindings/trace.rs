@@ -31,7 +31,7 @@ use js::jsapi::{JSObject, JSTracer, JS_CallTracer, JSTRACE_OBJECT};
 use js::jsval::JSVal;
 
 use libc;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
 
 use url::Url;
@@ -48,14 +48,17 @@ use http::headers::response::HeaderCollection as ResponseHeaderCollection;
 use http::headers::request::HeaderCollection as RequestHeaderCollection;
 use http::method::Method;
 use std::io::timer::Timer;
+use std::time::duration::Duration;
 use script_traits::UntrustedNodeAddress;
 use servo_msg::compositor_msg::ScriptListener;
 use servo_msg::constellation_msg::ConstellationChan;
 use servo_util::smallvec::{SmallVec1, SmallVec};
 use servo_util::str::LengthOrPercentageOrAuto;
-use layout_interface::{LayoutRPC, LayoutChan};
+use layout_interface::{LayoutRPC, LayoutChan, ReflowGoal, ReflowReason};
 use dom::node::{Node, TrustedNodeAddress};
 use dom::bindings::utils::WindowProxyHandler;
+use geom::point::Point2D;
+use geom::rect::Rect;
 use html5ever::tree_builder::QuirksMode;
 
 impl<T: Reflectable> JSTraceable for JS<T> {
@@ -198,7 +201,7 @@ no_jsmanaged_fields!(int, i8, i16, i32, i64)
 no_jsmanaged_fields!(Sender<T>)
 no_jsmanaged_fields!(Receiver<T>)
 no_jsmanaged_fields!(ImageCacheTask, ScriptControlChan)
-no_jsmanaged_fields!(Atom, Namespace, Timer)
+no_jsmanaged_fields!(Atom, Namespace, Timer, Duration)
 no_jsmanaged_fields!(PropertyDeclarationBlock)
 // These three are interdependent, if you plan to put jsmanaged data
 // in one of these make sure it is propagated properly to containing structs
@@ -207,10 +210,12 @@ no_jsmanaged_fields!(QuirksMode)
 no_jsmanaged_fields!(Cx)
 no_jsmanaged_fields!(ResponseHeaderCollection, RequestHeaderCollection, Method)
 no_jsmanaged_fields!(ConstellationChan)
-no_jsmanaged_fields!(LayoutChan)
+no_jsmanaged_fields!(LayoutChan, ReflowGoal, ReflowReason)
 no_jsmanaged_fields!(WindowProxyHandler)
 no_jsmanaged_fields!(UntrustedNodeAddress)
 no_jsmanaged_fields!(LengthOrPercentageOrAuto)
+no_jsmanaged_fields!(Point2D<T>, Rect<T>)
+no_jsmanaged_fields!(Weak<T>)
 
 impl<'a> JSTraceable for &'a str {
     #[inline]
@@ -894,7 +894,7 @@ impl<'a> ElementMethods for JSRef<'a, Element> {
         let win = window_from_node(self).root();
         let node: JSRef<Node> = NodeCast::from_ref(self);
         let rects = node.get_content_boxes();
-        let rects: Vec<Root<DOMRect>> = rects.iter().map(|r| {
+        let rects: Vec<Root<DOMRect>> = rects.iter().map(|&(_, ref r)| {
             DOMRect::new(
                 *win,
                 r.origin.y,
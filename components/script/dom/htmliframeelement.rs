@@ -177,7 +177,7 @@ impl<'a> HTMLIFrameElementMethods for JSRef<'a, HTMLIFrameElement> {
             let window = window_from_node(self).root();
             let children = window.page().children.borrow();
             let child = children.iter().find(|child| {
-                child.subpage_id.unwrap() == size.subpage_id
+                child.subpage_id.get().unwrap() == size.subpage_id
             });
             child.and_then(|page| {
                 page.frame.borrow().as_ref().map(|frame| {
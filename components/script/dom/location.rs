@@ -48,6 +48,12 @@ impl<'a> LocationMethods for JSRef<'a, Location> {
     fn Hash(self) -> DOMString {
         UrlHelper::Hash(&self.page.get_url())
     }
+
+    fn Reload(self) {
+        let frame = self.page.frame();
+        let window = frame.as_ref().unwrap().window.root();
+        self.page.reload(window.script_chan());
+    }
 }
 
 impl Reflectable for Location {
@@ -446,7 +446,7 @@ pub trait NodeHelpers<'a> {
     fn to_trusted_node_address(self) -> TrustedNodeAddress;
 
     fn get_bounding_content_box(self) -> Rect<Au>;
-    fn get_content_boxes(self) -> Vec<Rect<Au>>;
+    fn get_content_boxes(self) -> Vec<(uint, Rect<Au>)>;
 
     fn query_selector(self, selectors: DOMString) -> Fallible<Option<Temporary<Element>>>;
     fn query_selector_all(self, selectors: DOMString) -> Fallible<Temporary<NodeList>>;
@@ -695,7 +695,7 @@ impl<'a> NodeHelpers<'a> for JSRef<'a, Node> {
         window_from_node(self).root().page().content_box_query(self.to_trusted_node_address())
     }
 
-    fn get_content_boxes(self) -> Vec<Rect<Au>> {
+    fn get_content_boxes(self) -> Vec<(uint, Rect<Au>)> {
         window_from_node(self).root().page().content_boxes_query(self.to_trusted_node_address())
     }
 
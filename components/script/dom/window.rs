@@ -19,7 +19,7 @@ use dom::location::Location;
 use dom::navigator::Navigator;
 use dom::performance::Performance;
 use dom::screen::Screen;
-use layout_interface::NoQuery;
+use layout_interface::{NoQuery, ReflowReason};
 use page::Page;
 use script_task::{ExitWindowMsg, ScriptChan, TriggerLoadMsg, TriggerFragmentMsg};
 use script_task::FromWindow;
@@ -312,7 +312,7 @@ impl Reflectable for Window {
 
 pub trait WindowHelpers {
     fn reflow(self);
-    fn flush_layout(self);
+    fn flush_layout(self, reason: ReflowReason);
     fn wait_until_safe_to_modify_dom(self);
     fn init_browser_context(self, doc: JSRef<Document>);
     fn load_url(self, href: DOMString);
@@ -350,14 +350,18 @@ impl<'a> WindowHelpers for JSRef<'a, Window> {
         self.page().damage();
     }
 
-    fn flush_layout(self) {
-        self.page().flush_layout(NoQuery);
+    fn flush_layout(self, reason: ReflowReason) {
+        self.page().flush_layout(NoQuery, reason);
     }
 
     fn wait_until_safe_to_modify_dom(self) {
         // FIXME: This disables concurrent layout while we are modifying the DOM, since
         //        our current architecture is entirely unsafe in the presence of races.
-        self.page().join_layout();
+        match self.page().join_layout() {
+            Ok(()) => {}
+            Err(error) => error!("script: could not join layout before modifying the DOM: {:?}",
+                                 error),
+        }
     }
 
     fn init_browser_context(self, doc: JSRef<Document>) {
@@ -10,12 +10,15 @@ use dom::node::LayoutDataRef;
 
 use geom::point::Point2D;
 use geom::rect::Rect;
+use geom::size::Size2D;
 use script_traits::{ScriptControlChan, OpaqueScriptLayoutChannel, UntrustedNodeAddress};
 use servo_msg::constellation_msg::WindowSizeData;
 use servo_util::geometry::Au;
 use std::any::{Any, AnyRefExt};
 use std::comm::{channel, Receiver, Sender};
 use std::owned::BoxAny;
+use std::time::duration::Duration;
+use string_cache::Atom;
 use style::Stylesheet;
 use url::Url;
 
@@ -32,6 +35,10 @@ pub enum Msg {
     /// Requests a reflow.
     ReflowMsg(Box<Reflow>),
 
+    /// Requests that the reflow with the given id be abandoned if layout has not started it yet.
+    /// The join channel of a cancelled reflow is still notified, as if it had completed.
+    CancelReflowMsg(uint),
+
     /// Get an RPC interface.
     GetRPCMsg(Sender<Box<LayoutRPC + Send>>),
 
@@ -61,16 +68,88 @@ pub enum Msg {
 pub trait LayoutRPC {
     /// Requests the dimensions of the content box, as in the `getBoundingClientRect()` call.
     fn content_box(&self) -> ContentBoxResponse;
-    /// Requests the dimensions of all the content boxes, as in the `getClientRects()` call.
+    /// Requests the dimensions of all the content boxes, as in the `getClientRects()` call. Each
+    /// box comes with the index of the fragment it belongs to among the node's fragments, e.g.
+    /// the line of a wrapped inline element. Fragments with empty boxes are left out.
     fn content_boxes(&self) -> ContentBoxesResponse;
+    /// Requests the border box of a node, as used by `getBoundingClientRect()`. The caller is
+    /// responsible for translating the result into viewport coordinates.
+    fn client_rect(&self) -> ClientRectResponse;
+    /// Requests the resolved value of a CSS property, as in the `getComputedStyle()` call.
+    fn resolved_style(&self) -> ResolvedStyleResponse;
+    /// Requests the dimensions of a node's scrolling box, as in the `scrollWidth` and
+    /// `scrollHeight` attributes.
+    fn scroll_dimensions(&self) -> ScrollDimensionsResponse;
+    /// Requests the nearest positioned ancestor of a node and the node's border box relative to
+    /// it, as in the `offsetParent`, `offsetTop` and `offsetLeft` attributes.
+    fn offset_parent(&self) -> OffsetParentResponse;
+    /// Requests the union of the glyph boxes of a character range of a text node, as in the
+    /// `Range.getBoundingClientRect()` call.
+    fn text_range_box(&self) -> TextRangeBoxResponse;
+    /// Requests the nearest ancestor of a node that scrolls its overflowing content, or `None`
+    /// if the node is scrolled by the viewport, as needed by `scrollIntoView()`.
+    fn scroll_container(&self) -> ScrollContainerResponse;
+    /// Requests the responses to all the queries of a `BatchQuery`, in order.
+    fn batch_query(&self) -> BatchQueryResponse;
     /// Requests the node containing the point of interest
     fn hit_test(&self, node: TrustedNodeAddress, point: Point2D<f32>) -> Result<HitTestResponse, ()>;
+    /// Requests the topmost node containing the point of interest among `root` and its
+    /// descendants. Fails if no node of that subtree was hit, or if nothing was laid out yet.
+    fn hit_test_in(&self, root: TrustedNodeAddress, point: Point2D<f32>)
+                   -> Result<HitTestResponse, ()>;
+    /// Requests all the nodes containing the point of interest, from top to bottom, as in the
+    /// `elementsFromPoint()` call. The document element `node` is always last if it was hit.
+    /// Fails if nothing was laid out yet.
+    fn hit_test_all(&self, node: TrustedNodeAddress, point: Point2D<f32>)
+                    -> Result<HitTestAllResponse, ()>;
     fn mouse_over(&self, node: TrustedNodeAddress, point: Point2D<f32>) -> Result<MouseOverResponse, ()>;
 }
 
 pub struct ContentBoxResponse(pub Rect<Au>);
-pub struct ContentBoxesResponse(pub Vec<Rect<Au>>);
+pub struct ContentBoxesResponse(pub Vec<(uint, Rect<Au>)>);
+pub struct ClientRectResponse(pub Rect<Au>);
+pub struct ResolvedStyleResponse(pub Option<String>);
+pub struct ScrollDimensionsResponse(pub Size2D<Au>);
+pub struct TextRangeBoxResponse(pub Rect<Au>);
+pub struct ScrollContainerResponse(pub Option<UntrustedNodeAddress>);
+
+#[deriving(Clone)]
+pub struct OffsetParentResponse {
+    /// The nearest positioned ancestor, or `None` if the node has no offset parent.
+    pub node_address: Option<UntrustedNodeAddress>,
+    /// The border box of the node, relative to the offset parent.
+    pub rect: Rect<Au>,
+}
+
+impl OffsetParentResponse {
+    pub fn empty() -> OffsetParentResponse {
+        OffsetParentResponse {
+            node_address: None,
+            rect: Rect::zero(),
+        }
+    }
+}
+
+/// The response to one of the queries in a `BatchQuery`. Each variant holds what the
+/// corresponding `LayoutRPC` method would have returned for a single query.
+#[deriving(Clone)]
+pub enum QueryResponse {
+    NoQueryResponse,
+    ContentBoxQueryResponse(Rect<Au>),
+    ContentBoxesQueryResponse(Vec<(uint, Rect<Au>)>),
+    ClientRectQueryResponse(Rect<Au>),
+    ResolvedStyleQueryResponse(Option<String>),
+    ScrollDimensionsQueryResponse(Size2D<Au>),
+    OffsetParentQueryResponse(OffsetParentResponse),
+    TextRangeBoxQueryResponse(Rect<Au>),
+    ScrollContainerQueryResponse(Option<UntrustedNodeAddress>),
+    /// The response to a query that can't be part of a batch, i.e. another batch.
+    NestedBatchQueryResponse,
+}
+
+pub struct BatchQueryResponse(pub Vec<QueryResponse>);
 pub struct HitTestResponse(pub UntrustedNodeAddress);
+pub struct HitTestAllResponse(pub Vec<UntrustedNodeAddress>);
 pub struct MouseOverResponse(pub Vec<UntrustedNodeAddress>);
 
 /// Why we're doing reflow.
@@ -80,13 +159,72 @@ pub enum ReflowGoal {
     ReflowForDisplay,
     /// We're reflowing in order to satisfy a script query. No display list will be created.
     ReflowForScriptQuery,
+    /// We're reflowing because images finished loading without changing any geometry. The
+    /// existing flow tree is painted again, if there is one, rather than being rebuilt.
+    ReflowForImageUpdate,
+}
+
+/// How urgently a reflow is needed, so that the layout task can run the most urgent ones first.
+#[deriving(PartialEq, Eq, PartialOrd, Ord, Show)]
+pub enum ReflowPriority {
+    /// Nothing is waiting on the reflow, e.g. when repainting images that finished loading.
+    BackgroundReflowPriority,
+    /// The reflow updates what the user sees.
+    DisplayReflowPriority,
+    /// Script is blocked until the reflow finishes.
+    ScriptQueryReflowPriority,
+}
+
+/// What caused a reflow, so that profiles can break reflows down by cause.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ReflowReason {
+    /// The document is laid out for the first time.
+    FirstLoad,
+    /// The window was resized.
+    WindowResize,
+    /// The compositor asked for the page to be reflowed.
+    ReceivedReflowEvent,
+    /// Reflows that were asked for while layout was busy are run once it has finished.
+    PendingReflows,
+    /// A page that was loaded again from the cache needs to be laid out.
+    CachedPageNeededReflow,
+    /// The mouse moved onto other nodes.
+    MouseMove,
+    /// A DOM event, such as a click, was dispatched.
+    DOMEvent,
+    /// Script asked for geometry or style that layout has to compute.
+    ScriptQuery,
 }
 
+impl ReflowGoal {
+    /// The priority of reflows done for this goal.
+    pub fn priority(&self) -> ReflowPriority {
+        match *self {
+            ReflowForDisplay => DisplayReflowPriority,
+            ReflowForScriptQuery => ScriptQueryReflowPriority,
+            ReflowForImageUpdate => BackgroundReflowPriority,
+        }
+    }
+}
+
+/// The name of a CSS property whose resolved value is requested by script.
+pub type PropertyId = Atom;
+
 /// Any query to perform with this reflow.
 pub enum ReflowQueryType {
     NoQuery,
     ContentBoxQuery(TrustedNodeAddress),
     ContentBoxesQuery(TrustedNodeAddress),
+    ClientRectQuery(TrustedNodeAddress),
+    ResolvedStyleQuery(TrustedNodeAddress, PropertyId),
+    ScrollDimensionsQuery(TrustedNodeAddress),
+    OffsetParentQuery(TrustedNodeAddress),
+    /// The characters from `start` up to but not including `end` of a text node.
+    TextRangeBoxQuery(TrustedNodeAddress, uint, uint),
+    ScrollContainerQuery(TrustedNodeAddress),
+    /// Several of the above queries, answered by a single reflow. A batch within a batch is
+    /// answered by `NestedBatchQueryResponse`.
+    BatchQuery(Vec<ReflowQueryType>),
 }
 
 /// Information needed for a reflow.
@@ -95,6 +233,10 @@ pub struct Reflow {
     pub document_root: TrustedNodeAddress,
     /// The goal of reflow: either to render to the screen or to flush layout info for script.
     pub goal: ReflowGoal,
+    /// How urgent the reflow is, derived from `goal`.
+    pub priority: ReflowPriority,
+    /// What caused the reflow.
+    pub reason: ReflowReason,
     /// The URL of the page.
     pub url: Url,
     /// Is the current reflow of an iframe, as opposed to a root window?
@@ -103,8 +245,16 @@ pub struct Reflow {
     pub script_chan: ScriptControlChan,
     /// The current window size.
     pub window_size: WindowSizeData,
-    /// The channel that we send a notification to.
-    pub script_join_chan: Sender<()>,
+    /// Whether nothing laid out before can be reused, because the device pixel ratio changed
+    /// since the last reflow.
+    pub needs_full_reflow: bool,
+    /// The part of the page that is visible, outside of which nothing needs to be painted.
+    pub clip: Rect<Au>,
+    /// The channel that we send a notification to, carrying how long layout spent on the reflow.
+    pub script_join_chan: Sender<Duration>,
+    /// Channels that are sent the id of this reflow once it has finished or been cancelled, on
+    /// behalf of embedders waiting for particular reflows.
+    pub completion_chans: Vec<Sender<uint>>,
     /// Unique identifier
     pub id: uint,
     /// The type of query if any to perform during this reflow.
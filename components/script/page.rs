@@ -12,41 +12,114 @@ use dom::element::Element;
 use dom::node::{Node, NodeHelpers};
 use dom::window::Window;
 use layout_interface::{
-    ContentBoxQuery, ContentBoxResponse, ContentBoxesQuery, ContentBoxesResponse,
-    GetRPCMsg, HitTestResponse, LayoutChan, LayoutRPC, MouseOverResponse, NoQuery,
-    Reflow, ReflowForDisplay, ReflowForScriptQuery, ReflowGoal, ReflowMsg,
-    ReflowQueryType, TrustedNodeAddress
+    BatchQuery, BatchQueryResponse, CancelReflowMsg, ClientRectQuery, ClientRectQueryResponse,
+    ClientRectResponse, ContentBoxQuery, ContentBoxResponse, ContentBoxesQuery,
+    ContentBoxesResponse, GetRPCMsg, HitTestAllResponse, HitTestResponse, LayoutChan, LayoutRPC,
+    MouseOverResponse, NoQuery, OffsetParentQuery, OffsetParentResponse, PropertyId, QueryResponse,
+    Reflow, ReflowForDisplay, ReflowForImageUpdate, ReflowForScriptQuery, ReflowGoal, ReflowMsg,
+    ReflowQueryType, ReflowReason, ResolvedStyleQuery, ResolvedStyleResponse, ScrollContainerQuery,
+    ScriptQuery, ScrollContainerResponse, ScrollDimensionsQuery, ScrollDimensionsResponse,
+    TextRangeBoxQuery, TextRangeBoxResponse, TrustedNodeAddress
 };
+use script_task::{ReloadMsg, ScriptChan};
 use script_traits::{UntrustedNodeAddress, ScriptControlChan};
 
-use geom::{Point2D, Rect};
+use geom::{Point2D, Rect, Size2D};
 use js::rust::Cx;
-use servo_msg::compositor_msg::PerformingLayout;
+use servo_msg::compositor_msg::{LayerId, PerformingLayout};
 use servo_msg::compositor_msg::ScriptListener;
 use servo_msg::constellation_msg::{ConstellationChan, WindowSizeData};
 use servo_msg::constellation_msg::{PipelineId, SubpageId};
 use servo_net::resource_task::ResourceTask;
-use servo_util::geometry::Au;
+use servo_util::geometry::{Au, to_frac_px};
 use servo_util::str::DOMString;
 use servo_util::smallvec::{SmallVec1, SmallVec};
 use std::cell::Cell;
-use std::comm::{channel, Receiver, Empty, Disconnected};
+use std::f64;
+use std::collections::{Deque, HashMap, RingBuf};
+use std::comm::{channel, Receiver, Sender, Empty, Disconnected};
 use std::mem::replace;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+use std::time::duration::Duration;
 use url::Url;
 
+/// The ways in which joining the layout task can fail.
+#[deriving(PartialEq, Show)]
+pub enum LayoutJoinError {
+    /// The layout task failed while script was waiting for a result.
+    LayoutTaskFailed,
+}
+
+/// Whether the layout task is running a reflow for a page.
+#[deriving(Clone, PartialEq, Show)]
+#[jstraceable]
+pub enum LayoutState {
+    /// No reflow has been sent to layout since it was last joined.
+    Idle,
+    /// Layout was sent the reflow with the given id, and hasn't been joined since.
+    Running { reflow_id: uint },
+}
+
+/// The ways in which adding a page to a page tree can fail.
+#[deriving(PartialEq, Show)]
+pub enum PageTreeError {
+    /// The page would be nested more deeply than the page tree allows.
+    FrameTooDeep,
+}
+
+/// The default number of nested frames that a page tree allows below its root.
+pub static DEFAULT_MAX_FRAME_DEPTH: uint = 10;
+
+/// The ways in which setting the window size of a page can fail.
+#[deriving(PartialEq, Show)]
+pub enum WindowSizeError {
+    /// The window has a zero or negative width or height.
+    EmptyWindowSize,
+}
+
+/// The default distance, in pixels along each axis, that the mouse may move from the point of the
+/// last mouse-over query before the nodes under it are queried again.
+pub static DEFAULT_MOUSE_MOVE_HYSTERESIS: f32 = 1.0;
+
+/// The nodes found under the mouse by the last mouse-over query, and where and when it was made.
+#[jstraceable]
+struct MouseOverCache<T> {
+    /// The point that was queried.
+    point: Point2D<f32>,
+    /// The id of the last reflow sent to layout when the query was made.
+    reflow_id: uint,
+    /// The result of the query.
+    nodes: T,
+}
+
+/// The maximum number of content boxes kept by `content_box_cached`.
+static CONTENT_BOX_CACHE_CAPACITY: uint = 64;
+
+/// The content boxes computed by layout for one reflow, for `content_box_cached`.
+#[jstraceable]
+struct ContentBoxCache {
+    /// The id of the reflow that computed the boxes.
+    reflow_id: uint,
+    /// The boxes of the nodes queried since that reflow, oldest first.
+    boxes: Vec<(TrustedNodeAddress, Rect<Au>)>,
+}
+
 /// Encapsulates a handle to a frame and its associated layout information.
 #[jstraceable]
 pub struct Page {
     /// Pipeline id associated with this page.
     pub id: PipelineId,
 
-    /// Subpage id associated with this page, if any.
-    pub subpage_id: Option<SubpageId>,
+    /// Subpage id associated with this page, if any. This is cleared when the page becomes the
+    /// root of its own page tree; see `detach_as_root`.
+    pub subpage_id: Cell<Option<SubpageId>>,
 
     /// Unique id for last reflow request; used for confirming completion reply.
     pub last_reflow_id: Cell<uint>,
 
+    /// The goal of the last reflow request sent to layout.
+    pub last_reflow_goal: Cell<ReflowGoal>,
+
     /// The outermost frame containing the document, window, and page URL.
     pub frame: DOMRefCell<Option<Frame>>,
 
@@ -56,30 +129,52 @@ pub struct Page {
     /// A handle to perform RPC calls into the layout, quickly.
     layout_rpc: Box<LayoutRPC+'static>,
 
-    /// The port that we will use to join layout. If this is `None`, then layout is not running.
-    pub layout_join_port: DOMRefCell<Option<Receiver<()>>>,
+    /// The port that we will use to join layout, while it is running a reflow.
+    pub layout_join_port: DOMRefCell<Option<Receiver<Duration>>>,
+
+    /// Whether layout is running a reflow, kept in sync with `layout_join_port`.
+    layout_state: Cell<LayoutState>,
+
+    /// How long layout spent on the last reflow that script joined. Zero before the first one.
+    last_reflow_duration: Cell<Duration>,
 
     /// The current size of the window, in pixels.
     pub window_size: Cell<WindowSizeData>,
 
     js_info: DOMRefCell<Option<JSPageInfo>>,
 
-    /// Cached copy of the most recent url loaded by the script
-    /// TODO(tkuehn): this currently does not follow any particular caching policy
-    /// and simply caches pages forever (!). The bool indicates if reflow is required
-    /// when reloading.
+    /// Cached copy of the most recent url loaded by the script. The bool indicates if reflow is
+    /// required when reloading.
     url: DOMRefCell<Option<(Url, bool)>>,
 
+    /// The urls previously loaded by the script, least recently used first. Bounded by
+    /// `url_cache_capacity`.
+    url_history: DOMRefCell<Vec<(Url, bool)>>,
+
+    /// The maximum number of entries kept in `url_history`.
+    url_cache_capacity: uint,
+
     next_subpage_id: Cell<SubpageId>,
 
-    /// Pending resize event, if any.
-    pub resize_event: Cell<Option<WindowSizeData>>,
+    /// Subpage ids of child frames that the constellation has dropped, handed out again before
+    /// new ones are minted.
+    free_subpage_ids: DOMRefCell<Vec<SubpageId>>,
+
+    /// The window size sent with the last reflow, if any.
+    last_reflow_window_size: Cell<Option<WindowSizeData>>,
+
+    /// Pending resize event, if any; see `set_window_size`.
+    resize_event: Cell<Option<WindowSizeData>>,
 
     /// Any nodes that need to be dirtied before the next reflow.
     pub pending_dirty_nodes: DOMRefCell<SmallVec1<UntrustedNodeAddress>>,
 
     /// Pending scroll to fragment event, if any
-    pub fragment_name: DOMRefCell<Option<String>>,
+    pub fragment_name: DOMRefCell<Option<FragmentScroll>>,
+
+    /// The scroll offset of the viewport, as last reported by the compositor. Scrolls that
+    /// script requests only take effect once the compositor has performed them.
+    pub scroll_offset: Cell<Point2D<Au>>,
 
     /// Associated resource task for use by DOM objects like XMLHttpRequest
     pub resource_task: ResourceTask,
@@ -90,23 +185,262 @@ pub struct Page {
     // Child Pages.
     pub children: DOMRefCell<Vec<Rc<Page>>>,
 
+    /// The page containing this one, if any. This is weak so that pages don't keep their parents
+    /// alive.
+    parent: DOMRefCell<Option<Weak<Page>>>,
+
+    /// Every descendant of this page, keyed by pipeline id. Only maintained on the root of the
+    /// page tree, by `add_child` and `remove`; empty everywhere else.
+    page_index: DOMRefCell<HashMap<PipelineId, Rc<Page>>>,
+
+    /// How many frames deep pages may be nested below this page, if it is the root of its page
+    /// tree. Deeper pages are rejected by `add_child`.
+    max_frame_depth: Cell<uint>,
+
     /// Whether layout needs to be run at all.
     pub damaged: Cell<bool>,
 
+    /// Whether images finished loading since the last reflow that painted the page.
+    image_damaged: Cell<bool>,
+
+    /// The content boxes last computed by `content_box_query`, for `content_box_cached`.
+    content_box_cache: DOMRefCell<ContentBoxCache>,
+
+    /// The last mouse-over query made by `get_nodes_under_mouse`, if any.
+    mouse_over_cache: DOMRefCell<Option<MouseOverCache<Option<Vec<UntrustedNodeAddress>>>>>,
+
+    /// How far the mouse may move from the last queried point before `get_nodes_under_mouse`
+    /// queries layout again, in pixels along each axis.
+    mouse_move_hysteresis: Cell<f32>,
+
     /// Number of pending reflows that were sent while layout was active.
     pub pending_reflows: Cell<int>,
 
+    /// The reason for an idle reflow that is waiting for layout to be quiet, if any. See
+    /// `reflow_idle`.
+    pending_idle_reflow: Cell<Option<ReflowReason>>,
+
     /// Number of unnecessary potential reflows that were skipped since the last reflow
     pub avoided_reflows: Cell<int>,
+
+    /// Whether the page is being navigated away from, in which case reflows are skipped until a
+    /// document is loaded into it again.
+    pub suppress_reflows: Cell<bool>,
+
+    /// Number of reflow requests actually sent to layout over the lifetime of this page.
+    total_reflows: Cell<u64>,
+
+    /// Number of potential reflows that were skipped over the lifetime of this page.
+    total_avoided: Cell<u64>,
+
+    /// The completion channels of reflows that were coalesced into the one in flight. They are
+    /// notified along with the next reflow that is sent to layout, which includes their changes.
+    pending_completion_chans: DOMRefCell<Vec<Sender<uint>>>,
+}
+
+/// A snapshot of the reflow counters of a page, for telemetry.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ReflowStats {
+    /// Number of pending reflows that were sent while layout was active.
+    pub pending_reflows: int,
+    /// Number of unnecessary potential reflows that were skipped since the last reflow.
+    pub avoided_reflows: int,
+    /// Unique id of the last reflow request.
+    pub last_reflow_id: uint,
+    /// Number of reflow requests sent to layout so far.
+    pub total_reflows: u64,
+}
+
+/// A pending scroll to the fragment with the given name.
+#[deriving(Clone, PartialEq, Show)]
+#[jstraceable]
+pub struct FragmentScroll {
+    /// The name of the fragment to scroll to.
+    pub name: String,
+    /// Whether the compositor should scroll smoothly rather than jump.
+    pub smooth: bool,
+}
+
+impl FragmentScroll {
+    /// Creates an instant scroll to the named fragment; this is the default when no scroll
+    /// behavior was requested.
+    pub fn instant(name: String) -> FragmentScroll {
+        FragmentScroll {
+            name: name,
+            smooth: false,
+        }
+    }
+
+    /// Creates a smooth scroll to the named fragment.
+    pub fn smooth(name: String) -> FragmentScroll {
+        FragmentScroll {
+            name: name,
+            smooth: true,
+        }
+    }
+}
+
+/// Where `Page::scroll_to_node` puts a node in the viewport, as for the `block` and `inline`
+/// options of `scrollIntoView()`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ScrollAlign {
+    /// The start edge of the node is lined up with that of the viewport.
+    AlignStart,
+    /// The node is centered in the viewport.
+    AlignCenter,
+    /// The end edge of the node is lined up with that of the viewport.
+    AlignEnd,
+    /// The viewport is scrolled as little as possible to show the node, and not at all if the
+    /// node is already in view.
+    AlignNearest,
+}
+
+/// Returns the scroll position along one axis that puts the box from `start` measuring `size`
+/// in the viewport as `align` asks. `current` is the scroll position before scrolling.
+fn scroll_target(current: Au, viewport: Au, start: Au, size: Au, align: ScrollAlign) -> Au {
+    let end = start + size;
+    let target = match align {
+        AlignStart => start,
+        AlignCenter => start + (size - viewport).scale_by(0.5),
+        AlignEnd => end - viewport,
+        AlignNearest => {
+            let viewport_end = current + viewport;
+            if (start >= current && end <= viewport_end) ||
+                    (start <= current && end >= viewport_end) {
+                current
+            } else if start < current || size > viewport {
+                start
+            } else {
+                end - viewport
+            }
+        }
+    };
+    Au::max(target, Au(0))
 }
 
+/// Iterates over a page tree depth-first. Each page it yields is an `Rc` clone, since the
+/// iterator outlives the borrows of the pages' children; see `IterablePage::each_page` for a
+/// traversal without clones.
 pub struct PageIterator {
     stack: Vec<Rc<Page>>,
 }
 
+/// A handle to a node of a page tree, which lends out the handles to its children. This is
+/// generic so that traversals can be tested without real pages.
+trait PageTreeNode {
+    fn with_children(&self, f: |&[Self]|);
+    fn subpage_id(&self) -> Option<SubpageId>;
+}
+
+impl PageTreeNode for Rc<Page> {
+    fn with_children(&self, f: |&[Rc<Page>]|) {
+        f(self.children.borrow().as_slice())
+    }
+
+    fn subpage_id(&self) -> Option<SubpageId> {
+        self.subpage_id.get()
+    }
+}
+
+/// Pairs a removed node, if any, with the subpage id it had in its parent.
+fn with_subpage_id<T: PageTreeNode>(removed: Option<T>) -> Option<(T, Option<SubpageId>)> {
+    removed.map(|node| {
+        let subpage_id = node.subpage_id();
+        (node, subpage_id)
+    })
+}
+
+/// Pops the next node of a depth-first traversal off `stack`, pushing clones of its children.
+fn next_depth_first<T: PageTreeNode + Clone>(stack: &mut Vec<T>) -> Option<T> {
+    let next = stack.pop();
+    match next {
+        Some(ref node) => node.with_children(|children| stack.push_all(children)),
+        None => {}
+    }
+    next
+}
+
+/// Calls `f` on `node` and then on each of its descendants, parents before children, borrowing
+/// each node's children rather than cloning them.
+fn each_node<T: PageTreeNode>(node: &T, f: &mut |&T|) {
+    (*f)(node);
+    node.with_children(|children| {
+        for child in children.iter() {
+            each_node(child, &mut *f);
+        }
+    })
+}
+
+/// Returns the node among `children` and their descendants whose subpage id is `subpage_id`.
+/// Subpage ids are only unique among the children of one page, so `children` themselves are
+/// searched before any of their descendants.
+fn find_by_subpage_id<T: PageTreeNode + Clone>(children: &[T], subpage_id: SubpageId)
+                                               -> Option<T> {
+    match children.iter().find(|child| child.subpage_id() == Some(subpage_id)) {
+        Some(child) => return Some(child.clone()),
+        None => {}
+    }
+    let mut found = None;
+    for child in children.iter() {
+        child.with_children(|grandchildren| {
+            found = find_by_subpage_id(grandchildren, subpage_id);
+        });
+        if found.is_some() {
+            break
+        }
+    }
+    found
+}
+
+/// Like `each_node`, but stops as soon as `f` returns false. Returns false if it stopped early.
+fn each_node_while<T: PageTreeNode>(node: &T, f: &mut |&T| -> bool) -> bool {
+    if !(*f)(node) {
+        return false
+    }
+    let mut keep_going = true;
+    node.with_children(|children| {
+        for child in children.iter() {
+            if !each_node_while(child, &mut *f) {
+                keep_going = false;
+                break
+            }
+        }
+    });
+    keep_going
+}
+
+/// Iterates over a page tree level by level: the root, then all of its children, then all of its
+/// grandchildren, and so on.
+pub struct BreadthFirstPageIterator {
+    queue: RingBuf<Rc<Page>>,
+}
+
+/// Iterates over a page tree depth-first like `PageIterator`, also yielding the depth of each
+/// page. The root is at depth 0 and its direct children at depth 1.
+pub struct DepthPageIterator {
+    stack: Vec<(Rc<Page>, uint)>,
+}
+
+/// Iterates over a page tree like `PageIterator`, skipping pages that don't need layout.
+pub struct DamagedPageIterator {
+    iter: PageIterator,
+}
+
+/// Iterates from the parent of a page up to the root of its page tree.
+pub struct AncestorPageIterator {
+    current: Option<Rc<Page>>,
+}
+
 pub trait IterablePage {
     fn iter(&self) -> PageIterator;
+    fn iter_breadth_first(&self) -> BreadthFirstPageIterator;
+    fn iter_with_depth(&self) -> DepthPageIterator;
+    fn iter_damaged(&self) -> DamagedPageIterator;
+    fn ancestors(&self) -> AncestorPageIterator;
     fn find(&self, id: PipelineId) -> Option<Rc<Page>>;
+    /// Calls `f` on each page of the tree, parents before children. Unlike `iter`, this doesn't
+    /// clone an `Rc` for each page. `f` must not add or remove pages.
+    fn each_page(&self, f: |&Rc<Page>|);
 }
 
 impl IterablePage for Rc<Page> {
@@ -115,8 +449,40 @@ impl IterablePage for Rc<Page> {
             stack: vec!(self.clone()),
         }
     }
+    fn iter_breadth_first(&self) -> BreadthFirstPageIterator {
+        let mut queue = RingBuf::new();
+        queue.push_back(self.clone());
+        BreadthFirstPageIterator {
+            queue: queue,
+        }
+    }
+    fn iter_with_depth(&self) -> DepthPageIterator {
+        DepthPageIterator {
+            stack: vec!((self.clone(), 0)),
+        }
+    }
+    fn iter_damaged(&self) -> DamagedPageIterator {
+        DamagedPageIterator {
+            iter: self.iter(),
+        }
+    }
+    fn ancestors(&self) -> AncestorPageIterator {
+        AncestorPageIterator {
+            current: self.parent(),
+        }
+    }
+    fn each_page(&self, f: |&Rc<Page>|) {
+        let mut f = f;
+        each_node(self, &mut f)
+    }
     fn find(&self, id: PipelineId) -> Option<Rc<Page>> {
         if self.id == id { return Some(self.clone()); }
+        {
+            let page_index = self.page_index.borrow();
+            if !page_index.is_empty() {
+                return page_index.find(&id).map(|page| page.clone());
+            }
+        }
         for page in self.children.borrow().iter() {
             let found = page.find(id);
             if found.is_some() { return found; }
@@ -126,13 +492,17 @@ impl IterablePage for Rc<Page> {
 
 }
 
+/// The default number of previously loaded urls remembered by a page.
+pub static DEFAULT_URL_CACHE_CAPACITY: uint = 16;
+
 impl Page {
     pub fn new(id: PipelineId, subpage_id: Option<SubpageId>,
            layout_chan: LayoutChan,
            window_size: WindowSizeData,
            resource_task: ResourceTask,
            constellation_chan: ConstellationChan,
-           js_context: Rc<Cx>) -> Page {
+           js_context: Rc<Cx>,
+           url_cache_capacity: uint) -> Page {
         let js_info = JSPageInfo {
             dom_static: GlobalStaticData(),
             js_context: js_context,
@@ -145,72 +515,447 @@ impl Page {
         };
         Page {
             id: id,
-            subpage_id: subpage_id,
+            subpage_id: Cell::new(subpage_id),
             frame: DOMRefCell::new(None),
             layout_chan: layout_chan,
             layout_rpc: layout_rpc,
             layout_join_port: DOMRefCell::new(None),
+            layout_state: Cell::new(Idle),
+            last_reflow_duration: Cell::new(Duration::zero()),
             window_size: Cell::new(window_size),
             js_info: DOMRefCell::new(Some(js_info)),
             url: DOMRefCell::new(None),
+            url_history: DOMRefCell::new(vec!()),
+            url_cache_capacity: url_cache_capacity,
             next_subpage_id: Cell::new(SubpageId(0)),
+            free_subpage_ids: DOMRefCell::new(vec!()),
+            last_reflow_window_size: Cell::new(None),
             resize_event: Cell::new(None),
             pending_dirty_nodes: DOMRefCell::new(SmallVec1::new()),
             fragment_name: DOMRefCell::new(None),
+            scroll_offset: Cell::new(Point2D(Au(0), Au(0))),
             last_reflow_id: Cell::new(0),
+            last_reflow_goal: Cell::new(ReflowForDisplay),
             resource_task: resource_task,
             constellation_chan: constellation_chan,
             children: DOMRefCell::new(vec!()),
+            parent: DOMRefCell::new(None),
+            page_index: DOMRefCell::new(HashMap::new()),
+            max_frame_depth: Cell::new(DEFAULT_MAX_FRAME_DEPTH),
             damaged: Cell::new(false),
+            image_damaged: Cell::new(false),
+            content_box_cache: DOMRefCell::new(ContentBoxCache {
+                reflow_id: 0,
+                boxes: vec!(),
+            }),
+            mouse_over_cache: DOMRefCell::new(None),
+            mouse_move_hysteresis: Cell::new(DEFAULT_MOUSE_MOVE_HYSTERESIS),
             pending_reflows: Cell::new(0),
+            pending_idle_reflow: Cell::new(None),
             avoided_reflows: Cell::new(0),
+            suppress_reflows: Cell::new(false),
+            total_reflows: Cell::new(0),
+            total_avoided: Cell::new(0),
+            pending_completion_chans: DOMRefCell::new(vec!()),
         }
     }
 
-    pub fn flush_layout(&self, query: ReflowQueryType) {
-        // If we are damaged, we need to force a full reflow, so that queries interact with
-        // an accurate flow tree.
-        let (reflow_goal, force_reflow) = if self.damaged.get() {
-            (ReflowForDisplay, true)
-        } else {
-            match query {
-                ContentBoxQuery(_) | ContentBoxesQuery(_) => (ReflowForScriptQuery, true),
-                NoQuery => (ReflowForDisplay, false),
+    pub fn flush_layout(&self, query: ReflowQueryType, reason: ReflowReason) {
+        match flush_reflow_goal(self.is_damaged(), self.image_damaged.get(), &query) {
+            Some(reflow_goal) => {
+                let frame = self.frame();
+                let window = frame.as_ref().unwrap().window.root();
+                self.reflow(reflow_goal,
+                            reason,
+                            window.control_chan().clone(),
+                            &mut **window.compositor(),
+                            query,
+                            None,
+                            None);
+            }
+            None => {
+                self.avoided_reflows.set(self.avoided_reflows.get() + 1);
+                self.total_avoided.set(self.total_avoided.get() + 1);
             }
-        };
-
-        if force_reflow {
-            let frame = self.frame();
-            let window = frame.as_ref().unwrap().window.root();
-            self.reflow(reflow_goal, window.control_chan().clone(), &mut **window.compositor(), query);
-        } else {
-            self.avoided_reflows.set(self.avoided_reflows.get() + 1);
         }
     }
 
-     pub fn layout(&self) -> &LayoutRPC {
-        self.flush_layout(NoQuery);
-        self.join_layout(); //FIXME: is this necessary, or is layout_rpc's mutex good enough?
+    /// Flushes layout and returns the RPC interface to it, or an error if the layout task died.
+    pub fn layout(&self) -> Result<&LayoutRPC, LayoutJoinError> {
+        self.query_layout(NoQuery)
+    }
+
+    /// Flushes layout for the given query and waits for layout to finish, so that the response
+    /// can be read from the returned RPC interface.
+    fn query_layout(&self, query: ReflowQueryType) -> Result<&LayoutRPC, LayoutJoinError> {
+        self.flush_layout(query, ScriptQuery);
+        try!(self.join_layout()); //FIXME: is this necessary, or is layout_rpc's mutex good enough?
         let layout_rpc: &LayoutRPC = &*self.layout_rpc;
-        layout_rpc
+        Ok(layout_rpc)
     }
 
     pub fn content_box_query(&self, content_box_request: TrustedNodeAddress) -> Rect<Au> {
-        self.flush_layout(ContentBoxQuery(content_box_request));
-        self.join_layout(); //FIXME: is this necessary, or is layout_rpc's mutex good enough?
-        let ContentBoxResponse(rect) = self.layout_rpc.content_box();
-        rect
+        match self.query_layout(ContentBoxQuery(content_box_request)) {
+            Ok(layout_rpc) => {
+                let ContentBoxResponse(rect) = layout_rpc.content_box();
+                let mut cache = self.content_box_cache.borrow_mut();
+                if cache.reflow_id != self.last_reflow_id.get() {
+                    cache.reflow_id = self.last_reflow_id.get();
+                    cache.boxes.clear();
+                }
+                cache_value(&mut cache.boxes,
+                            content_box_request,
+                            rect,
+                            CONTENT_BOX_CACHE_CAPACITY);
+                rect
+            }
+            Err(error) => {
+                error!("script: content box query failed: {:?}", error);
+                Rect::zero()
+            }
+        }
+    }
+
+    /// Returns the content box of the given node as last computed by layout, without forcing a
+    /// reflow unless the page is damaged or has been reflowed since. This suits callers that
+    /// query the same nodes repeatedly, like animations that poll every frame. Returns `None` if
+    /// the content box of the node hasn't been computed since the last reflow.
+    pub fn content_box_cached(&self, node: TrustedNodeAddress) -> Option<Rect<Au>> {
+        {
+            let cache = self.content_box_cache.borrow();
+            if !self.is_damaged() && cache.reflow_id == self.last_reflow_id.get() {
+                return cached_value(&cache.boxes, &node)
+            }
+        }
+        Some(self.content_box_query(node))
+    }
+
+    /// Returns the content boxes of the given node, each with the index of its fragment.
+    pub fn content_boxes_query(&self, content_boxes_request: TrustedNodeAddress)
+                               -> Vec<(uint, Rect<Au>)> {
+        match self.query_layout(ContentBoxesQuery(content_boxes_request)) {
+            Ok(layout_rpc) => {
+                let ContentBoxesResponse(rects) = layout_rpc.content_boxes();
+                rects
+            }
+            Err(error) => {
+                error!("script: content boxes query failed: {:?}", error);
+                vec!()
+            }
+        }
+    }
+
+    /// Translates `rect` from page coordinates into coordinates relative to the viewport.
+    fn to_viewport_rect(&self, rect: Rect<Au>) -> Rect<Au> {
+        let scroll_offset = self.scroll_offset.get();
+        Rect(rect.origin - scroll_offset, rect.size)
+    }
+
+    /// Returns the border box of the given node relative to the viewport, as used by
+    /// `getBoundingClientRect()`.
+    pub fn client_rect_query(&self, client_rect_request: TrustedNodeAddress) -> Rect<Au> {
+        match self.query_layout(ClientRectQuery(client_rect_request)) {
+            Ok(layout_rpc) => {
+                let ClientRectResponse(rect) = layout_rpc.client_rect();
+                self.to_viewport_rect(rect)
+            }
+            Err(error) => {
+                error!("script: client rect query failed: {:?}", error);
+                Rect::zero()
+            }
+        }
     }
 
-    pub fn content_boxes_query(&self, content_boxes_request: TrustedNodeAddress) -> Vec<Rect<Au>> {
-        self.flush_layout(ContentBoxesQuery(content_boxes_request));
-        self.join_layout(); //FIXME: is this necessary, or is layout_rpc's mutex good enough?
-        let ContentBoxesResponse(rects) = self.layout_rpc.content_boxes();
-        rects
+    /// Returns the resolved value of `property` for the given node, as used by
+    /// `getComputedStyle()`. Returns an empty string if the node has no associated flow.
+    pub fn resolved_style_query(&self, node: TrustedNodeAddress, property: PropertyId)
+                                -> DOMString {
+        match self.query_layout(ResolvedStyleQuery(node, property)) {
+            Ok(layout_rpc) => {
+                let ResolvedStyleResponse(value) = layout_rpc.resolved_style();
+                value.unwrap_or(String::new())
+            }
+            Err(error) => {
+                error!("script: resolved style query failed: {:?}", error);
+                String::new()
+            }
+        }
+    }
+
+    /// Returns the size of the given node's scrolling box, as used by `scrollWidth` and
+    /// `scrollHeight`. This is the border box size if the node has no scrollable overflow.
+    pub fn scroll_dimensions_query(&self, node: TrustedNodeAddress) -> Size2D<Au> {
+        match self.query_layout(ScrollDimensionsQuery(node)) {
+            Ok(layout_rpc) => {
+                let ScrollDimensionsResponse(size) = layout_rpc.scroll_dimensions();
+                size
+            }
+            Err(error) => {
+                error!("script: scroll dimensions query failed: {:?}", error);
+                Size2D(Au(0), Au(0))
+            }
+        }
+    }
+
+    /// Returns the union of the glyph boxes of the characters from `start` up to but not
+    /// including `end` of the given text node, relative to the viewport, as used by
+    /// `Range.getBoundingClientRect()`. A collapsed range has an empty box.
+    pub fn text_range_box_query(&self, node: TrustedNodeAddress, start: uint, end: uint)
+                                -> Rect<Au> {
+        match self.query_layout(TextRangeBoxQuery(node, start, end)) {
+            Ok(layout_rpc) => {
+                let TextRangeBoxResponse(rect) = layout_rpc.text_range_box();
+                if rect.is_empty() {
+                    return Rect::zero()
+                }
+                self.to_viewport_rect(rect)
+            }
+            Err(error) => {
+                error!("script: text range box query failed: {:?}", error);
+                Rect::zero()
+            }
+        }
+    }
+
+    /// Returns the offset parent of the given node together with the node's border box relative
+    /// to it, as used by `offsetParent`, `offsetTop` and `offsetLeft`.
+    pub fn offset_parent_query(&self, node: TrustedNodeAddress) -> OffsetParentResponse {
+        match self.query_layout(OffsetParentQuery(node)) {
+            Ok(layout_rpc) => layout_rpc.offset_parent(),
+            Err(error) => {
+                error!("script: offset parent query failed: {:?}", error);
+                OffsetParentResponse::empty()
+            }
+        }
+    }
+
+    /// Returns the nearest ancestor of the given node that scrolls its overflowing content, or
+    /// `None` if the node is scrolled by the viewport.
+    pub fn scroll_container_query(&self, node: TrustedNodeAddress)
+                                  -> Option<UntrustedNodeAddress> {
+        match self.query_layout(ScrollContainerQuery(node)) {
+            Ok(layout_rpc) => {
+                let ScrollContainerResponse(container) = layout_rpc.scroll_container();
+                container
+            }
+            Err(error) => {
+                error!("script: scroll container query failed: {:?}", error);
+                None
+            }
+        }
+    }
+
+    /// Performs all of `queries` with a single reflow. The i-th response answers the i-th query;
+    /// client rects are translated into viewport coordinates as by `client_rect_query`. Returns
+    /// no responses at all if the layout task failed.
+    pub fn batch_query(&self, queries: Vec<ReflowQueryType>) -> Vec<QueryResponse> {
+        match self.query_layout(BatchQuery(queries)) {
+            Ok(layout_rpc) => {
+                let BatchQueryResponse(responses) = layout_rpc.batch_query();
+                responses.into_iter().map(|response| {
+                    match response {
+                        ClientRectQueryResponse(rect) => {
+                            ClientRectQueryResponse(self.to_viewport_rect(rect))
+                        }
+                        response => response,
+                    }
+                }).collect()
+            }
+            Err(error) => {
+                error!("script: batch query failed: {:?}", error);
+                vec!()
+            }
+        }
+    }
+
+    /// Scrolls the viewport so that the given node is placed in it as `align` asks, as for
+    /// `scrollIntoView()`. Does nothing if the node has no boxes.
+    pub fn scroll_to_node(&self,
+                          node: TrustedNodeAddress,
+                          align: ScrollAlign,
+                          compositor: &mut ScriptListener) {
+        let boxes = self.content_boxes_query(node);
+        let mut rects = boxes.iter().map(|&(_, rect)| rect);
+        let rect = match rects.next() {
+            None => {
+                debug!("script: not scrolling to a node without boxes");
+                return
+            }
+            Some(first) => rects.fold(first, |union, rect| union.union(&rect)),
+        };
+
+        let current = self.scroll_offset.get();
+        let viewport = self.window_size.get().visible_viewport;
+        let target = Point2D(scroll_target(current.x,
+                                           Au::from_frac32_px(viewport.width.get()),
+                                           rect.origin.x,
+                                           rect.size.width,
+                                           align),
+                             scroll_target(current.y,
+                                           Au::from_frac32_px(viewport.height.get()),
+                                           rect.origin.y,
+                                           rect.size.height,
+                                           align));
+
+        // FIXME(#2003, pcwalton): As in `ScriptTask::scroll_fragment_point`, this assumes that
+        // the page is a single layer.
+        let point = Point2D(to_frac_px(target.x) as f32, to_frac_px(target.y) as f32);
+        compositor.scroll_fragment_point(self.id, LayerId::null(), point, false);
+    }
+
+    /// Returns true if any part of this page's document element lies within `viewport`, given in
+    /// the page's own coordinates, e.g. so that offscreen frames can be reflowed after visible
+    /// ones. Returns false if the page has no frame or no document element.
+    pub fn intersects_viewport(&self, viewport: Rect<Au>) -> bool {
+        let document = match *self.frame() {
+            None => return false,
+            Some(ref frame) => frame.document.root(),
+        };
+        let root = match document.GetDocumentElement().root() {
+            None => return false,
+            Some(root) => root,
+        };
+        let root: JSRef<Node> = NodeCast::from_ref(*root);
+        box_intersects_viewport(self.content_box_query(root.to_trusted_node_address()), viewport)
+    }
+
+    /// Adds `child` to the children of `parent`, which must be part of the page tree rooted at
+    /// this page. This keeps the root's pipeline id index up to date. Fails, leaving the tree
+    /// untouched, if any page of `child`'s subtree would end up nested too deeply.
+    pub fn add_child(&self, parent: &Rc<Page>, child: Rc<Page>) -> Result<(), PageTreeError> {
+        let parent_depth = parent.ancestors().count();
+        let subtree_height = child.iter_with_depth().map(|(_, depth)| depth).max().unwrap_or(0);
+        try!(check_frame_depth(parent_depth, subtree_height, self.max_frame_depth.get()));
+        {
+            let mut page_index = self.page_index.borrow_mut();
+            for page in child.iter() {
+                page_index.insert(page.id, page.clone());
+            }
+        }
+        *child.parent.borrow_mut() = Some(parent.downgrade());
+        parent.children.borrow_mut().push(child);
+        Ok(())
+    }
+
+    /// Sets how many frames deep pages may be nested below this page, which must be the root of
+    /// its page tree. Pages that are already nested more deeply are kept.
+    pub fn set_max_frame_depth(&self, max_frame_depth: uint) {
+        self.max_frame_depth.set(max_frame_depth);
+    }
+
+    /// Returns the page containing this one, if any.
+    pub fn parent(&self) -> Option<Rc<Page>> {
+        self.parent.borrow().as_ref().and_then(|parent| parent.upgrade())
+    }
+
+    /// Returns the pipeline ids of the pages from the root of the page tree down to this one,
+    /// e.g. for logging which frame something happened in.
+    pub fn pipeline_path(&self) -> Vec<PipelineId> {
+        let ancestor_ids = self.parent().into_iter().flat_map(|parent| {
+            Some(parent.id).into_iter().chain(parent.ancestors().map(|page| page.id))
+        });
+        path_from_root(self.id, ancestor_ids)
     }
 
-    // must handle root case separately
+    /// Detaches the page with the given id from this page's descendants. The returned page still
+    /// owns its children, so the whole detached subtree can be walked (see
+    /// `descendant_pipeline_ids`).
+    ///
+    /// This must handle the root case separately.
     pub fn remove(&self, id: PipelineId) -> Option<Rc<Page>> {
+        let removed = self.remove_from_children(id);
+        match removed {
+            Some(ref removed) => {
+                let mut page_index = self.page_index.borrow_mut();
+                for page in removed.iter() {
+                    page_index.remove(&page.id);
+                }
+            }
+            None => {}
+        }
+        removed
+    }
+
+    /// Like `remove`, for a page whose frame the constellation has dropped: the subpage id that
+    /// the page had in its parent is also released to the parent (see `release_subpage_id`), and
+    /// returned along with the page.
+    pub fn remove_and_release(&self, id: PipelineId) -> Option<(Rc<Page>, Option<SubpageId>)> {
+        let parent = self.find(id).and_then(|page| page.parent());
+        let removed = with_subpage_id(self.remove(id));
+        match (&removed, parent) {
+            (&Some((_, Some(subpage_id))), Some(parent)) => parent.release_subpage_id(subpage_id),
+            _ => {}
+        }
+        removed
+    }
+
+    /// Removes this page from its page tree and makes it the root of its own, e.g. when an
+    /// iframe is promoted to a top-level browsing context. Its subpage id is cleared and its
+    /// parent pointer is severed; pages that are already roots only lose their subpage id. The
+    /// caller must keep its own reference to the page, since the old parent no longer holds one.
+    pub fn detach_as_root(&self) {
+        let _detached = match self.parent() {
+            Some(parent) => {
+                let root = parent.ancestors().last().unwrap_or(parent);
+                root.remove(self.id)
+            }
+            None => None,
+        };
+        self.subpage_id.set(None);
+
+        // Only roots maintain a pipeline id index, so build one for the detached subtree.
+        let mut page_index = self.page_index.borrow_mut();
+        page_index.clear();
+        for child in self.children.borrow().iter() {
+            for page in child.iter() {
+                page_index.insert(page.id, page.clone());
+            }
+        }
+    }
+
+    /// Calls `f` on this page and then on each of its descendants, parents before children, until
+    /// `f` returns false, e.g. to find the first frame that matches something. `f` must not add
+    /// or remove pages.
+    pub fn for_each_descendant(&self, f: |&Page| -> bool) {
+        let mut f = f;
+        if !f(self) {
+            return
+        }
+        for child in self.children.borrow().iter() {
+            if !each_node_while(child, &mut |page: &Rc<Page>| f(&**page)) {
+                return
+            }
+        }
+    }
+
+    /// Returns the descendant of this page with the given subpage id, e.g. to route a message
+    /// about an iframe. The direct children of this page are searched first, since subpage ids
+    /// are only unique among the children of one page.
+    pub fn find_by_subpage(&self, subpage: SubpageId) -> Option<Rc<Page>> {
+        find_by_subpage_id(self.children.borrow().as_slice(), subpage)
+    }
+
+    /// Returns the number of direct children of this page.
+    pub fn child_count(&self) -> uint {
+        self.children.borrow().len()
+    }
+
+    /// Returns the number of pages in the tree rooted at this page, including this page.
+    pub fn total_page_count(&self) -> uint {
+        self.children.borrow().iter().fold(1, |count, child| count + child.iter().count())
+    }
+
+    /// Returns the pipeline ids of all of this page's descendants, excluding this page. Each page
+    /// comes before its own children.
+    pub fn descendant_pipeline_ids(&self) -> Vec<PipelineId> {
+        let mut ids = vec!();
+        for child in self.children.borrow().iter() {
+            ids.extend(child.iter().map(|page| page.id));
+        }
+        ids
+    }
+
+    fn remove_from_children(&self, id: PipelineId) -> Option<Rc<Page>> {
         let remove_idx = {
             self.children
                 .borrow_mut()
@@ -224,10 +969,14 @@ impl Page {
                 .map(|(idx, _)| idx)
         };
         match remove_idx {
-            Some(idx) => return Some(self.children.borrow_mut().remove(idx).unwrap()),
+            Some(idx) => {
+                let removed = self.children.borrow_mut().remove(idx).unwrap();
+                *removed.parent.borrow_mut() = None;
+                return Some(removed)
+            }
             None => {
                 for page_tree in self.children.borrow_mut().iter_mut() {
-                    match page_tree.remove(id) {
+                    match page_tree.remove_from_children(id) {
                         found @ Some(_) => return found,
                         None => (), // keep going...
                     }
@@ -240,18 +989,53 @@ impl Page {
 
 impl Iterator<Rc<Page>> for PageIterator {
     fn next(&mut self) -> Option<Rc<Page>> {
-        if !self.stack.is_empty() {
-            let next = self.stack.pop().unwrap();
-            for child in next.children.borrow().iter() {
-                self.stack.push(child.clone());
+        next_depth_first(&mut self.stack)
+    }
+}
+
+impl Iterator<Rc<Page>> for DamagedPageIterator {
+    fn next(&mut self) -> Option<Rc<Page>> {
+        self.iter.find(|page| page.is_damaged())
+    }
+}
+
+impl Iterator<Rc<Page>> for BreadthFirstPageIterator {
+    fn next(&mut self) -> Option<Rc<Page>> {
+        let next = self.queue.pop_front();
+        match next {
+            Some(ref page) => {
+                for child in page.children.borrow().iter() {
+                    self.queue.push_back(child.clone());
+                }
+            }
+            None => {}
+        }
+        next
+    }
+}
+
+impl Iterator<(Rc<Page>, uint)> for DepthPageIterator {
+    fn next(&mut self) -> Option<(Rc<Page>, uint)> {
+        match self.stack.pop() {
+            Some((next, depth)) => {
+                for child in next.children.borrow().iter() {
+                    self.stack.push((child.clone(), depth + 1));
+                }
+                Some((next, depth))
             }
-            Some(next.clone())
-        } else {
-            None
+            None => None,
         }
     }
 }
 
+impl Iterator<Rc<Page>> for AncestorPageIterator {
+    fn next(&mut self) -> Option<Rc<Page>> {
+        let next = self.current.take();
+        self.current = next.as_ref().and_then(|page| page.parent());
+        next
+    }
+}
+
 impl Page {
     pub fn mut_js_info<'a>(&'a self) -> RefMut<'a, Option<JSPageInfo>> {
         self.js_info.borrow_mut()
@@ -269,6 +1053,37 @@ impl Page {
         self.url.borrow_mut()
     }
 
+    /// Makes `url` the most recent url of this page. The previous one is remembered in the url
+    /// history, evicting the least recently used entry if the history is full. The current url
+    /// is never part of the history.
+    pub fn set_url(&self, url: Url, needs_reflow: bool) {
+        let mut url_history = self.url_history.borrow_mut();
+        match url_history.iter().position(|&(ref cached, _)| *cached == url) {
+            Some(index) => {
+                url_history.remove(index);
+            }
+            None => {}
+        }
+
+        let previous = replace(&mut *self.url.borrow_mut(), Some((url.clone(), needs_reflow)));
+        match previous {
+            Some((previous_url, previous_needs_reflow)) if previous_url != url => {
+                if url_history.len() >= self.url_cache_capacity && !url_history.is_empty() {
+                    url_history.remove(0);
+                }
+                if self.url_cache_capacity > 0 {
+                    url_history.push((previous_url, previous_needs_reflow));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the urls previously loaded by this page, least recently used first.
+    pub fn url_history<'a>(&'a self) -> Ref<'a, Vec<(Url, bool)>> {
+        self.url_history.borrow()
+    }
+
     pub fn frame<'a>(&'a self) -> Ref<'a, Option<Frame>> {
         self.frame.borrow()
     }
@@ -277,134 +1092,418 @@ impl Page {
         self.frame.borrow_mut()
     }
 
+    /// Returns a subpage id for a new child, reusing one released by `release_subpage_id` if
+    /// possible.
     pub fn get_next_subpage_id(&self) -> SubpageId {
-        let subpage_id = self.next_subpage_id.get();
-        let SubpageId(id_num) = subpage_id;
-        self.next_subpage_id.set(SubpageId(id_num + 1));
-        subpage_id
+        allocate_subpage_id(&self.next_subpage_id, &mut *self.free_subpage_ids.borrow_mut())
+    }
+
+    /// Frees the subpage id of a child frame of this page once the constellation has dropped the
+    /// frame, so that `get_next_subpage_id` can hand it out again. An iframe keeps its subpage id
+    /// when the page inside it is replaced, so the id stays taken while a child page has it.
+    pub fn release_subpage_id(&self, subpage_id: SubpageId) {
+        if self.children.borrow().iter().any(|child| child.subpage_id.get() == Some(subpage_id)) {
+            return
+        }
+        let mut free_subpage_ids = self.free_subpage_ids.borrow_mut();
+        if !free_subpage_ids.contains(&subpage_id) {
+            free_subpage_ids.push(subpage_id);
+        }
     }
 
     pub fn get_url(&self) -> Url {
         self.url().as_ref().unwrap().ref0().clone()
     }
 
-    // FIXME(cgaebel): join_layout is racey. What if the compositor triggers a
-    // reflow between the "join complete" message and returning from this
-    // function?
+    /// Asks the script task to load the current url of this page again, in this same pipeline.
+    /// The cached layout is then reused unless the url was marked as needing a reflow, in which
+    /// case a full reflow is forced (see `take_cached_reload`). Going through the constellation
+    /// instead would load the url into a new pipeline, with none of this page's layout.
+    pub fn reload(&self, script_chan: &ScriptChan) {
+        let ScriptChan(ref chan) = *script_chan;
+        chan.send(ReloadMsg(self.id));
+    }
 
-    /// Sends a ping to layout and waits for the response. The response will arrive when the
-    /// layout task has finished any pending request messages.
-    pub fn join_layout(&self) {
-        let mut layout_join_port = self.layout_join_port.borrow_mut();
-        if layout_join_port.is_some() {
-            let join_port = replace(&mut *layout_join_port, None);
-            match join_port {
-                Some(ref join_port) => {
-                    match join_port.try_recv() {
-                        Err(Empty) => {
-                            info!("script: waiting on layout");
-                            join_port.recv();
-                        }
-                        Ok(_) => {}
-                        Err(Disconnected) => {
-                            fail!("Layout task failed while script was waiting for a result.");
-                        }
-                    }
+    /// If `url` is the cached url of this page, clears its reflow-needed bit and returns whether
+    /// it was set. Returns `None` if the page has to be loaded from scratch instead.
+    pub fn take_cached_reload(&self, url: &Url) -> Option<bool> {
+        take_cached_reload(&mut *self.url.borrow_mut(), url)
+    }
 
-                    debug!("script: layout joined")
-                }
-                None => fail!("reader forked but no join port?"),
-            }
+    /// Returns whether layout is running a reflow for this page, and which one.
+    pub fn layout_state(&self) -> LayoutState {
+        self.layout_state.get()
+    }
+
+    /// Returns true if a reflow has been sent to layout and not joined yet. Unlike `join_layout`,
+    /// this doesn't wait for layout or consume the join port.
+    pub fn is_layout_busy(&self) -> bool {
+        self.layout_state.get() != Idle
+    }
+
+    /// Records that layout has finished the reflow with the given id. Layout is only idle once
+    /// it has finished the latest reflow sent to it.
+    pub fn layout_complete(&self, reflow_id: uint) {
+        if self.layout_state.get() == (Running { reflow_id: reflow_id }) {
+            // Layout signals the join port before reporting completion, so this doesn't block.
+            let _ = self.join_layout();
         }
     }
 
+    /// Asks layout to abandon the outstanding reflow, if it has not started working on it yet,
+    /// because a newer reflow is about to supersede it. Layout still notifies the join port of a
+    /// cancelled reflow, so `join_layout` treats it like a completed one.
+    ///
+    /// Like `join_layout`, this races with reflows triggered by the compositor: if one is sent
+    /// between the reflow being cancelled and script issuing its own reflow, the compositor's
+    /// reflow is the one that ends up being cancelled (it is keyed by `last_reflow_id`).
+    pub fn cancel_reflow(&self) {
+        if !self.is_layout_busy() {
+            return;
+        }
+        debug!("script: cancelling reflow {:u}", self.last_reflow_id.get());
+        let LayoutChan(ref chan) = self.layout_chan;
+        chan.send(CancelReflowMsg(self.last_reflow_id.get()));
+    }
+
+    // FIXME(cgaebel): join_layout is racey. What if the compositor triggers a
+    // reflow between the "join complete" message and returning from this
+    // function? The same race applies to `cancel_reflow`.
+
+    /// Sends a ping to layout and waits for the response. The response will arrive when the
+    /// layout task has finished any pending request messages, or has cancelled the outstanding
+    /// reflow.
+    ///
+    /// Returns an error if the layout task failed while script was waiting for it. The layout
+    /// task reports its own failure to the constellation, so callers only need to stop relying
+    /// on layout for this page.
+    pub fn join_layout(&self) -> Result<(), LayoutJoinError> {
+        wait_for_join_port(&mut *self.layout_join_port.borrow_mut(),
+                           &self.layout_state,
+                           &self.last_reflow_duration)
+    }
+
+    /// Returns how long layout spent on the last reflow that script joined, or zero if no reflow
+    /// has completed yet.
+    pub fn last_reflow_duration(&self) -> Duration {
+        self.last_reflow_duration.get()
+    }
+
     /// Reflows the page if it's possible to do so. This method will wait until the layout task has
     /// completed its current action, join the layout task, and then request a new layout run. It
     /// won't wait for the new layout computation to finish.
     ///
+    /// If layout is still running a reflow for the same goal and no query is requested, the new
+    /// request is merged into the outstanding one: it is counted in `pending_reflows`, and the
+    /// script task performs a single fresh reflow, flushing all the dirty nodes accumulated in
+    /// the meantime, once layout has joined.
+    ///
     /// If there is no window size yet, the page is presumed invisible and no reflow is performed.
+    /// Nor is one while `suppress_reflows` is set, as the page is being navigated away from; the
+    /// skipped reflow is counted in `avoided_reflows`.
+    ///
+    /// If `completion_chan` is given, it is sent the id of the reflow that lays out the current
+    /// changes once layout has finished or cancelled it. For a coalesced request, that is the
+    /// id of the reflow that is sent once layout has joined.
+    ///
+    /// If `clip` is given, layout only paints the part of the page within it, e.g. for a frame
+    /// that is partly offscreen. Otherwise the whole viewport is painted.
     ///
     /// This function fails if there is no root frame.
     pub fn reflow(&self,
                   goal: ReflowGoal,
+                  reason: ReflowReason,
                   script_chan: ScriptControlChan,
                   compositor: &mut ScriptListener,
-                  query_type: ReflowQueryType) {
-        let root = match *self.frame() {
-            None => return,
+                  query_type: ReflowQueryType,
+                  completion_chan: Option<Sender<uint>>,
+                  clip: Option<Rect<Au>>) {
+        match self.send_reflow(goal, reason, script_chan, compositor, query_type,
+                               completion_chan, clip, false) {
+            Ok(_) => {}
+            Err(error) => error!("script: not reflowing pipeline {:?}: {:?}", self.id, error),
+        }
+    }
+
+    /// Does the work of `reflow`, and returns the id of the reflow sent to layout, or `None` if
+    /// none was. A `sync` reflow is never coalesced into the one in flight or suppressed, since
+    /// its caller needs the layout of the current changes. Returns an error, without sending a
+    /// reflow, if the layout task failed while it was joined.
+    fn send_reflow(&self,
+                   goal: ReflowGoal,
+                   reason: ReflowReason,
+                   script_chan: ScriptControlChan,
+                   compositor: &mut ScriptListener,
+                   query_type: ReflowQueryType,
+                   completion_chan: Option<Sender<uint>>,
+                   clip: Option<Rect<Au>>,
+                   sync: bool)
+                   -> Result<Option<uint>, LayoutJoinError> {
+        if !sync && skip_suppressed_reflow(self.suppress_reflows.get(),
+                                           &self.avoided_reflows,
+                                           &self.total_avoided) {
+            debug!("script: not reflowing pipeline {:?} while navigating away", self.id);
+            match completion_chan {
+                Some(chan) => self.pending_completion_chans.borrow_mut().push(chan),
+                None => {}
+            }
+            return Ok(None)
+        }
+
+        let document_element = match *self.frame() {
+            None => return Ok(None),
             Some(ref frame) => {
                 frame.document.root().GetDocumentElement()
             }
         };
 
-        match root.root() {
-            None => {},
-            Some(root) => {
-                debug!("avoided {:d} reflows", self.avoided_reflows.get());
-                self.avoided_reflows.set(0);
+        // Without a document element there is nothing to lay out yet. Everything is left as it
+        // is, `damaged` included, so that the page is reflowed once it has content; a completion
+        // channel waits for that reflow.
+        let root = match document_element.root() {
+            None => {
+                debug!("script: not reflowing pipeline {:?} without a document element", self.id);
+                match completion_chan {
+                    Some(chan) => self.pending_completion_chans.borrow_mut().push(chan),
+                    None => {}
+                }
+                return Ok(None)
+            }
+            Some(root) => root,
+        };
 
-                debug!("script: performing reflow for goal {:?}", goal);
+        let has_query = match query_type {
+            NoQuery => false,
+            _ => true,
+        };
+        if !sync && !has_query && self.is_layout_busy() && self.last_reflow_goal.get() == goal {
+            debug!("script: coalescing reflow for goal {:?} into reflow {:u}",
+                   goal, self.last_reflow_id.get());
+            self.pending_reflows.set(self.pending_reflows.get() + 1);
+            match completion_chan {
+                Some(chan) => self.pending_completion_chans.borrow_mut().push(chan),
+                None => {}
+            }
+            return Ok(None)
+        }
+
+        // This reflow lays out everything that a pending idle reflow would have.
+        self.pending_idle_reflow.set(None);
+
+        debug!("avoided {:d} reflows", self.avoided_reflows.get());
+        self.avoided_reflows.set(0);
 
-                // Now, join the layout so that they will see the latest changes we have made.
-                self.join_layout();
+        debug!("script: performing reflow for goal {:?} because of {:?}", goal, reason);
 
-                // Tell the user that we're performing layout.
-                compositor.set_ready_state(self.id, PerformingLayout);
+        // A repaint for image updates that layout hasn't started yet is made redundant by a
+        // reflow for display, so layout may skip straight to this one.
+        if reflow_supersedes(self.last_reflow_goal.get(), goal) {
+            self.cancel_reflow();
+        }
+
+        // Now, join the layout so that they will see the latest changes we have made.
+        try!(self.join_layout());
 
-                // Layout will let us know when it's done.
-                let (join_chan, join_port) = channel();
-                let mut layout_join_port = self.layout_join_port.borrow_mut();
-                *layout_join_port = Some(join_port);
+        // Tell the user that we're performing layout.
+        compositor.set_ready_state(self.id, PerformingLayout);
 
-                let last_reflow_id = &self.last_reflow_id;
-                last_reflow_id.set(last_reflow_id.get() + 1);
+        // Layout will let us know when it's done.
+        let (join_chan, join_port) = channel();
+        let mut layout_join_port = self.layout_join_port.borrow_mut();
+        *layout_join_port = Some(join_port);
+
+        let last_reflow_id = &self.last_reflow_id;
+        last_reflow_id.set(last_reflow_id.get() + 1);
+        self.last_reflow_goal.set(goal);
+        self.layout_state.set(Running { reflow_id: last_reflow_id.get() });
+
+        let root: JSRef<Node> = NodeCast::from_ref(*root);
+
+        // The rasterization scale affects every glyph, so a device pixel ratio change
+        // can't be handled incrementally like a logical resize can.
+        let window_size = self.window_size.get();
+        let needs_full_reflow = resize_needs_full_reflow(self.last_reflow_window_size.get(),
+                                                         window_size);
+        self.last_reflow_window_size.set(Some(window_size));
+        self.damaged.set(false);
+        if goal != ReflowForScriptQuery {
+            self.image_damaged.set(false);
+        }
+
+        // Send new document and relevant styles to layout.
+        let reflow = box Reflow {
+            document_root: root.to_trusted_node_address(),
+            url: self.get_url(),
+            iframe: self.subpage_id.get().is_some(),
+            goal: goal,
+            priority: goal.priority(),
+            reason: reason,
+            window_size: window_size,
+            needs_full_reflow: needs_full_reflow,
+            clip: reflow_clip(clip, window_size),
+            script_chan: script_chan,
+            script_join_chan: join_chan,
+            completion_chans: take_completion_chans(
+                &mut *self.pending_completion_chans.borrow_mut(), completion_chan),
+            id: last_reflow_id.get(),
+            query_type: query_type,
+        };
+
+        let LayoutChan(ref chan) = self.layout_chan;
+        chan.send(ReflowMsg(reflow));
+        self.total_reflows.set(self.total_reflows.get() + 1);
+
+        debug!("script: layout forked");
+        Ok(Some(last_reflow_id.get()))
+    }
+
+    /// Reflows the page like `reflow`, then waits for that layout run to finish, so that its
+    /// results can be used straight away. The reflow is sent even if layout is busy or reflows
+    /// are suppressed. Returns whether the current changes were laid out, which they aren't if
+    /// the page has no document element yet, or an error if the layout task failed.
+    ///
+    /// The join port only ever belongs to the latest reflow, so once joined, the reflow id is
+    /// checked against the one that was sent: if another reflow was started in the meantime (see
+    /// the FIXME on `join_layout`), that one, which includes the same changes, is waited for too.
+    pub fn reflow_sync(&self,
+                       goal: ReflowGoal,
+                       reason: ReflowReason,
+                       script_chan: ScriptControlChan,
+                       compositor: &mut ScriptListener,
+                       query_type: ReflowQueryType)
+                       -> Result<bool, LayoutJoinError> {
+        let reflow_id = match try!(self.send_reflow(goal, reason, script_chan, compositor,
+                                                    query_type, None, None, true)) {
+            Some(reflow_id) => reflow_id,
+            None => return Ok(false),
+        };
+        loop {
+            let latest_reflow_id = self.last_reflow_id.get();
+            try!(self.join_layout());
+            if self.last_reflow_id.get() == latest_reflow_id {
+                return Ok(true)
+            }
+            debug!("script: reflow {:u} superseded while joining layout", reflow_id);
+        }
+    }
 
-                let root: JSRef<Node> = NodeCast::from_ref(*root);
+    /// Asks for a low-priority reflow, e.g. for style changes to offscreen content, that must not
+    /// hold up interactive work. It is performed right away if layout is idle and no other reflow
+    /// is pending. Otherwise it waits for layout to be quiet (see `take_idle_reflow`), and is
+    /// dropped if another reflow is performed first, since that one lays out the same changes.
+    /// Idle reflows coalesce with each other, keeping the first reason.
+    pub fn reflow_idle(&self,
+                       reason: ReflowReason,
+                       script_chan: ScriptControlChan,
+                       compositor: &mut ScriptListener) {
+        if should_run_idle_reflow(self.layout_state.get(), self.pending_reflows.get()) {
+            self.reflow(ReflowForDisplay, reason, script_chan, compositor, NoQuery, None, None);
+        } else if self.pending_idle_reflow.get().is_none() {
+            debug!("script: deferring idle reflow for {:?}", reason);
+            self.pending_idle_reflow.set(Some(reason));
+        }
+    }
 
-                let window_size = self.window_size.get();
-                self.damaged.set(false);
+    /// Takes the reason for the pending idle reflow, if there is one and layout is now quiet
+    /// enough to perform it.
+    pub fn take_idle_reflow(&self) -> Option<ReflowReason> {
+        if !should_run_idle_reflow(self.layout_state.get(), self.pending_reflows.get()) {
+            return None
+        }
+        let reason = self.pending_idle_reflow.get();
+        self.pending_idle_reflow.set(None);
+        reason
+    }
 
-                // Send new document and relevant styles to layout.
-                let reflow = box Reflow {
-                    document_root: root.to_trusted_node_address(),
-                    url: self.get_url(),
-                    iframe: self.subpage_id.is_some(),
-                    goal: goal,
-                    window_size: window_size,
-                    script_chan: script_chan,
-                    script_join_chan: join_chan,
-                    id: last_reflow_id.get(),
-                    query_type: query_type,
-                };
+    /// Takes the pending resize event, if any. Events are debounced when they are queued, so
+    /// that a size stored by `set_window_size` is not dropped here for matching the current one.
+    pub fn take_resize_event(&self) -> Option<WindowSizeData> {
+        let resize_event = self.resize_event.get();
+        self.resize_event.set(None);
+        resize_event
+    }
 
-                let LayoutChan(ref chan) = self.layout_chan;
-                chan.send(ReflowMsg(reflow));
+    /// Takes the nodes queued to be dirtied before the next reflow, leaving none pending, so
+    /// that a node is not dirtied again by a later reflow.
+    pub fn take_pending_dirty_nodes(&self) -> SmallVec1<UntrustedNodeAddress> {
+        take_dirty_nodes(&mut *self.pending_dirty_nodes.borrow_mut())
+    }
 
-                debug!("script: layout forked")
+    /// Stores `size` as the window size of this page and queues a resize event for it, unless
+    /// it is the current size. Sizes with a zero or negative dimension are rejected and leave the
+    /// page untouched.
+    pub fn set_window_size(&self, size: WindowSizeData) -> Result<(), WindowSizeError> {
+        match try!(window_size_update(self.window_size.get(), size)) {
+            Some(size) => {
+                self.window_size.set(size);
+                self.resize_event.set(Some(size));
             }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the current values of this page's reflow counters.
+    pub fn reflow_stats(&self) -> ReflowStats {
+        ReflowStats {
+            pending_reflows: self.pending_reflows.get(),
+            avoided_reflows: self.avoided_reflows.get(),
+            last_reflow_id: self.last_reflow_id.get(),
+            total_reflows: self.total_reflows.get(),
         }
     }
 
+    /// Returns the ratio of avoided reflows to reflows sent to layout over the lifetime of this
+    /// page. A low ratio means that the page reflows for most of the flushes that script asks
+    /// for.
+    pub fn reflow_efficiency(&self) -> f64 {
+        reflow_efficiency(self.total_avoided.get(), self.total_reflows.get())
+    }
+
     pub fn damage(&self) {
         self.damaged.set(true);
     }
 
-    /// Attempt to find a named element in this page's document.
+    /// Records that images finished loading into boxes whose sizes were already known, so that
+    /// the page needs painting again but no new layout.
+    pub fn damage_images(&self) {
+        self.image_damaged.set(true);
+    }
+
+    /// Whether anything on this page needs layout.
+    pub fn is_damaged(&self) -> bool {
+        self.damaged.get()
+    }
+
+    /// Attempt to find a named element in this page's document. Returns `None` if there is no
+    /// document yet.
     pub fn find_fragment_node(&self, fragid: DOMString) -> Option<Temporary<Element>> {
-        let document = self.frame().as_ref().unwrap().document.root();
+        let document = match *self.frame() {
+            None => return None,
+            Some(ref frame) => frame.document.root(),
+        };
         document.find_fragment_node(fragid)
     }
 
     pub fn hit_test(&self, point: &Point2D<f32>) -> Option<UntrustedNodeAddress> {
-        let frame = self.frame();
-        let document = frame.as_ref().unwrap().document.root();
+        let document = match *self.frame() {
+            None => return None,
+            Some(ref frame) => frame.document.root(),
+        };
         let root = document.GetDocumentElement().root();
         if root.is_none() {
             return None;
         }
         let root = root.unwrap();
         let root: JSRef<Node> = NodeCast::from_ref(*root);
-        let address = match self.layout().hit_test(root.to_trusted_node_address(), *point) {
+        let layout_rpc = match self.layout() {
+            Ok(layout_rpc) => layout_rpc,
+            Err(error) => {
+                error!("script: hit test failed: {:?}", error);
+                return None;
+            }
+        };
+        let address = match layout_rpc.hit_test(root.to_trusted_node_address(), *point) {
             Ok(HitTestResponse(node_address)) => {
                 Some(node_address)
             }
@@ -416,16 +1515,91 @@ impl Page {
         address
     }
 
+    /// Returns the topmost node under `point` among `root` and its descendants, or `None` if the
+    /// point is outside everything that subtree painted.
+    pub fn hit_test_in(&self, root: TrustedNodeAddress, point: &Point2D<f32>)
+                       -> Option<UntrustedNodeAddress> {
+        let layout_rpc = match self.layout() {
+            Ok(layout_rpc) => layout_rpc,
+            Err(error) => {
+                error!("script: subtree hit test failed: {:?}", error);
+                return None;
+            }
+        };
+        match layout_rpc.hit_test_in(root, *point) {
+            Ok(HitTestResponse(node_address)) => Some(node_address),
+            Err(()) => None,
+        }
+    }
+
+    /// Returns every node under `point`, from top to bottom, with the document element last. The
+    /// result is empty if nothing was hit.
+    pub fn hit_test_all(&self, point: &Point2D<f32>) -> Vec<UntrustedNodeAddress> {
+        let document = match *self.frame() {
+            None => return vec!(),
+            Some(ref frame) => frame.document.root(),
+        };
+        let root = match document.GetDocumentElement().root() {
+            None => return vec!(),
+            Some(root) => root,
+        };
+        let root: JSRef<Node> = NodeCast::from_ref(*root);
+        let layout_rpc = match self.layout() {
+            Ok(layout_rpc) => layout_rpc,
+            Err(error) => {
+                error!("script: hit test failed: {:?}", error);
+                return vec!();
+            }
+        };
+        match layout_rpc.hit_test_all(root.to_trusted_node_address(), *point) {
+            Ok(HitTestAllResponse(node_addresses)) => node_addresses,
+            Err(()) => vec!(),
+        }
+    }
+
+    /// Returns the nodes under the mouse at `point`. The result of the last query is reused if
+    /// `point` is within `mouse_move_hysteresis` pixels of it and neither a reflow nor damage has
+    /// happened since, so that tiny moves over the same element don't each ask layout.
     pub fn get_nodes_under_mouse(&self, point: &Point2D<f32>) -> Option<Vec<UntrustedNodeAddress>> {
-        let frame = self.frame();
-        let document = frame.as_ref().unwrap().document.root();
+        query_with_hysteresis(&mut *self.mouse_over_cache.borrow_mut(),
+                              *point,
+                              self.last_reflow_id.get(),
+                              self.is_damaged(),
+                              self.mouse_move_hysteresis.get(),
+                              || self.query_nodes_under_mouse(point))
+    }
+
+    /// Returns how far the mouse may move before `get_nodes_under_mouse` queries layout again.
+    pub fn mouse_move_hysteresis(&self) -> f32 {
+        self.mouse_move_hysteresis.get()
+    }
+
+    /// Sets how far the mouse may move, in pixels along each axis, from the point of the last
+    /// mouse-over query before `get_nodes_under_mouse` queries layout again. A negative distance
+    /// makes every call query layout.
+    pub fn set_mouse_move_hysteresis(&self, threshold: f32) {
+        self.mouse_move_hysteresis.set(threshold);
+    }
+
+    fn query_nodes_under_mouse(&self, point: &Point2D<f32>) -> Option<Vec<UntrustedNodeAddress>> {
+        let document = match *self.frame() {
+            None => return None,
+            Some(ref frame) => frame.document.root(),
+        };
         let root = document.GetDocumentElement().root();
         if root.is_none() {
             return None;
         }
         let root = root.unwrap();
         let root: JSRef<Node> = NodeCast::from_ref(*root);
-        let address = match self.layout().mouse_over(root.to_trusted_node_address(), *point) {
+        let layout_rpc = match self.layout() {
+            Ok(layout_rpc) => layout_rpc,
+            Err(error) => {
+                error!("script: mouse over query failed: {:?}", error);
+                return None;
+            }
+        };
+        let address = match layout_rpc.mouse_over(root.to_trusted_node_address(), *point) {
             Ok(MouseOverResponse(node_address)) => {
                 Some(node_address)
             }
@@ -455,3 +1629,1324 @@ pub struct JSPageInfo {
     /// The JavaScript context.
     pub js_context: Rc<Cx>,
 }
+
+/// Returns the goal of the reflow needed to flush layout for the given query, or `None` if no
+/// reflow is needed. If we are damaged, we need to force a full reflow, so that queries interact
+/// with an accurate flow tree.
+fn flush_reflow_goal(damaged: bool, image_damaged: bool, query: &ReflowQueryType)
+                     -> Option<ReflowGoal> {
+    if damaged {
+        return Some(ReflowForDisplay)
+    }
+    match *query {
+        ContentBoxQuery(_) | ContentBoxesQuery(_) | ClientRectQuery(_) |
+        ResolvedStyleQuery(..) | ScrollDimensionsQuery(_) | OffsetParentQuery(_) |
+        TextRangeBoxQuery(..) | ScrollContainerQuery(_) | BatchQuery(_) => {
+            Some(ReflowForScriptQuery)
+        }
+        NoQuery if image_damaged => Some(ReflowForImageUpdate),
+        NoQuery => None,
+    }
+}
+
+/// Returns true if a reflow for `goal` makes an outstanding reflow for `in_flight` redundant.
+/// Image updates only repaint, and carry no damage, so any reflow that builds a display list
+/// covers them; reflows for script queries build none, and nothing else is safe to drop.
+fn reflow_supersedes(in_flight: ReflowGoal, goal: ReflowGoal) -> bool {
+    in_flight == ReflowForImageUpdate && goal == ReflowForDisplay
+}
+
+/// Returns the ratio of `avoided` to `performed` reflows. A page that never reflowed has an
+/// infinite ratio, so that it is never reported as reflowing excessively.
+fn reflow_efficiency(avoided: u64, performed: u64) -> f64 {
+    if performed == 0 {
+        return f64::INFINITY
+    }
+    avoided as f64 / performed as f64
+}
+
+/// Returns true if an idle reflow may be performed: layout is idle and no other reflow is waiting
+/// for it.
+fn should_run_idle_reflow(layout_state: LayoutState, pending_reflows: int) -> bool {
+    layout_state == Idle && pending_reflows == 0
+}
+
+/// Returns the clip rect to send with a reflow: `clip` if given, or else the whole viewport.
+fn reflow_clip(clip: Option<Rect<Au>>, window_size: WindowSizeData) -> Rect<Au> {
+    clip.unwrap_or_else(|| {
+        let viewport = window_size.initial_viewport;
+        Rect(Point2D(Au(0), Au(0)),
+             Size2D(Au::from_frac32_px(viewport.width.get()),
+                    Au::from_frac32_px(viewport.height.get())))
+    })
+}
+
+/// Returns whether any part of `content_box` lies within `viewport`. Boxes that merely touch
+/// the edge of the viewport are not visible.
+fn box_intersects_viewport(content_box: Rect<Au>, viewport: Rect<Au>) -> bool {
+    content_box.intersects(&viewport)
+}
+
+/// Returns `id` preceded by the ids of its ancestors, which are given nearest first, from the
+/// root down.
+fn path_from_root<T, I: Iterator<T>>(id: T, ancestor_ids: I) -> Vec<T> {
+    let mut path: Vec<T> = ancestor_ids.collect();
+    path.reverse();
+    path.push(id);
+    path
+}
+
+/// Checks that a subtree of the given height can be added below a page that is nested
+/// `parent_depth` frames deep, without nesting any page more than `max_depth` frames deep.
+fn check_frame_depth(parent_depth: uint, subtree_height: uint, max_depth: uint)
+                     -> Result<(), PageTreeError> {
+    if parent_depth + 1 + subtree_height > max_depth {
+        return Err(FrameTooDeep)
+    }
+    Ok(())
+}
+
+/// Takes the completion channels of coalesced reflows, together with `completion_chan`, for the
+/// reflow that is about to be sent.
+fn take_completion_chans(pending: &mut Vec<Sender<uint>>, completion_chan: Option<Sender<uint>>)
+                         -> Vec<Sender<uint>> {
+    let mut completion_chans = replace(pending, vec!());
+    completion_chans.extend(completion_chan.into_iter());
+    completion_chans
+}
+
+/// Takes the nodes in `pending`, leaving it empty.
+fn take_dirty_nodes<T>(pending: &mut SmallVec1<T>) -> SmallVec1<T> {
+    replace(pending, SmallVec1::new())
+}
+
+/// Returns the resize event to keep pending when the window is resized to `new_size`, given the
+/// size that was last applied: `None` if nothing actually changes.
+fn debounce_resize(applied_size: WindowSizeData, new_size: WindowSizeData)
+                   -> Option<WindowSizeData> {
+    if new_size == applied_size {
+        None
+    } else {
+        Some(new_size)
+    }
+}
+
+/// Returns the resize event to queue when the window size of a page changes from `current_size`
+/// to `new_size`, or an error if `new_size` is degenerate.
+fn window_size_update(current_size: WindowSizeData, new_size: WindowSizeData)
+                      -> Result<Option<WindowSizeData>, WindowSizeError> {
+    if new_size.is_empty() {
+        return Err(EmptyWindowSize)
+    }
+    Ok(debounce_resize(current_size, new_size))
+}
+
+/// Returns true if reflowing at `new_size` after a reflow at `last_size` requires a full reflow.
+fn resize_needs_full_reflow(last_size: Option<WindowSizeData>, new_size: WindowSizeData) -> bool {
+    match last_size {
+        Some(last_size) => last_size.dpr_changed(&new_size),
+        None => false,
+    }
+}
+
+/// Replaces the value cached for `key`, or adds one. The oldest value is evicted once the cache
+/// would hold more than `capacity` values.
+fn cache_value<K: PartialEq, V>(cache: &mut Vec<(K, V)>, key: K, value: V, capacity: uint) {
+    match cache.iter().position(|&(ref cached_key, _)| *cached_key == key) {
+        Some(index) => {
+            cache.remove(index);
+        }
+        None => {}
+    }
+    if cache.len() >= capacity {
+        cache.remove(0);
+    }
+    cache.push((key, value));
+}
+
+/// Returns the result cached for a query at a point within `threshold` along each axis of
+/// `point`, if the cache is still valid for `reflow_id` and the page isn't `damaged`. Otherwise
+/// runs `query` and caches its result for `point`.
+fn query_with_hysteresis<T: Clone>(cache: &mut Option<MouseOverCache<T>>,
+                                   point: Point2D<f32>,
+                                   reflow_id: uint,
+                                   damaged: bool,
+                                   threshold: f32,
+                                   query: || -> T)
+                                   -> T {
+    match *cache {
+        Some(ref cached) if !damaged && cached.reflow_id == reflow_id &&
+                            (cached.point.x - point.x).abs() <= threshold &&
+                            (cached.point.y - point.y).abs() <= threshold => {
+            return cached.nodes.clone()
+        }
+        _ => {}
+    }
+    let nodes = query();
+    *cache = Some(MouseOverCache {
+        point: point,
+        reflow_id: reflow_id,
+        nodes: nodes.clone(),
+    });
+    nodes
+}
+
+/// Counts a reflow as avoided if reflows are `suppressed`, and returns whether it is to be skipped.
+fn skip_suppressed_reflow(suppressed: bool, avoided_reflows: &Cell<int>, total_avoided: &Cell<u64>)
+                          -> bool {
+    if suppressed {
+        avoided_reflows.set(avoided_reflows.get() + 1);
+        total_avoided.set(total_avoided.get() + 1);
+    }
+    suppressed
+}
+
+/// Returns a copy of the value cached for `key`, if any.
+fn cached_value<K: PartialEq, V: Clone>(cache: &Vec<(K, V)>, key: &K) -> Option<V> {
+    cache.iter().find(|&&(ref cached_key, _)| *cached_key == *key).map(|&(_, ref value)| {
+        value.clone()
+    })
+}
+
+/// Clears the reflow-needed bit of the cached url and returns its old value, if the cached url is
+/// `url`.
+fn take_cached_reload(cached_url: &mut Option<(Url, bool)>, url: &Url) -> Option<bool> {
+    match *cached_url {
+        Some((ref loaded, ref mut needs_reflow)) => {
+            if *loaded == *url {
+                Some(replace(needs_reflow, false))
+            } else {
+                None
+            }
+        }
+        None => None,
+    }
+}
+
+/// Pops a previously freed subpage id, or mints the next one from `next_subpage_id`.
+fn allocate_subpage_id(next_subpage_id: &Cell<SubpageId>, free_subpage_ids: &mut Vec<SubpageId>)
+                       -> SubpageId {
+    match free_subpage_ids.pop() {
+        Some(subpage_id) => subpage_id,
+        None => {
+            let subpage_id = next_subpage_id.get();
+            let SubpageId(id_num) = subpage_id;
+            next_subpage_id.set(SubpageId(id_num + 1));
+            subpage_id
+        }
+    }
+}
+
+/// Waits for the layout task to signal the outstanding join port, if there is one, and clears it,
+/// recording how long layout spent on the reflow. Layout is idle afterwards, even if it failed.
+fn wait_for_join_port(layout_join_port: &mut Option<Receiver<Duration>>,
+                      layout_state: &Cell<LayoutState>,
+                      last_reflow_duration: &Cell<Duration>)
+                      -> Result<(), LayoutJoinError> {
+    layout_state.set(Idle);
+    match replace(layout_join_port, None) {
+        Some(ref join_port) => {
+            let duration = match join_port.try_recv() {
+                Err(Empty) => {
+                    info!("script: waiting on layout");
+                    match join_port.recv_opt() {
+                        Ok(duration) => duration,
+                        Err(()) => return Err(LayoutTaskFailed),
+                    }
+                }
+                Ok(duration) => duration,
+                Err(Disconnected) => return Err(LayoutTaskFailed),
+            };
+            last_reflow_duration.set(duration);
+
+            debug!("script: layout joined after {}", duration)
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FragmentScroll, flush_reflow_goal};
+    use super::{cache_value, cached_value, reflow_efficiency, resize_needs_full_reflow};
+    use super::{EmptyWindowSize, take_cached_reload, take_completion_chans, window_size_update};
+    use super::{DEFAULT_MAX_FRAME_DEPTH, FrameTooDeep, check_frame_depth, wait_for_join_port};
+    use super::{AlignCenter, AlignEnd, AlignNearest, AlignStart, scroll_target};
+    use super::{Idle, LayoutTaskFailed, Running, path_from_root, should_run_idle_reflow};
+    use super::{PageTreeNode, each_node, each_node_while, next_depth_first, reflow_clip};
+    use super::{find_by_subpage_id, reflow_supersedes};
+    use super::{DEFAULT_MOUSE_MOVE_HYSTERESIS, query_with_hysteresis, skip_suppressed_reflow};
+    use super::{box_intersects_viewport, take_dirty_nodes};
+    use super::{DEFAULT_URL_CACHE_CAPACITY, Frame, IterablePage, Page};
+    use layout_interface::{NoQuery, ReflowForDisplay, ReflowForImageUpdate, ReflowForScriptQuery};
+    use layout_interface::{BackgroundReflowPriority, DisplayReflowPriority};
+    use layout_interface::{ScriptQueryReflowPriority, ScrollContainerQuery, TrustedNodeAddress};
+    use layout_interface::{CancelReflowMsg, GetRPCMsg, LayoutChan, LayoutRPC, Msg, ScriptQuery};
+    use layout_interface::{BatchQuery, DOMEvent, ReflowMsg};
+    use layout_interface::{BatchQueryResponse, ClientRectResponse, ContentBoxResponse};
+    use layout_interface::{ContentBoxesResponse, HitTestAllResponse, HitTestResponse};
+    use layout_interface::{MouseOverResponse, OffsetParentResponse, ResolvedStyleResponse};
+    use layout_interface::{ScrollContainerResponse, ScrollDimensionsResponse, TextRangeBoxResponse};
+    use dom::bindings::codegen::Bindings::DocumentBinding::DocumentMethods;
+    use dom::bindings::codegen::Bindings::NodeBinding::NodeMethods;
+    use dom::bindings::codegen::InheritTypes::NodeCast;
+    use dom::bindings::js::{JS, JSRef, RootCollection};
+    use dom::document::{Document, HTMLDocument, NotFromParser};
+    use dom::node::Node;
+    use dom::window::Window;
+    use script_task::{ReloadMsg, ScriptChan, ScriptTask, StackRootTLS};
+    use js::rust::{Cx, rt};
+    use geom::{Point2D, Rect, Size2D};
+    use geom::scale_factor::ScaleFactor;
+    use geom::size::TypedSize2D;
+    use servo_msg::constellation_msg::{ConstellationChan, PipelineId, SubpageId, WindowSizeData};
+    use servo_msg::constellation_msg::Msg as ConstellationMsg;
+    use servo_msg::compositor_msg::{LayerId, ReadyState, ScriptListener};
+    use script_traits::ScriptControlChan;
+    use servo_net::image_cache_task::ImageCacheTask;
+    use servo_util::geometry::Au;
+    use servo_util::smallvec::{SmallVec1, SmallVec};
+    use servo_util::task_state;
+    use servo_util::taskpool::TaskPool;
+    use std::cell::{Cell, RefCell};
+    use std::comm::Empty;
+    use std::f64;
+    use std::rc::Rc;
+    use std::ptr;
+    use std::time::duration::Duration;
+    use url::Url;
+
+    fn window_size(width: f32, height: f32) -> WindowSizeData {
+        WindowSizeData {
+            initial_viewport: TypedSize2D(width, height),
+            visible_viewport: TypedSize2D(width, height),
+            device_pixel_ratio: ScaleFactor(1.0),
+        }
+    }
+
+    /// The layout RPC interface of the stand-in layout tasks of `TestPages`. Content boxes are
+    /// empty and nothing is under the mouse; the other queries aren't made by tests.
+    struct TestLayoutRPC;
+
+    impl LayoutRPC for TestLayoutRPC {
+        fn content_box(&self) -> ContentBoxResponse {
+            ContentBoxResponse(Rect::zero())
+        }
+        fn content_boxes(&self) -> ContentBoxesResponse {
+            ContentBoxesResponse(vec!())
+        }
+        fn client_rect(&self) -> ClientRectResponse {
+            ClientRectResponse(Rect::zero())
+        }
+        fn resolved_style(&self) -> ResolvedStyleResponse {
+            ResolvedStyleResponse(None)
+        }
+        fn scroll_dimensions(&self) -> ScrollDimensionsResponse {
+            ScrollDimensionsResponse(Size2D::zero())
+        }
+        fn offset_parent(&self) -> OffsetParentResponse {
+            OffsetParentResponse::empty()
+        }
+        fn text_range_box(&self) -> TextRangeBoxResponse {
+            TextRangeBoxResponse(Rect::zero())
+        }
+        fn scroll_container(&self) -> ScrollContainerResponse {
+            ScrollContainerResponse(None)
+        }
+        fn batch_query(&self) -> BatchQueryResponse {
+            BatchQueryResponse(vec!())
+        }
+        fn hit_test(&self, _: TrustedNodeAddress, _: Point2D<f32>)
+                    -> Result<HitTestResponse, ()> {
+            Err(())
+        }
+        fn hit_test_in(&self, _: TrustedNodeAddress, _: Point2D<f32>)
+                       -> Result<HitTestResponse, ()> {
+            Err(())
+        }
+        fn hit_test_all(&self, _: TrustedNodeAddress, _: Point2D<f32>)
+                        -> Result<HitTestAllResponse, ()> {
+            Ok(HitTestAllResponse(vec!()))
+        }
+        fn mouse_over(&self, _: TrustedNodeAddress, _: Point2D<f32>)
+                      -> Result<MouseOverResponse, ()> {
+            Err(())
+        }
+    }
+
+    /// Spawns a stand-in layout task, which answers the RPC request that `Page::new` makes and
+    /// passes every other message on to the returned port, so that tests can check what a page
+    /// sent to layout.
+    fn test_layout_task() -> (LayoutChan, Receiver<Msg>) {
+        let (layout_chan, layout_port) = channel();
+        let (sent_chan, sent_port) = channel();
+        spawn(proc() {
+            for msg in layout_port.iter() {
+                match msg {
+                    GetRPCMsg(response_chan) => {
+                        response_chan.send(box TestLayoutRPC as Box<LayoutRPC + Send>);
+                    }
+                    msg => {
+                        if sent_chan.send_opt(msg).is_err() {
+                            break
+                        }
+                    }
+                }
+            }
+        });
+        (LayoutChan(layout_chan), sent_port)
+    }
+
+    /// Returns true if a page sent nothing (more) to its layout task.
+    fn sent_nothing(layout_msgs: &Receiver<Msg>) -> bool {
+        match layout_msgs.try_recv() {
+            Err(Empty) => true,
+            _ => false,
+        }
+    }
+
+    /// A compositor that ignores everything script tells it.
+    struct TestCompositor;
+
+    impl ScriptListener for TestCompositor {
+        fn set_ready_state(&mut self, _: PipelineId, _: ReadyState) {}
+        fn scroll_fragment_point(&mut self, _: PipelineId, _: LayerId, _: Point2D<f32>, _: bool) {}
+        fn close(&mut self) {}
+        fn dup(&mut self) -> Box<ScriptListener+'static> {
+            box TestCompositor as Box<ScriptListener+'static>
+        }
+    }
+
+    fn test_control_chan() -> ScriptControlChan {
+        let (chan, _) = channel();
+        ScriptControlChan(chan)
+    }
+
+    /// Builds real pages, with a stand-in layout task each and no document, so that the logic of
+    /// `Page` itself can be tested. The JS runtime is kept alive for as long as the pages.
+    struct TestPages {
+        _js_runtime: rt,
+        js_context: Rc<Cx>,
+        constellation_chan: ConstellationChan,
+        /// The messages that the pages sent to the constellation.
+        constellation_msgs: Receiver<ConstellationMsg>,
+    }
+
+    impl TestPages {
+        fn new() -> TestPages {
+            task_state::initialize(task_state::Script);
+            let (js_runtime, js_context) = ScriptTask::new_rt_and_cx();
+            let (constellation_msgs, constellation_chan) = ConstellationChan::new();
+            TestPages {
+                _js_runtime: js_runtime,
+                js_context: js_context,
+                constellation_chan: constellation_chan,
+                constellation_msgs: constellation_msgs,
+            }
+        }
+
+        /// Returns a new page, and the messages that it sends to its layout task.
+        fn page(&self, id: uint, subpage_id: Option<uint>) -> (Rc<Page>, Receiver<Msg>) {
+            let (layout_chan, layout_msgs) = test_layout_task();
+            let (resource_task, _) = channel();
+            let page = Page::new(PipelineId(id),
+                                 subpage_id.map(SubpageId),
+                                 layout_chan,
+                                 window_size(800.0, 600.0),
+                                 resource_task,
+                                 self.constellation_chan.clone(),
+                                 self.js_context.clone(),
+                                 DEFAULT_URL_CACHE_CAPACITY);
+            (Rc::new(page), layout_msgs)
+        }
+
+        /// Gives `page` a window with an empty document, as if it had just started loading one.
+        /// The caller must have set up the stack roots.
+        fn load_empty_document(&self, page: &Rc<Page>) {
+            let (_, script_chan) = ScriptChan::new();
+            let (resource_task, _) = channel();
+            let image_cache_task = ImageCacheTask::new(resource_task, TaskPool::new(1));
+            let window = Window::new(self.js_context.ptr,
+                                     page.clone(),
+                                     script_chan,
+                                     test_control_chan(),
+                                     box TestCompositor as Box<ScriptListener+'static>,
+                                     image_cache_task).root();
+            let document = Document::new(*window, None, HTMLDocument, None, NotFromParser).root();
+            *page.mut_frame() = Some(Frame {
+                document: JS::from_rooted(*document),
+                window: JS::from_rooted(*window),
+            });
+        }
+
+        /// Returns the root of a page tree, whose pipeline ids are the positions of its pages.
+        /// The root is page 0, and each `(id, parent id)` adds page `id` as the last child of
+        /// page `parent id`, which must already be in the tree.
+        fn tree(&self, children: &[(uint, uint)]) -> Rc<Page> {
+            let (root, _) = self.page(0, None);
+            for &(id, parent_id) in children.iter() {
+                let parent = root.find(PipelineId(parent_id)).unwrap();
+                let SubpageId(subpage_id) = parent.get_next_subpage_id();
+                let (child, _) = self.page(id, Some(subpage_id));
+                assert!(root.add_child(&parent, child).is_ok());
+            }
+            root
+        }
+    }
+
+    /// Gives the document of `page`, which must have been loaded, a document element, so that
+    /// there is something to reflow.
+    fn add_document_element(page: &Rc<Page>) {
+        let document = page.frame().as_ref().unwrap().document.root();
+        let html = document.CreateElement("html".to_string()).ok().expect("no element").root();
+        let document: JSRef<Node> = NodeCast::from_ref(*document);
+        let html: JSRef<Node> = NodeCast::from_ref(*html);
+        assert!(document.AppendChild(html).is_ok());
+    }
+
+    /// The page tree used by the tree tests: the root has two children, the first of which has
+    /// two children of its own, and the first of those has one more.
+    static TEST_TREE: &'static [(uint, uint)] = &[(1, 0), (2, 0), (3, 1), (4, 1), (5, 3)];
+
+    /// Returns the numbers of the pipeline ids of `pages`, in order.
+    fn ids<I: Iterator<Rc<Page>>>(pages: I) -> Vec<uint> {
+        pages.map(|page| { let PipelineId(id) = page.id; id }).collect()
+    }
+
+    #[test]
+    fn test_identical_resizes_are_debounced() {
+        let pages = TestPages::new();
+        let (page, _layout_msgs) = pages.page(0, None);
+        let new_size = window_size(1024.0, 768.0);
+
+        let mut pending_events = 0u;
+        for _ in range(0u, 3) {
+            assert!(page.set_window_size(new_size).is_ok());
+            if page.take_resize_event().is_some() {
+                pending_events += 1;
+            }
+        }
+        assert_eq!(pending_events, 1);
+        assert!(page.window_size.get() == new_size);
+    }
+
+    #[test]
+    fn test_image_update_does_not_escalate_to_full_reflow() {
+        assert_eq!(flush_reflow_goal(false, true, &NoQuery), Some(ReflowForImageUpdate));
+        assert_eq!(flush_reflow_goal(true, true, &NoQuery), Some(ReflowForDisplay));
+        assert_eq!(flush_reflow_goal(false, false, &NoQuery), None);
+    }
+
+    #[test]
+    fn test_scroll_container_query_forces_script_reflow() {
+        let query = ScrollContainerQuery(TrustedNodeAddress(ptr::null()));
+        assert_eq!(flush_reflow_goal(false, false, &query), Some(ReflowForScriptQuery));
+    }
+
+    #[test]
+    fn test_fragment_scroll_variants() {
+        let url_fragment = Some("section".to_string());
+        let instant = url_fragment.clone().map(FragmentScroll::instant).unwrap();
+        assert_eq!(instant.name.as_slice(), "section");
+        assert!(!instant.smooth);
+
+        let smooth = FragmentScroll::smooth("section".to_string());
+        assert_eq!(smooth.name, instant.name);
+        assert!(smooth.smooth);
+        assert!(smooth != instant);
+    }
+
+    #[test]
+    fn test_layout_is_busy_until_joined() {
+        let (chan, port) = channel();
+        let mut layout_join_port = Some(port);
+        let layout_state = Cell::new(Running { reflow_id: 1 });
+        let last_reflow_duration = Cell::new(Duration::zero());
+        assert!(layout_join_port.is_some());
+
+        chan.send(Duration::zero());
+        assert!(wait_for_join_port(&mut layout_join_port, &layout_state, &last_reflow_duration)
+                    .is_ok());
+        assert!(layout_join_port.is_none());
+        assert_eq!(layout_state.get(), Idle);
+
+        // Joining again without an outstanding reflow is a no-op.
+        assert!(wait_for_join_port(&mut layout_join_port, &layout_state, &last_reflow_duration)
+                    .is_ok());
+        assert_eq!(layout_state.get(), Idle);
+    }
+
+    #[test]
+    fn test_failed_layout_is_idle_once_joined() {
+        let (chan, port) = channel::<Duration>();
+        let mut layout_join_port = Some(port);
+        let layout_state = Cell::new(Running { reflow_id: 2 });
+        let last_reflow_duration = Cell::new(Duration::zero());
+        drop(chan);
+        assert_eq!(wait_for_join_port(&mut layout_join_port, &layout_state, &last_reflow_duration),
+                   Err(LayoutTaskFailed));
+        assert_eq!(layout_state.get(), Idle);
+    }
+
+    #[test]
+    fn test_joining_layout_records_the_reflow_duration() {
+        let last_reflow_duration = Cell::new(Duration::zero());
+        let layout_state = Cell::new(Running { reflow_id: 3 });
+        let (chan, port) = channel();
+        let mut layout_join_port = Some(port);
+        chan.send(Duration::milliseconds(12));
+        assert!(wait_for_join_port(&mut layout_join_port, &layout_state, &last_reflow_duration)
+                    .is_ok());
+        assert_eq!(last_reflow_duration.get(), Duration::milliseconds(12));
+
+        // Each completed reflow replaces the previous duration.
+        let (chan, port) = channel();
+        layout_join_port = Some(port);
+        chan.send(Duration::milliseconds(3));
+        assert!(wait_for_join_port(&mut layout_join_port, &layout_state, &last_reflow_duration)
+                    .is_ok());
+        assert_eq!(last_reflow_duration.get(), Duration::milliseconds(3));
+
+        // Joining without an outstanding reflow keeps the last duration.
+        assert!(wait_for_join_port(&mut layout_join_port, &layout_state, &last_reflow_duration)
+                    .is_ok());
+        assert_eq!(last_reflow_duration.get(), Duration::milliseconds(3));
+    }
+
+    fn counted_query(queries: &Cell<uint>) -> uint {
+        queries.set(queries.get() + 1);
+        queries.get()
+    }
+
+    #[test]
+    fn test_nearby_mouse_moves_share_a_query() {
+        let mut cache = None;
+        let queries = Cell::new(0u);
+        let threshold = DEFAULT_MOUSE_MOVE_HYSTERESIS;
+        assert_eq!(query_with_hysteresis(&mut cache, Point2D(10.0, 10.0), 1, false, threshold,
+                                         || counted_query(&queries)), 1);
+        assert_eq!(query_with_hysteresis(&mut cache, Point2D(10.5, 9.75), 1, false, threshold,
+                                         || counted_query(&queries)), 1);
+        assert_eq!(queries.get(), 1);
+
+        // Moving further away, reflowing or damaging the page queries again.
+        assert_eq!(query_with_hysteresis(&mut cache, Point2D(20.0, 10.0), 1, false, threshold,
+                                         || counted_query(&queries)), 2);
+        assert_eq!(query_with_hysteresis(&mut cache, Point2D(20.0, 10.0), 2, false, threshold,
+                                         || counted_query(&queries)), 3);
+        assert_eq!(query_with_hysteresis(&mut cache, Point2D(20.0, 10.0), 2, true, threshold,
+                                         || counted_query(&queries)), 4);
+        assert_eq!(queries.get(), 4);
+    }
+
+    #[test]
+    fn test_reflows_are_skipped_while_suppressed() {
+        let avoided_reflows = Cell::new(0i);
+        let total_avoided = Cell::new(0u64);
+        let suppress_reflows = Cell::new(true);
+        assert!(skip_suppressed_reflow(suppress_reflows.get(), &avoided_reflows, &total_avoided));
+        assert!(skip_suppressed_reflow(suppress_reflows.get(), &avoided_reflows, &total_avoided));
+        assert_eq!((avoided_reflows.get(), total_avoided.get()), (2, 2));
+
+        // Once a document is loaded, reflows go ahead again.
+        suppress_reflows.set(false);
+        assert!(!skip_suppressed_reflow(suppress_reflows.get(), &avoided_reflows, &total_avoided));
+        assert_eq!((avoided_reflows.get(), total_avoided.get()), (2, 2));
+    }
+
+    #[test]
+    fn test_freed_subpage_ids_are_reused() {
+        let pages = TestPages::new();
+        let root = pages.tree(&[(1, 0), (2, 0)]);
+
+        // The id of a frame stays taken while a child page has it.
+        root.release_subpage_id(SubpageId(1));
+        assert!(root.get_next_subpage_id() == SubpageId(2));
+
+        // Once the constellation has dropped the frame, its id is handed out again.
+        assert!(root.remove_and_release(PipelineId(2)).is_some());
+        assert!(root.get_next_subpage_id() == SubpageId(1));
+        assert!(root.get_next_subpage_id() == SubpageId(3));
+    }
+
+    #[test]
+    fn test_dpr_only_change_triggers_full_reflow() {
+        let size = window_size(800.0, 600.0);
+        let mut hidpi_size = size;
+        hidpi_size.device_pixel_ratio = ScaleFactor(2.0);
+        assert!(size.dpr_changed(&hidpi_size));
+        assert!(resize_needs_full_reflow(Some(size), hidpi_size));
+
+        assert!(!resize_needs_full_reflow(Some(size), window_size(1024.0, 768.0)));
+        assert!(!resize_needs_full_reflow(None, hidpi_size));
+    }
+
+    #[test]
+    fn test_reload_reflows_only_if_needed() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut needs_reflow = Some((url.clone(), true));
+        assert_eq!(take_cached_reload(&mut needs_reflow, &url), Some(true));
+        // The bit is consumed by the reload.
+        assert_eq!(take_cached_reload(&mut needs_reflow, &url), Some(false));
+
+        let mut up_to_date = Some((url.clone(), false));
+        assert_eq!(take_cached_reload(&mut up_to_date, &url), Some(false));
+
+        let other = Url::parse("http://example.org/").unwrap();
+        assert_eq!(take_cached_reload(&mut up_to_date, &other), None);
+        assert_eq!(take_cached_reload(&mut None, &url), None);
+    }
+
+    #[test]
+    fn test_sync_reflow_without_document_lays_nothing_out() {
+        let pages = TestPages::new();
+        let (page, layout_msgs) = pages.page(1, None);
+        page.suppress_reflows.set(true);
+        assert_eq!(page.reflow_sync(ReflowForDisplay, ScriptQuery, test_control_chan(),
+                                    &mut TestCompositor, NoQuery),
+                   Ok(false));
+        assert!(sent_nothing(&layout_msgs));
+        // A sync reflow isn't one that suppression avoided.
+        assert_eq!(page.avoided_reflows.get(), 0);
+        assert!(page.layout_state() == Idle);
+    }
+
+    #[test]
+    fn test_url_history_drops_the_oldest_url() {
+        let pages = TestPages::new();
+        let (page, _layout_msgs) = pages.page(1, None);
+        let url = |i: uint| Url::parse(format!("http://example.com/{}", i).as_slice()).unwrap();
+        for i in range(0, DEFAULT_URL_CACHE_CAPACITY + 2) {
+            page.set_url(url(i), true);
+        }
+
+        // The last url is the current one, and the one before the history's oldest was dropped.
+        let url_history = page.url_history();
+        assert_eq!(url_history.len(), DEFAULT_URL_CACHE_CAPACITY);
+        assert!(url_history.as_slice()[0].ref0() == &url(1));
+        assert!(url_history.last().unwrap().ref0() == &url(DEFAULT_URL_CACHE_CAPACITY));
+        assert!(page.get_url() == url(DEFAULT_URL_CACHE_CAPACITY + 1));
+    }
+
+    #[test]
+    fn test_current_url_is_not_in_its_own_history() {
+        let pages = TestPages::new();
+        let (page, _layout_msgs) = pages.page(1, None);
+        let first = Url::parse("http://example.com/").unwrap();
+        let second = Url::parse("http://example.com/second").unwrap();
+
+        // Parsing sets the url of a page before its load completes and sets it again.
+        page.set_url(first.clone(), true);
+        page.set_url(first.clone(), false);
+        assert!(page.url_history().is_empty());
+
+        // Going back to a url takes it out of the history.
+        page.set_url(second.clone(), true);
+        page.set_url(first.clone(), true);
+        assert_eq!(page.url_history().len(), 1);
+        assert!(page.url_history().as_slice()[0].ref0() == &second);
+    }
+
+    #[test]
+    fn test_reload_reuses_the_cached_page() {
+        let pages = TestPages::new();
+        let (page, _layout_msgs) = pages.page(1, None);
+        page.set_url(Url::parse("http://example.com/").unwrap(), true);
+
+        let (chan, script_port) = channel();
+        let script_chan = ScriptChan(chan);
+        for needs_reflow in [true, false].iter() {
+            page.reload(&script_chan);
+            match script_port.try_recv() {
+                Ok(ReloadMsg(id)) => assert!(id == page.id),
+                _ => fail!("the page wasn't reloaded by its own script task"),
+            }
+            // The script task then loads the url of the page, which is still cached: the first
+            // reload reflows, as the page was marked as needing it, and the next one reuses the
+            // layout.
+            assert_eq!(page.take_cached_reload(&page.get_url()), Some(*needs_reflow));
+        }
+
+        // No new pipeline was asked for, which wouldn't have the cached layout.
+        assert!(pages.constellation_msgs.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_cached_content_box_is_replaced_by_new_query() {
+        let mut cache = vec!();
+        assert_eq!(cached_value(&cache, &1u), None);
+        cache_value(&mut cache, 1u, "stale", 2);
+        cache_value(&mut cache, 2u, "other", 2);
+        assert_eq!(cached_value(&cache, &1u), Some("stale"));
+        cache_value(&mut cache, 1u, "fresh", 2);
+        assert_eq!(cached_value(&cache, &1u), Some("fresh"));
+        assert_eq!(cache.len(), 2);
+
+        // The oldest value makes room for a new one.
+        cache_value(&mut cache, 3u, "new", 2);
+        assert_eq!(cached_value(&cache, &2u), None);
+        assert_eq!(cached_value(&cache, &1u), Some("fresh"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_cached_content_box_is_only_valid_for_its_reflow() {
+        let pages = TestPages::new();
+        let (page, layout_msgs) = pages.page(1, None);
+        let node = TrustedNodeAddress(ptr::null());
+        let rect = Rect(Point2D(Au(10), Au(20)), Size2D(Au(30), Au(40)));
+        assert_eq!(page.content_box_cached(node), None);
+
+        // Layout answers queries on this page with empty boxes, so getting `rect` back means
+        // that nothing was queried.
+        page.last_reflow_id.set(2);
+        page.content_box_cache.borrow_mut().reflow_id = 2;
+        page.content_box_cache.borrow_mut().boxes.push((node, rect));
+        assert_eq!(page.content_box_cached(node), Some(rect));
+        assert!(sent_nothing(&layout_msgs));
+
+        // Any later reflow may have moved the box.
+        page.last_reflow_id.set(3);
+        assert_eq!(page.content_box_cached(node), Some(Rect::zero()));
+        assert_eq!(page.content_box_cache.borrow().reflow_id, 3);
+    }
+
+    #[test]
+    fn test_reflow_efficiency_accumulates_over_lifetime() {
+        // Three flushes are avoided, one reflow is performed, then one more flush is avoided.
+        let (mut avoided, mut performed) = (0u64, 0u64);
+        assert_eq!(reflow_efficiency(avoided, performed), f64::INFINITY);
+        avoided += 3;
+        performed += 1;
+        assert_eq!(reflow_efficiency(avoided, performed), 3.0);
+        avoided += 1;
+        performed += 1;
+        assert_eq!(reflow_efficiency(avoided, performed), 2.0);
+        performed += 2;
+        assert_eq!(reflow_efficiency(avoided, performed), 1.0);
+    }
+
+    #[test]
+    fn test_reflow_clip_defaults_to_viewport() {
+        let size = window_size(800.0, 600.0);
+        let clip = Rect(Point2D(Au::from_px(0), Au::from_px(100)),
+                        Size2D(Au::from_px(800), Au::from_px(200)));
+        assert!(reflow_clip(Some(clip), size) == clip);
+        assert!(reflow_clip(None, size) ==
+                Rect(Point2D(Au(0), Au(0)), Size2D(Au::from_px(800), Au::from_px(600))));
+    }
+
+    #[test]
+    fn test_empty_window_size_is_rejected() {
+        let size = window_size(800.0, 600.0);
+        assert!(window_size_update(size, window_size(0.0, 600.0)) == Err(EmptyWindowSize));
+        assert!(window_size_update(size, window_size(800.0, -1.0)) == Err(EmptyWindowSize));
+
+        // Valid sizes queue a resize event only if they change something.
+        assert!(window_size_update(size, size) == Ok(None));
+        let larger = window_size(1024.0, 768.0);
+        assert!(window_size_update(size, larger) == Ok(Some(larger)));
+    }
+
+    #[test]
+    fn test_completion_chans_receive_final_reflow_id() {
+        let (coalesced_chan, coalesced_port) = channel();
+        let (final_chan, final_port) = channel();
+
+        // A request coalesced into reflow 6 waits for the reflow sent once layout has joined.
+        let mut pending = vec!(coalesced_chan);
+        let completion_chans = take_completion_chans(&mut pending, Some(final_chan));
+        assert!(pending.is_empty());
+        assert_eq!(completion_chans.len(), 2);
+
+        // Layout reports reflow 7 as done, or cancelled, to every completion channel.
+        for chan in completion_chans.iter() {
+            chan.send(7u);
+        }
+        assert_eq!(coalesced_port.recv(), 7);
+        assert_eq!(final_port.recv(), 7);
+        assert!(take_completion_chans(&mut pending, None).is_empty());
+    }
+
+    #[test]
+    fn test_frames_nested_past_limit_are_rejected() {
+        let max_depth = DEFAULT_MAX_FRAME_DEPTH;
+        // A child of the root is one frame deep.
+        assert_eq!(check_frame_depth(0, 0, max_depth), Ok(()));
+        assert_eq!(check_frame_depth(max_depth - 1, 0, max_depth), Ok(()));
+        assert_eq!(check_frame_depth(max_depth, 0, max_depth), Err(FrameTooDeep));
+
+        // The subtree of the new child counts as well.
+        assert_eq!(check_frame_depth(max_depth - 3, 2, max_depth), Ok(()));
+        assert_eq!(check_frame_depth(max_depth - 3, 3, max_depth), Err(FrameTooDeep));
+    }
+
+    #[test]
+    fn test_reflow_priority_follows_goal() {
+        assert_eq!(ReflowForScriptQuery.priority(), ScriptQueryReflowPriority);
+        assert_eq!(ReflowForDisplay.priority(), DisplayReflowPriority);
+        assert_eq!(ReflowForImageUpdate.priority(), BackgroundReflowPriority);
+
+        // Script is blocked on its queries, so they come before everything else.
+        assert!(ReflowForScriptQuery.priority() > ReflowForDisplay.priority());
+        assert!(ReflowForDisplay.priority() > ReflowForImageUpdate.priority());
+    }
+
+    #[test]
+    fn test_only_image_updates_are_superseded() {
+        assert!(reflow_supersedes(ReflowForImageUpdate, ReflowForDisplay));
+        // A script query builds no display list, so the repaint is still needed.
+        assert!(!reflow_supersedes(ReflowForImageUpdate, ReflowForScriptQuery));
+        assert!(!reflow_supersedes(ReflowForImageUpdate, ReflowForImageUpdate));
+        assert!(!reflow_supersedes(ReflowForDisplay, ReflowForDisplay));
+        assert!(!reflow_supersedes(ReflowForScriptQuery, ReflowForDisplay));
+    }
+
+    #[test]
+    fn test_outstanding_reflow_is_cancelled() {
+        let pages = TestPages::new();
+        let (page, layout_msgs) = pages.page(1, None);
+        page.cancel_reflow();
+        assert!(sent_nothing(&layout_msgs));
+
+        page.last_reflow_id.set(3);
+        page.layout_state.set(Running { reflow_id: 3 });
+        page.cancel_reflow();
+        match layout_msgs.recv_opt() {
+            Ok(CancelReflowMsg(id)) => assert_eq!(id, 3),
+            _ => fail!("layout wasn't asked to cancel the outstanding reflow"),
+        }
+    }
+
+    #[test]
+    fn test_viewport_rects_follow_the_reported_scroll_offset() {
+        let pages = TestPages::new();
+        let (page, _layout_msgs) = pages.page(1, None);
+        let rect = Rect(Point2D(Au(100), Au(300)), Size2D(Au(50), Au(20)));
+        assert!(page.to_viewport_rect(rect) == rect);
+
+        page.scroll_offset.set(Point2D(Au(0), Au(200)));
+        assert!(page.to_viewport_rect(rect) == Rect(Point2D(Au(100), Au(100)), rect.size));
+    }
+
+    #[test]
+    fn test_scroll_target_honors_alignment() {
+        // A 100px viewport scrolled to 200px, and a 20px box at 400px, out of view below it.
+        let (current, viewport) = (Au::from_px(200), Au::from_px(100));
+        let (start, size) = (Au::from_px(400), Au::from_px(20));
+        assert_eq!(scroll_target(current, viewport, start, size, AlignStart), Au::from_px(400));
+        assert_eq!(scroll_target(current, viewport, start, size, AlignCenter), Au::from_px(360));
+        assert_eq!(scroll_target(current, viewport, start, size, AlignEnd), Au::from_px(320));
+        assert_eq!(scroll_target(current, viewport, start, size, AlignNearest), Au::from_px(320));
+
+        // Nearest scrolls up to a box above the viewport, and not at all to one inside it.
+        let above = Au::from_px(50);
+        assert_eq!(scroll_target(current, viewport, above, size, AlignNearest), above);
+        let inside = Au::from_px(250);
+        assert_eq!(scroll_target(current, viewport, inside, size, AlignNearest), current);
+
+        // The viewport can't be scrolled past the start of the page.
+        assert_eq!(scroll_target(current, viewport, Au(0), size, AlignEnd), Au(0));
+    }
+
+    #[test]
+    fn test_pipeline_path_runs_from_root() {
+        // A page in a frame of a frame of the root, whose ancestors are listed nearest first.
+        let ancestors = vec!(PipelineId(7), PipelineId(3));
+        let path = path_from_root(PipelineId(12), ancestors.into_iter());
+        assert!(path == vec!(PipelineId(3), PipelineId(7), PipelineId(12)));
+
+        // The path of a root page is just its own id.
+        assert!(path_from_root(PipelineId(3), vec!().into_iter()) == vec!(PipelineId(3)));
+    }
+
+    /// A page tree node that counts how often it is cloned.
+    struct CountingNode {
+        id: uint,
+        children: Rc<RefCell<Vec<CountingNode>>>,
+        clones: Rc<Cell<uint>>,
+    }
+
+    impl Clone for CountingNode {
+        fn clone(&self) -> CountingNode {
+            self.clones.set(self.clones.get() + 1);
+            CountingNode {
+                id: self.id,
+                children: self.children.clone(),
+                clones: self.clones.clone(),
+            }
+        }
+    }
+
+    impl PageTreeNode for CountingNode {
+        fn with_children(&self, f: |&[CountingNode]|) {
+            f(self.children.borrow().as_slice())
+        }
+
+        fn subpage_id(&self) -> Option<SubpageId> {
+            Some(SubpageId(self.id))
+        }
+    }
+
+    fn counting_node(id: uint, children: Vec<CountingNode>, clones: &Rc<Cell<uint>>)
+                     -> CountingNode {
+        CountingNode {
+            id: id,
+            children: Rc::new(RefCell::new(children)),
+            clones: clones.clone(),
+        }
+    }
+
+    #[test]
+    fn test_each_page_visits_without_cloning() {
+        let clones = Rc::new(Cell::new(0u));
+        let grandchild = counting_node(3, vec!(), &clones);
+        let children = vec!(counting_node(2, vec!(grandchild), &clones),
+                            counting_node(4, vec!(), &clones));
+        let root = counting_node(1, children, &clones);
+
+        let mut visited = vec!();
+        {
+            let mut visit = |node: &CountingNode| visited.push(node.id);
+            each_node(&root, &mut visit);
+        }
+        assert_eq!(visited, vec!(1, 2, 3, 4));
+        assert_eq!(clones.get(), 0);
+
+        // The iterator clones every page below the root.
+        let mut stack = vec!(root);
+        let mut iterated = vec!();
+        loop {
+            match next_depth_first(&mut stack) {
+                Some(node) => iterated.push(node.id),
+                None => break,
+            }
+        }
+        assert_eq!(iterated, vec!(1, 4, 2, 3));
+        assert_eq!(clones.get(), 3);
+    }
+
+    #[test]
+    fn test_taking_dirty_nodes_leaves_none_pending() {
+        let mut pending = SmallVec1::new();
+        pending.push(1u);
+        pending.push(2u);
+
+        let taken = take_dirty_nodes(&mut pending);
+        assert_eq!(taken.iter().map(|node| *node).collect::<Vec<uint>>(), vec!(1u, 2u));
+        assert_eq!(pending.len(), 0);
+        assert_eq!(take_dirty_nodes(&mut pending).len(), 0);
+    }
+
+    #[test]
+    fn test_only_onscreen_boxes_intersect_viewport() {
+        let px = |x: int| Au::from_px(x);
+        let viewport = Rect(Point2D(px(0), px(0)), Size2D(px(800), px(600)));
+        let onscreen = Rect(Point2D(px(700), px(500)), Size2D(px(300), px(300)));
+        let below = Rect(Point2D(px(0), px(600)), Size2D(px(800), px(600)));
+        let left = Rect(Point2D(px(-400), px(0)), Size2D(px(300), px(600)));
+
+        assert!(box_intersects_viewport(onscreen, viewport));
+        assert!(!box_intersects_viewport(below, viewport));
+        assert!(!box_intersects_viewport(left, viewport));
+        assert!(!box_intersects_viewport(Rect::zero(), viewport));
+    }
+
+    #[test]
+    fn test_removed_page_comes_with_its_subpage_id() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        let subpage_id = root.find(PipelineId(4)).unwrap().subpage_id.get();
+        assert!(subpage_id == Some(SubpageId(1)));
+        match root.remove_and_release(PipelineId(4)) {
+            Some((removed, removed_subpage_id)) => {
+                assert!(removed.id == PipelineId(4));
+                assert!(removed_subpage_id == subpage_id);
+            }
+            None => fail!("the removed child was lost"),
+        }
+        assert!(root.remove_and_release(PipelineId(4)).is_none());
+
+        // The id went back to the parent of the removed page.
+        assert!(root.find(PipelineId(1)).unwrap().get_next_subpage_id() == SubpageId(1));
+    }
+
+    #[test]
+    fn test_traversal_stops_when_asked() {
+        let clones = Rc::new(Cell::new(0u));
+        let grandchild = counting_node(3, vec!(), &clones);
+        let children = vec!(counting_node(2, vec!(grandchild), &clones),
+                            counting_node(4, vec!(), &clones));
+        let root = counting_node(1, children, &clones);
+
+        let mut visited = vec!();
+        let completed = {
+            let mut visit = |node: &CountingNode| {
+                visited.push(node.id);
+                visited.len() < 2
+            };
+            each_node_while(&root, &mut visit)
+        };
+        assert!(!completed);
+        assert_eq!(visited, vec!(1, 2));
+
+        let mut count = 0u;
+        assert!(each_node_while(&root, &mut |_: &CountingNode| { count += 1; true }));
+        assert_eq!(count, 4);
+        assert_eq!(clones.get(), 0);
+    }
+
+    #[test]
+    fn test_idle_reflow_waits_for_quiet_layout() {
+        assert!(should_run_idle_reflow(Idle, 0));
+        assert!(!should_run_idle_reflow(Running { reflow_id: 3 }, 0));
+
+        // A normal reflow that is already pending comes first.
+        assert!(!should_run_idle_reflow(Idle, 1));
+        assert!(!should_run_idle_reflow(Running { reflow_id: 3 }, 2));
+    }
+
+    #[test]
+    fn test_pages_are_found_by_subpage_id() {
+        let clones = Rc::new(Cell::new(0u));
+        let grandchildren = vec!(counting_node(3, vec!(), &clones),
+                                 counting_node(5, vec!(), &clones));
+        let children = vec!(counting_node(2, grandchildren, &clones),
+                            counting_node(4, vec!(), &clones));
+        let root = counting_node(1, children, &clones);
+        let find = |subpage_id: uint| {
+            let mut found = None;
+            root.with_children(|children| {
+                found = find_by_subpage_id(children, SubpageId(subpage_id)).map(|node| node.id);
+            });
+            found
+        };
+
+        assert_eq!(find(4), Some(4));
+        assert_eq!(find(5), Some(5));
+        assert_eq!(find(2), Some(2));
+        assert_eq!(find(1), None);
+        assert_eq!(find(9), None);
+    }
+
+    #[test]
+    fn test_frame_dependent_queries_without_a_frame_find_nothing() {
+        let pages = TestPages::new();
+        let (page, layout_msgs) = pages.page(1, None);
+        assert!(page.frame().is_none());
+
+        let point = Point2D(10.0f32, 10.0);
+        assert!(page.find_fragment_node("section".to_string()).is_none());
+        assert!(page.hit_test(&point).is_none());
+        assert!(page.get_nodes_under_mouse(&point).is_none());
+        assert!(sent_nothing(&layout_msgs));
+    }
+
+    #[test]
+    fn test_breadth_first_iteration_visits_shallow_pages_first() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        assert_eq!(ids(root.iter_breadth_first()), vec!(0, 1, 2, 3, 4, 5));
+        // Depth-first iteration finishes the subtree of a page before moving on.
+        assert_eq!(ids(root.iter()), vec!(0, 2, 1, 4, 3, 5));
+    }
+
+    #[test]
+    fn test_removed_subtree_is_not_found() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        assert!(root.find(PipelineId(5)).is_some());
+
+        assert!(root.remove(PipelineId(1)).is_some());
+        for &id in [1u, 3, 4, 5].iter() {
+            assert!(root.find(PipelineId(id)).is_none());
+        }
+        assert!(root.find(PipelineId(2)).is_some());
+        assert!(root.find(PipelineId(0)).is_some());
+    }
+
+    #[test]
+    fn test_removed_page_keeps_its_children() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        let removed = root.remove(PipelineId(1)).unwrap();
+        assert_eq!(removed.child_count(), 2);
+        assert!(removed.descendant_pipeline_ids() ==
+                vec!(PipelineId(3), PipelineId(5), PipelineId(4)));
+        assert!(removed.parent().is_none());
+    }
+
+    #[test]
+    fn test_ancestors_run_up_to_the_root() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        let leaf = root.find(PipelineId(5)).unwrap();
+        assert_eq!(ids(leaf.ancestors()), vec!(3, 1, 0));
+        assert!(root.ancestors().next().is_none());
+
+        // Parent links are weak, so the rest of the tree goes away with the root.
+        drop(root);
+        assert!(leaf.parent().is_none());
+    }
+
+    #[test]
+    fn test_failed_layout_task_is_reported_to_the_page() {
+        let pages = TestPages::new();
+        let (page, _layout_msgs) = pages.page(1, None);
+        let fail_layout = || {
+            let (chan, port) = channel::<Duration>();
+            *page.layout_join_port.borrow_mut() = Some(port);
+            page.layout_state.set(Running { reflow_id: 1 });
+            drop(chan);
+        };
+
+        fail_layout();
+        assert_eq!(page.join_layout(), Err(LayoutTaskFailed));
+        assert!(page.layout_state() == Idle);
+
+        // Queries that need layout give up rather than taking the script task down.
+        fail_layout();
+        assert!(page.layout().is_err());
+        fail_layout();
+        assert!(page.content_box_query(TrustedNodeAddress(ptr::null())) == Rect::zero());
+    }
+
+    #[test]
+    fn test_page_counts_of_a_tree() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        assert_eq!((root.child_count(), root.total_page_count()), (2, 6));
+
+        let page = root.find(PipelineId(1)).unwrap();
+        assert_eq!((page.child_count(), page.total_page_count()), (2, 4));
+        let leaf = root.find(PipelineId(5)).unwrap();
+        assert_eq!((leaf.child_count(), leaf.total_page_count()), (0, 1));
+    }
+
+    #[test]
+    fn test_only_damaged_pages_are_iterated() {
+        let pages = TestPages::new();
+        let root = pages.tree(&[(1, 0), (2, 0), (3, 1)]);
+        assert!(root.iter_damaged().next().is_none());
+
+        root.find(PipelineId(2)).unwrap().damage();
+        root.find(PipelineId(3)).unwrap().damage();
+        let mut damaged = ids(root.iter_damaged());
+        damaged.sort();
+        assert_eq!(damaged, vec!(2, 3));
+    }
+
+    #[test]
+    fn test_descendant_pipeline_ids_exclude_the_page() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        let descendant_ids: Vec<uint> = root.descendant_pipeline_ids().into_iter().map(|id| {
+            let PipelineId(id) = id;
+            id
+        }).collect();
+        assert_eq!(descendant_ids, vec!(1, 4, 3, 5, 2));
+        assert!(root.find(PipelineId(5)).unwrap().descendant_pipeline_ids().is_empty());
+    }
+
+    #[test]
+    fn test_detached_page_becomes_a_root() {
+        let pages = TestPages::new();
+        let root = pages.tree(TEST_TREE);
+        let page = root.find(PipelineId(3)).unwrap();
+        let old_parent = page.parent().unwrap();
+        assert!(page.subpage_id.get().is_some());
+
+        page.detach_as_root();
+        assert!(page.subpage_id.get().is_none());
+        assert!(page.parent().is_none());
+        assert!(old_parent.find(PipelineId(3)).is_none());
+        assert!(root.find(PipelineId(3)).is_none());
+        // The detached page keeps its own subtree.
+        assert!(page.find(PipelineId(5)).is_some());
+        assert!(root.find(PipelineId(5)).is_none());
+    }
+
+    #[test]
+    fn test_reflow_without_document_element_keeps_the_page_damaged() {
+        let pages = TestPages::new();
+        let roots = RootCollection::new();
+        let _stack_roots_tls = StackRootTLS::new(&roots);
+        let (page, layout_msgs) = pages.page(1, None);
+        pages.load_empty_document(&page);
+        page.damage();
+        page.avoided_reflows.set(2);
+        page.last_reflow_id.set(5);
+
+        page.reflow(ReflowForDisplay, ScriptQuery, test_control_chan(), &mut TestCompositor,
+                    NoQuery, None, None);
+        assert!(sent_nothing(&layout_msgs));
+        assert!(page.is_damaged());
+        assert_eq!(page.avoided_reflows.get(), 2);
+        assert_eq!(page.last_reflow_id.get(), 5);
+    }
+
+    #[test]
+    fn test_flushed_reflow_tells_layout_its_reason() {
+        let pages = TestPages::new();
+        let roots = RootCollection::new();
+        let _stack_roots_tls = StackRootTLS::new(&roots);
+        let (page, layout_msgs) = pages.page(1, None);
+        page.set_url(Url::parse("http://example.com/").unwrap(), false);
+        pages.load_empty_document(&page);
+        add_document_element(&page);
+
+        page.damage();
+        page.flush_layout(NoQuery, DOMEvent);
+        match layout_msgs.recv_opt() {
+            Ok(ReflowMsg(reflow)) => assert_eq!(reflow.reason, DOMEvent),
+            _ => fail!("no reflow was sent to layout"),
+        }
+    }
+
+    #[test]
+    fn test_device_pixel_ratio_change_asks_for_a_full_reflow() {
+        let pages = TestPages::new();
+        let roots = RootCollection::new();
+        let _stack_roots_tls = StackRootTLS::new(&roots);
+        let (page, layout_msgs) = pages.page(1, None);
+        page.set_url(Url::parse("http://example.com/").unwrap(), false);
+        pages.load_empty_document(&page);
+        add_document_element(&page);
+
+        // Reflows the page and returns whether layout was asked to redo everything.
+        let reflow_needs_full_reflow = || {
+            page.damage();
+            page.flush_layout(NoQuery, DOMEvent);
+            match layout_msgs.recv_opt() {
+                Ok(ReflowMsg(reflow)) => {
+                    reflow.script_join_chan.send(Duration::zero());
+                    assert!(page.join_layout().is_ok());
+                    reflow.needs_full_reflow
+                }
+                _ => fail!("no reflow was sent to layout"),
+            }
+        };
+        assert!(!reflow_needs_full_reflow());
+
+        let mut hidpi_size = window_size(800.0, 600.0);
+        hidpi_size.device_pixel_ratio = ScaleFactor(2.0);
+        assert!(page.set_window_size(hidpi_size).is_ok());
+        assert!(reflow_needs_full_reflow());
+
+        // Later reflows at the same ratio can be incremental again.
+        assert!(!reflow_needs_full_reflow());
+    }
+
+    #[test]
+    fn test_nested_batch_query_is_not_fatal() {
+        let pages = TestPages::new();
+        let roots = RootCollection::new();
+        let _stack_roots_tls = StackRootTLS::new(&roots);
+        let (page, _layout_msgs) = pages.page(1, None);
+        pages.load_empty_document(&page);
+
+        // Without a document element nothing is laid out, so nothing is answered either.
+        assert!(page.batch_query(vec!(BatchQuery(vec!(NoQuery)), NoQuery)).is_empty());
+    }
+}
@@ -227,7 +227,7 @@ pub fn parse_html(page: &Page,
                 // Store the final URL before we start parsing, so that DOM routines
                 // (e.g. HTMLImageElement::update_image) can resolve relative URLs
                 // correctly.
-                *page.mut_url() = Some((base_url.clone(), true));
+                page.set_url(base_url.clone(), true);
             }
 
             (Some(base_url), Some(load_response))
@@ -30,8 +30,10 @@ use dom::worker::{Worker, TrustedWorkerAddress};
 use dom::xmlhttprequest::{TrustedXHRAddress, XMLHttpRequest, XHRProgress};
 use parse::html::{InputString, InputUrl, parse_html};
 use layout_interface::{ScriptLayoutChan, LayoutChan, NoQuery, ReflowForDisplay};
+use layout_interface::{CachedPageNeededReflow, DOMEvent, FirstLoad, MouseMove, PendingReflows};
+use layout_interface::{ReceivedReflowEvent, ReflowReason, WindowResize};
 use layout_interface;
-use page::{Page, IterablePage, Frame};
+use page::{Page, IterablePage, Frame, FragmentScroll, DEFAULT_URL_CACHE_CAPACITY};
 use timers::TimerId;
 
 use devtools_traits;
@@ -40,9 +42,10 @@ use devtools_traits::{DevtoolScriptControlMsg, EvaluateJS, EvaluateJSReply, GetD
 use devtools_traits::{GetChildren, GetLayout};
 use script_traits::{CompositorEvent, ResizeEvent, ReflowEvent, ClickEvent, MouseDownEvent};
 use script_traits::{MouseMoveEvent, MouseUpEvent, ConstellationControlMsg, ScriptTaskFactory};
+use script_traits::ScrollEvent;
 use script_traits::{ResizeMsg, AttachLayoutMsg, LoadMsg, SendEventMsg, ResizeInactiveMsg};
 use script_traits::{ExitPipelineMsg, NewLayoutInfo, OpaqueScriptLayoutChannel, ScriptControlChan};
-use script_traits::ReflowCompleteMsg;
+use script_traits::{ReflowCompleteMsg, SuppressReflowsMsg};
 use servo_msg::compositor_msg::{FinishedLoading, LayerId, Loading};
 use servo_msg::compositor_msg::{ScriptListener};
 use servo_msg::constellation_msg::{ConstellationChan, LoadCompleteMsg, LoadUrlMsg, NavigationDirection};
@@ -50,7 +53,7 @@ use servo_msg::constellation_msg::{LoadData, PipelineId, Failure, FailureMsg, Wi
 use servo_msg::constellation_msg;
 use servo_net::image_cache_task::ImageCacheTask;
 use servo_net::resource_task::ResourceTask;
-use servo_util::geometry::to_frac_px;
+use servo_util::geometry::{Au, to_frac_px};
 use servo_util::smallvec::{SmallVec1, SmallVec};
 use servo_util::task::spawn_named_with_send_on_failure;
 use servo_util::task_state;
@@ -68,7 +71,6 @@ use libc::size_t;
 use std::any::{Any, AnyRefExt};
 use std::collections::HashSet;
 use std::comm::{channel, Sender, Receiver, Select};
-use std::mem::replace;
 use std::rc::Rc;
 use std::u32;
 
@@ -88,6 +90,9 @@ pub enum ScriptMsg {
     /// Begins a content-initiated load on the specified pipeline (only
     /// dispatched to ScriptTask).
     TriggerLoadMsg(PipelineId, LoadData),
+    /// Loads the current url of the specified pipeline again, reusing its
+    /// cached layout if possible (only dispatched to ScriptTask).
+    ReloadMsg(PipelineId),
     /// Instructs the script task to send a navigate message to
     /// the constellation (only dispatched to ScriptTask).
     NavigateMsg(NavigationDirection),
@@ -330,7 +335,8 @@ impl ScriptTask {
         let page = Page::new(id, None, layout_chan, window_size,
                              resource_task.clone(),
                              constellation_chan.clone(),
-                             js_context.clone());
+                             js_context.clone(),
+                             DEFAULT_URL_CACHE_CAPACITY);
 
         // Notify devtools that a new script global exists.
         //FIXME: Move this into handle_load after we create a window instead.
@@ -422,14 +428,11 @@ impl ScriptTask {
             let mut page = self.page.borrow_mut();
             for page in page.iter() {
                 // Only process a resize if layout is idle.
-                let layout_join_port = page.layout_join_port.borrow();
-                if layout_join_port.is_none() {
-                    let mut resize_event = page.resize_event.get();
-                    match resize_event.take() {
+                if !page.is_layout_busy() {
+                    match page.take_resize_event() {
                         Some(size) => resizes.push((page.id, size)),
                         None => ()
                     }
-                    page.resize_event.set(None);
                 }
             }
         }
@@ -486,7 +489,10 @@ impl ScriptTask {
                 FromConstellation(ResizeMsg(id, size)) => {
                     let mut page = self.page.borrow_mut();
                     let page = page.find(id).expect("resize sent to nonexistent pipeline");
-                    page.resize_event.set(Some(size));
+                    match page.set_window_size(size) {
+                        Ok(()) => {}
+                        Err(error) => debug!("script: ignoring resize of {:?}: {:?}", id, error),
+                    }
                 }
                 FromConstellation(SendEventMsg(id, ReflowEvent(node_addresses))) => {
                     let mut page = self.page.borrow_mut();
@@ -523,12 +529,14 @@ impl ScriptTask {
                 FromConstellation(LoadMsg(id, load_data)) => self.load(id, load_data),
                 FromScript(TriggerLoadMsg(id, load_data)) => self.trigger_load(id, load_data),
                 FromScript(TriggerFragmentMsg(id, url)) => self.trigger_fragment(id, url),
+                FromScript(ReloadMsg(id)) => self.handle_reload_msg(id),
                 FromConstellation(SendEventMsg(id, event)) => self.handle_event(id, event),
                 FromScript(FireTimerMsg(FromWindow(id), timer_id)) => self.handle_fire_timer_msg(id, timer_id),
                 FromScript(FireTimerMsg(FromWorker, _)) => fail!("Worker timeouts must not be sent to script task"),
                 FromScript(NavigateMsg(direction)) => self.handle_navigate_msg(direction),
                 FromConstellation(ReflowCompleteMsg(id, reflow_id)) => self.handle_reflow_complete_msg(id, reflow_id),
                 FromConstellation(ResizeInactiveMsg(id, new_size)) => self.handle_resize_inactive_msg(id, new_size),
+                FromConstellation(SuppressReflowsMsg(id)) => self.handle_suppress_reflows_msg(id),
                 FromConstellation(ExitPipelineMsg(id)) => if self.handle_exit_pipeline_msg(id) { return false },
                 FromScript(ExitWindowMsg(id)) => self.handle_exit_window_msg(id),
                 FromConstellation(ResizeMsg(..)) => fail!("should have handled ResizeMsg already"),
@@ -643,9 +651,20 @@ impl ScriptTask {
                       window_size,
                       parent_page.resource_task.clone(),
                       self.constellation_chan.clone(),
-                      self.js_context.borrow().as_ref().unwrap().clone())
+                      self.js_context.borrow().as_ref().unwrap().clone(),
+                      DEFAULT_URL_CACHE_CAPACITY)
         };
-        parent_page.children.borrow_mut().push(Rc::new(new_page));
+        match page.add_child(&parent_page, Rc::new(new_page)) {
+            Ok(()) => {}
+            Err(error) => {
+                error!("script: not adding pipeline {:?}: {:?}", new_pipeline_id, error);
+                let ConstellationChan(ref chan) = self.constellation_chan;
+                chan.send(FailureMsg(Failure {
+                    pipeline_id: new_pipeline_id,
+                    subpage_id: Some(subpage_id),
+                }));
+            }
+        }
     }
 
     /// Handles a timer that fired.
@@ -665,17 +684,18 @@ impl ScriptTask {
         let page = page.find(pipeline_id).expect(
             "ScriptTask: received a load message for a layout channel that is not associated \
              with this script task. This is a bug.");
-        let last_reflow_id = page.last_reflow_id.get();
-        if last_reflow_id == reflow_id {
-            let mut layout_join_port = page.layout_join_port.borrow_mut();
-            *layout_join_port = None;
-        }
+        page.layout_complete(reflow_id);
 
         self.compositor.borrow_mut().set_ready_state(pipeline_id, FinishedLoading);
 
         if page.pending_reflows.get() > 0 {
             page.pending_reflows.set(0);
-            self.force_reflow(&*page);
+            self.force_reflow(&*page, PendingReflows);
+        }
+
+        match page.take_idle_reflow() {
+            Some(reason) => self.force_reflow(&*page, reason),
+            None => {}
         }
     }
 
@@ -698,6 +718,16 @@ impl ScriptTask {
         }
     }
 
+    /// The page is being navigated away from, so don't reflow it until it is loaded again.
+    fn handle_suppress_reflows_msg(&self, id: PipelineId) {
+        let mut page = self.page.borrow_mut();
+        // The page may have been removed in the meantime.
+        match page.find(id) {
+            Some(page) => page.suppress_reflows.set(true),
+            None => debug!("script: not suppressing reflows of missing pipeline {:?}", id),
+        }
+    }
+
     /// We have gotten a window.close from script, which we pass on to the compositor.
     /// We do not shut down the script task now, because the compositor will ask the
     /// constellation to shut down the pipeline, which will clean everything up
@@ -725,8 +755,10 @@ impl ScriptTask {
         }
 
         // otherwise find just the matching page and exit all sub-pages
-        match page.remove(id) {
-            Some(ref mut page) => {
+        match page.remove_and_release(id) {
+            Some((ref mut page, _)) => {
+                debug!("shutting down layout for detached page {:?} and its descendants {:?}",
+                       id, page.descendant_pipeline_ids());
                 shut_down_layout(&*page, (*self.js_runtime).ptr);
                 false
             }
@@ -749,18 +781,21 @@ impl ScriptTask {
             message for a layout channel that is not associated with this script task. This
             is a bug.");
 
-        let last_loaded_url = replace(&mut *page.mut_url(), None);
-        match last_loaded_url {
-            Some((ref loaded, needs_reflow)) if *loaded == url => {
-                *page.mut_url() = Some((loaded.clone(), false));
+        // Whatever is loaded, this page may reflow again.
+        page.suppress_reflows.set(false);
+
+        match page.take_cached_reload(&url) {
+            Some(needs_reflow) => {
                 if needs_reflow {
-                    self.force_reflow(&*page);
+                    self.force_reflow(&*page, CachedPageNeededReflow);
                 }
                 return;
-            },
-            _ => (),
+            }
+            None => {}
         }
 
+        let last_loaded_url = page.url().clone();
+
         let is_javascript = url.scheme.as_slice() == "javascript";
         let last_url = last_loaded_url.map(|(ref loaded, _)| loaded.clone());
 
@@ -777,7 +812,7 @@ impl ScriptTask {
             let doc_url = last_url.unwrap_or_else(|| {
                 Url::parse("about:blank").unwrap()
             });
-            *page.mut_url() = Some((doc_url.clone(), true));
+            page.set_url(doc_url.clone(), true);
             doc_url
         } else {
             url.clone()
@@ -818,13 +853,10 @@ impl ScriptTask {
             let document_as_node = NodeCast::from_ref(document_js_ref);
             document.content_changed(document_as_node);
         }
-        window.flush_layout();
+        window.flush_layout(FirstLoad);
 
-        {
-            // No more reflow required
-            let mut page_url = page.mut_url();
-            *page_url = Some((url.clone(), false));
-        }
+        // No more reflow required
+        page.set_url(url.clone(), false);
 
         // https://html.spec.whatwg.org/multipage/#the-end step 4
         let event = Event::new(&global::Window(*window), "DOMContentLoaded".to_string(),
@@ -843,13 +875,13 @@ impl ScriptTask {
         let wintarget: JSRef<EventTarget> = EventTargetCast::from_ref(*window);
         let _ = wintarget.dispatch_event_with_target(Some(doctarget), *event);
 
-        *page.fragment_name.borrow_mut() = url.fragment.clone();
+        *page.fragment_name.borrow_mut() = url.fragment.clone().map(FragmentScroll::instant);
 
         let ConstellationChan(ref chan) = self.constellation_chan;
         chan.send(LoadCompleteMsg(page.id, url));
     }
 
-    fn scroll_fragment_point(&self, pipeline_id: PipelineId, node: JSRef<Element>) {
+    fn scroll_fragment_point(&self, pipeline_id: PipelineId, node: JSRef<Element>, smooth: bool) {
         let node: JSRef<Node> = NodeCast::from_ref(node);
         let rect = node.get_bounding_content_box();
         let point = Point2D(to_frac_px(rect.origin.x).to_f32().unwrap(),
@@ -858,12 +890,15 @@ impl ScriptTask {
         // Really what needs to happen is that this needs to go through layout to ask which
         // layer the element belongs to, and have it send the scroll message to the
         // compositor.
-        self.compositor.borrow_mut().scroll_fragment_point(pipeline_id, LayerId::null(), point);
+        self.compositor.borrow_mut().scroll_fragment_point(pipeline_id,
+                                                          LayerId::null(),
+                                                          point,
+                                                          smooth);
     }
 
-    fn force_reflow(&self, page: &Page) {
+    fn force_reflow(&self, page: &Page, reason: ReflowReason) {
         {
-            let mut pending = page.pending_dirty_nodes.borrow_mut();
+            let mut pending = page.take_pending_dirty_nodes();
             let js_runtime = self.js_runtime.deref().ptr;
 
             for untrusted_node in pending.into_iter() {
@@ -874,9 +909,12 @@ impl ScriptTask {
 
         page.damage();
         page.reflow(ReflowForDisplay,
+                    reason,
                     self.control_chan.clone(),
                     &mut **self.compositor.borrow_mut(),
-                    NoQuery);
+                    NoQuery,
+                    None,
+                    None);
     }
 
     /// This is the main entry point for receiving and dispatching DOM events.
@@ -893,17 +931,19 @@ impl ScriptTask {
 
                     let frame = page.frame();
                     if frame.is_some() {
-                        self.force_reflow(&*page);
+                        self.force_reflow(&*page, WindowResize);
                     }
 
-                    let fragment_node =
-                        page.fragment_name
-                            .borrow_mut()
-                            .take()
-                            .and_then(|name| page.find_fragment_node(name))
-                            .root();
-                    match fragment_node {
-                        Some(node) => self.scroll_fragment_point(pipeline_id, *node),
+                    let fragment = page.fragment_name.borrow_mut().take();
+                    match fragment {
+                        Some(fragment) => {
+                            match page.find_fragment_node(fragment.name).root() {
+                                Some(node) => {
+                                    self.scroll_fragment_point(pipeline_id, *node, fragment.smooth)
+                                }
+                                None => {}
+                            }
+                        }
                         None => {}
                     }
 
@@ -934,11 +974,10 @@ impl ScriptTask {
                 let page = get_page(&*self.page.borrow(), pipeline_id);
                 let frame = page.frame();
                 if frame.is_some() {
-                    let in_layout = page.layout_join_port.borrow().is_some();
-                    if in_layout {
+                    if page.is_layout_busy() {
                         page.pending_reflows.set(page.pending_reflows.get() + 1);
                     } else {
-                        self.force_reflow(&*page);
+                        self.force_reflow(&*page, ReceivedReflowEvent);
                     }
                 }
             }
@@ -975,7 +1014,7 @@ impl ScriptTask {
                                         let eventtarget: JSRef<EventTarget> = EventTargetCast::from_ref(node);
                                         let _ = eventtarget.dispatch_event_with_target(None, *event);
 
-                                        window.flush_layout();
+                                        window.flush_layout(DOMEvent);
                                     }
                                     None => {}
                                 }
@@ -989,6 +1028,11 @@ impl ScriptTask {
             }
             MouseDownEvent(..) => {}
             MouseUpEvent(..) => {}
+            ScrollEvent(point) => {
+                let page = get_page(&*self.page.borrow(), pipeline_id);
+                page.scroll_offset.set(Point2D(Au::from_frac32_px(point.x),
+                                               Au::from_frac32_px(point.y)));
+            }
             MouseMoveEvent(point) => {
                 let page = get_page(&*self.page.borrow(), pipeline_id);
                 match page.get_nodes_under_mouse(&point) {
@@ -1043,7 +1087,7 @@ impl ScriptTask {
 
                         if target_compare {
                             if mouse_over_targets.is_some() {
-                                self.force_reflow(&*page);
+                                self.force_reflow(&*page, MouseMove);
                             }
                             *mouse_over_targets = Some(target_list);
                         }
@@ -1062,13 +1106,21 @@ impl ScriptTask {
         const_chan.send(LoadUrlMsg(pipeline_id, load_data));
     }
 
+    /// Loads the current url of the given pipeline again. The page is still cached, so `load`
+    /// only reflows it if it was marked as needing a reflow.
+    fn handle_reload_msg(&self, pipeline_id: PipelineId) {
+        let url = get_page(&*self.page.borrow(), pipeline_id).get_url();
+        self.load(pipeline_id, LoadData::new(url));
+    }
+
     /// The entry point for content to notify that a fragment url has been requested
     /// for the given pipeline.
     fn trigger_fragment(&self, pipeline_id: PipelineId, url: Url) {
         let page = get_page(&*self.page.borrow(), pipeline_id);
-        match page.find_fragment_node(url.fragment.unwrap()).root() {
+        let fragment = FragmentScroll::instant(url.fragment.unwrap());
+        match page.find_fragment_node(fragment.name).root() {
             Some(node) => {
-                self.scroll_fragment_point(pipeline_id, *node);
+                self.scroll_fragment_point(pipeline_id, *node, fragment.smooth);
             }
             None => {}
          }
@@ -1078,7 +1130,14 @@ impl ScriptTask {
 /// Shuts down layout for the given page tree.
 fn shut_down_layout(page_tree: &Rc<Page>, rt: *mut JSRuntime) {
     for page in page_tree.iter() {
-        page.join_layout();
+        match page.join_layout() {
+            Ok(()) => {}
+            Err(error) => {
+                // The layout task is already gone; there is nothing left to shut down.
+                debug!("layout for {:?} already exited: {:?}", page.id, error);
+                continue;
+            }
+        }
 
         // Tell the layout task to begin shutting down, and wait until it
         // processed this message.
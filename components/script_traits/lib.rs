@@ -62,6 +62,9 @@ pub enum ConstellationControlMsg {
     SendEventMsg(PipelineId, CompositorEvent),
     /// Notifies script that reflow is finished.
     ReflowCompleteMsg(PipelineId, uint),
+    /// Notifies script that the specified pipeline is being navigated away from, so that it
+    /// doesn't reflow until a document is loaded into it again.
+    SuppressReflowsMsg(PipelineId),
 }
 
 /// Events from the compositor that the script task needs to know about
@@ -71,7 +74,10 @@ pub enum CompositorEvent {
     ClickEvent(uint, Point2D<f32>),
     MouseDownEvent(uint, Point2D<f32>),
     MouseUpEvent(uint, Point2D<f32>),
-    MouseMoveEvent(Point2D<f32>)
+    MouseMoveEvent(Point2D<f32>),
+    /// The compositor scrolled the page so that this point, in page coordinates, is at the top
+    /// left corner of the viewport.
+    ScrollEvent(Point2D<f32>)
 }
 
 /// An opaque wrapper around script<->layout channels to avoid leaking message types into
@@ -15,16 +15,18 @@ use url::{Url, UrlParser};
 
 
 pub fn iter_font_face_rules_inner(rules: &[CSSRule], device: &Device,
-                                    callback: |family: &str, source: &Source|) {
+                                    callback: |family: &str, source: &Source,
+                                               unicode_range: &Option<Vec<(u32, u32)>>|) {
     for rule in rules.iter() {
         match *rule {
             CSSStyleRule(_) => {},
             CSSMediaRule(ref rule) => if rule.media_queries.evaluate(device) {
-                iter_font_face_rules_inner(rule.rules.as_slice(), device, |f, s| callback(f, s))
+                iter_font_face_rules_inner(rule.rules.as_slice(), device,
+                                           |f, s, r| callback(f, s, r))
             },
             CSSFontFaceRule(ref rule) => {
                 for source in rule.sources.iter() {
-                    callback(rule.family.as_slice(), source)
+                    callback(rule.family.as_slice(), source, &rule.unicode_range)
                 }
             },
         }
@@ -46,6 +48,9 @@ pub struct UrlSource {
 pub struct FontFaceRule {
     pub family: String,
     pub sources: Vec<Source>,
+    /// The first and last codepoints of each range that the `unicode-range` descriptor limits
+    /// the faces to, or `None` if the faces may be used for every codepoint.
+    pub unicode_range: Option<Vec<(u32, u32)>>,
 }
 
 pub fn parse_font_face_rule(rule: AtRule, parent_rules: &mut Vec<CSSRule>, base_url: &Url) {
@@ -64,6 +69,7 @@ pub fn parse_font_face_rule(rule: AtRule, parent_rules: &mut Vec<CSSRule>, base_
 
     let mut maybe_family = None;
     let mut maybe_sources = None;
+    let mut unicode_range = None;
 
     for item in ErrorLoggerIterator(parse_declaration_list(block.into_iter())) {
         match item {
@@ -93,6 +99,15 @@ pub fn parse_font_face_rule(rule: AtRule, parent_rules: &mut Vec<CSSRule>, base_
                             Err(()) => log_css_error(location, "Invalid src in @font-face"),
                         };
                     },
+                    "unicode-range" => {
+                        match parse_slice_comma_separated(value.as_slice(),
+                                                          parse_one_unicode_range) {
+                            Ok(ranges) => unicode_range = Some(ranges),
+                            Err(()) => {
+                                log_css_error(location, "Invalid unicode-range in @font-face")
+                            }
+                        };
+                    },
                     _ => {
                         log_css_error(location, format!("Unsupported declaration {:s}", name).as_slice());
                     }
@@ -105,6 +120,7 @@ pub fn parse_font_face_rule(rule: AtRule, parent_rules: &mut Vec<CSSRule>, base_
         (Some(family), Some(sources)) => parent_rules.push(CSSFontFaceRule(FontFaceRule {
             family: family,
             sources: sources,
+            unicode_range: unicode_range,
         })),
         (None, _) => log_css_error(rule.location, "@font-face without a font-family descriptor"),
         _ => log_css_error(rule.location, "@font-face without an src descriptor"),
@@ -155,6 +171,17 @@ fn parse_one_src(iter: ParserIter, base_url: &Url) -> Result<Source, ()> {
 }
 
 
+/// Parses one range of a `unicode-range` descriptor into its first and last codepoints.
+fn parse_one_unicode_range(iter: ParserIter) -> Result<(u32, u32), ()> {
+    match iter.next() {
+        Some(&UnicodeRange(first, last)) if first <= last && last <= 0x10ffff => {
+            Ok((first, last))
+        }
+        _ => Err(())
+    }
+}
+
+
 fn parse_one_format(iter: ParserIter) -> Result<String, ()> {
     match iter.next() {
         Some(&QuotedString(ref value)) => {
@@ -167,3 +194,37 @@ fn parse_one_format(iter: ParserIter) -> Result<String, ()> {
         _ => Err(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use geom::size::TypedSize2D;
+    use media_queries::{Device, Screen};
+    use stylesheets::{iter_font_face_rules, Stylesheet};
+    use url::Url;
+
+    fn unicode_ranges(css: &str) -> Vec<Option<Vec<(u32, u32)>>> {
+        let url = Url::parse("http://localhost").unwrap();
+        let stylesheet = Stylesheet::from_str(css, url);
+        let device = Device::new(Screen, TypedSize2D(200.0, 100.0));
+        let mut ranges = vec!();
+        iter_font_face_rules(&stylesheet, &device, |_, _, unicode_range| {
+            ranges.push(unicode_range.clone())
+        });
+        ranges
+    }
+
+    #[test]
+    fn test_unicode_range_is_parsed() {
+        assert_eq!(unicode_ranges("@font-face { font-family: A; src: local(A); \
+                                   unicode-range: U+0-7F, U+4E00-9FFF }"),
+                   vec!(Some(vec!((0x0, 0x7f), (0x4e00, 0x9fff)))));
+        assert_eq!(unicode_ranges("@font-face { font-family: A; src: local(A) }"), vec!(None));
+    }
+
+    #[test]
+    fn test_invalid_unicode_range_is_ignored() {
+        assert_eq!(unicode_ranges("@font-face { font-family: A; src: local(A); \
+                                   unicode-range: U+7F-0 }"),
+                   vec!(None));
+    }
+}
@@ -158,7 +158,7 @@ pub mod longhands {
             ${caller.body()}
             pub mod computed_value {
                 #[allow(non_camel_case_types)]
-                #[deriving(PartialEq, Clone, FromPrimitive)]
+                #[deriving(PartialEq, Eq, Hash, Clone, FromPrimitive)]
                 pub enum T {
                     % for value in values.split():
                         ${to_rust_ident(value)},
@@ -833,6 +833,99 @@ pub mod longhands {
 
     ${single_keyword("font-style", "normal italic oblique")}
     ${single_keyword("font-variant", "normal small-caps")}
+    ${single_keyword("font-variant-ligatures", "normal none")}
+    ${single_keyword("font-stretch", "normal ultra-condensed extra-condensed condensed semi-condensed semi-expanded expanded extra-expanded ultra-expanded")}
+    ${single_keyword("text-rendering", "auto optimizespeed optimizelegibility geometricprecision")}
+
+    <%self:longhand name="font-feature-settings">
+        pub use super::computed_as_specified as to_computed_value;
+        pub mod computed_value {
+            /// An OpenType feature tag, such as `liga`, and the value to set it to.
+            #[deriving(PartialEq, Eq, Hash, Clone, Show)]
+            pub struct FeatureTagValue {
+                pub tag: String,
+                pub value: u32,
+            }
+            pub type T = Vec<FeatureTagValue>;
+        }
+        pub type SpecifiedValue = computed_value::T;
+
+        #[inline]
+        pub fn get_initial_value() -> computed_value::T {
+            vec![]
+        }
+        /// normal | <feature-tag-value>#
+        pub fn parse(input: &[ComponentValue], _base_url: &Url) -> Result<SpecifiedValue, ()> {
+            match one_component_value(input).and_then(get_ident_lower) {
+                Ok(ref keyword) if keyword.as_slice() == "normal" => return Ok(vec![]),
+                _ => {}
+            }
+            parse_slice_comma_separated(input, parse_one_feature)
+        }
+        /// <feature-tag-value> = <string> [ <integer> | on | off ]?
+        pub fn parse_one_feature<'a>(iter: ParserIter) -> Result<FeatureTagValue, ()> {
+            let tag = match iter.next() {
+                Some(&QuotedString(ref tag)) if tag.len() == 4 &&
+                        tag.as_slice().chars().all(|c| c >= ' ' && c <= '~') => tag.clone(),
+                _ => return Err(())
+            };
+            let value = match iter.next() {
+                Some(&Number(ref value)) => match value.int_value {
+                    Some(value) if value >= 0 => value as u32,
+                    _ => return Err(()),
+                },
+                Some(&Ident(ref value)) => match value.as_slice().to_ascii_lower().as_slice() {
+                    "on" => 1,
+                    "off" => 0,
+                    _ => return Err(()),
+                },
+                Some(component_value) => {
+                    iter.push_back(component_value);
+                    1
+                }
+                None => 1,
+            };
+            Ok(FeatureTagValue {
+                tag: tag,
+                value: value,
+            })
+        }
+    </%self:longhand>
+
+    <%self:longhand name="font-synthesis">
+        pub use super::computed_as_specified as to_computed_value;
+        pub mod computed_value {
+            /// Whether a bold or an italic face that is missing may be synthesized.
+            #[deriving(PartialEq, Eq, Hash, Clone, Show)]
+            pub struct T {
+                pub weight: bool,
+                pub style: bool,
+            }
+        }
+        pub type SpecifiedValue = computed_value::T;
+
+        #[inline]
+        pub fn get_initial_value() -> computed_value::T {
+            computed_value::T { weight: true, style: true }
+        }
+        /// none | [ weight || style ]
+        pub fn parse(input: &[ComponentValue], _base_url: &Url) -> Result<SpecifiedValue, ()> {
+            let mut result = computed_value::T { weight: false, style: false };
+            match one_component_value(input).and_then(get_ident_lower) {
+                Ok(ref keyword) if keyword.as_slice() == "none" => return Ok(result),
+                _ => {}
+            }
+            for component_value in input.skip_whitespace() {
+                let keyword = try!(get_ident_lower(component_value));
+                match keyword.as_slice() {
+                    "weight" if !result.weight => result.weight = true,
+                    "style" if !result.style => result.style = true,
+                    _ => return Err(()),
+                }
+            }
+            if result.weight || result.style { Ok(result) } else { Err(()) }
+        }
+    </%self:longhand>
 
     <%self:single_component_value name="font-weight">
         #[deriving(Clone)]
@@ -184,6 +184,7 @@ pub fn iter_stylesheet_style_rules(stylesheet: &Stylesheet, device: &media_queri
 
 #[inline]
 pub fn iter_font_face_rules(stylesheet: &Stylesheet, device: &Device,
-                            callback: |family: &str, source: &Source|) {
+                            callback: |family: &str, source: &Source,
+                                       unicode_range: &Option<Vec<(u32, u32)>>|) {
     iter_font_face_rules_inner(stylesheet.rules.as_slice(), device, callback)
 }